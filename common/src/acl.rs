@@ -0,0 +1,255 @@
+use crate::{error::Error, Address, Peer, Protocol, PublicKey};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+/// Range of ip addresses, written as address/prefix length
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IpRange {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl IpRange {
+    pub fn new(address: IpAddr, prefix_length: u8) -> Result<Self, Error> {
+        let max_prefix_length = match address {
+            IpAddr::V4(_) => Ipv4Addr::BITS,
+            IpAddr::V6(_) => Ipv6Addr::BITS,
+        };
+
+        if prefix_length as u32 > max_prefix_length {
+            return Err(Error::InvalidAddress);
+        }
+
+        Ok(Self {
+            address,
+            prefix_length,
+        })
+    }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(Ipv4Addr::BITS - self.prefix_length as u32)
+                    .unwrap_or(0);
+
+                u32::from(network) & mask == u32::from(*address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(Ipv6Addr::BITS - self.prefix_length as u32)
+                    .unwrap_or(0);
+
+                u128::from(network) & mask == u128::from(*address) & mask
+            }
+            // Mapped addresses are already turned into ipv4 ones when peers are created
+            _ => false,
+        }
+    }
+}
+
+/// Range holding just the one address, a /32 or /128
+impl From<IpAddr> for IpRange {
+    fn from(address: IpAddr) -> Self {
+        let address = address.to_canonical();
+        let prefix_length = match address {
+            IpAddr::V4(_) => Ipv4Addr::BITS,
+            IpAddr::V6(_) => Ipv6Addr::BITS,
+        };
+
+        Self {
+            address,
+            prefix_length: prefix_length as u8,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_length) = s.split_once('/').ok_or(Error::InvalidAddress)?;
+
+        IpRange::new(
+            address.parse().map_err(|_| Error::InvalidAddress)?,
+            prefix_length.parse().map_err(|_| Error::InvalidAddress)?,
+        )
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AclAction {
+    Allow,
+    Deny,
+}
+
+/// What an acl rule applies to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AclMatcher {
+    Peer(Peer),
+    Protocol(Protocol),
+    IpRange(IpRange),
+    Node(PublicKey),
+}
+
+impl AclMatcher {
+    /// Whether this applies to a connection with a peer
+    pub fn matches_peer(&self, peer: &Peer) -> bool {
+        match self {
+            AclMatcher::Peer(matcher) => matcher == peer,
+            AclMatcher::Protocol(protocol) => *protocol == peer.protocol,
+            AclMatcher::IpRange(range) => match peer.address {
+                Address::Ip { address, .. } => range.contains(&address),
+                Address::Bluetooth { .. } | Address::Unix { .. } => false,
+            },
+            AclMatcher::Node(_) => false,
+        }
+    }
+
+    /// Whether this applies to a channel with a node
+    pub fn matches_node(&self, node: &PublicKey) -> bool {
+        match self {
+            AclMatcher::Node(matcher) => matcher == node,
+            _ => false,
+        }
+    }
+}
+
+/// Single line of an acl, written like `deny ip 10.0.0.0/8` or `allow node <public key>`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub matcher: AclMatcher,
+}
+
+impl AclRule {
+    pub fn allow(matcher: AclMatcher) -> Self {
+        Self {
+            action: AclAction::Allow,
+            matcher,
+        }
+    }
+
+    pub fn deny(matcher: AclMatcher) -> Self {
+        Self {
+            action: AclAction::Deny,
+            matcher,
+        }
+    }
+}
+
+impl FromStr for AclRule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut split = s.split_whitespace();
+        let action = split.next().ok_or(Error::InvalidAclRule)?;
+        let kind = split.next().ok_or(Error::InvalidAclRule)?;
+        let value = split.next().ok_or(Error::InvalidAclRule)?;
+
+        if split.next().is_some() {
+            return Err(Error::InvalidAclRule);
+        }
+
+        let action = match action.to_ascii_lowercase().as_str() {
+            "allow" => AclAction::Allow,
+            "deny" => AclAction::Deny,
+            _ => return Err(Error::InvalidAclRule),
+        };
+
+        let matcher = match kind.to_ascii_lowercase().as_str() {
+            "peer" => AclMatcher::Peer(value.parse()?),
+            "protocol" => AclMatcher::Protocol(value.parse()?),
+            "ip" => AclMatcher::IpRange(value.parse()?),
+            "node" => AclMatcher::Node(value.parse()?),
+            _ => return Err(Error::InvalidAclRule),
+        };
+
+        Ok(AclRule { action, matcher })
+    }
+}
+
+impl Display for AclRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let action = match self.action {
+            AclAction::Allow => "allow",
+            AclAction::Deny => "deny",
+        };
+
+        match &self.matcher {
+            AclMatcher::Peer(peer) => write!(f, "{} peer {}", action, peer),
+            AclMatcher::Protocol(protocol) => write!(f, "{} protocol {}", action, protocol),
+            AclMatcher::IpRange(range) => write!(f, "{} ip {}", action, range),
+            AclMatcher::Node(node) => write!(f, "{} node {}", action, node),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AclMatcher, AclRule, IpRange};
+    use crate::{Address, Peer, Protocol, PublicKey};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn ip_range_contains() {
+        let range: IpRange = "10.1.0.0/16".parse().unwrap();
+
+        assert!(range.contains(&IpAddr::V4(Ipv4Addr::new(10, 1, 200, 3))));
+        assert!(!range.contains(&IpAddr::V4(Ipv4Addr::new(10, 2, 0, 1))));
+        assert!(!range.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        let everything: IpRange = "::/0".parse().unwrap();
+        assert!(everything.contains(&IpAddr::V6(Ipv6Addr::LOCALHOST)));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+
+        let single = IpRange::from(IpAddr::V6(Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped()));
+        assert_eq!(single.to_string(), "10.0.0.1/32");
+        assert!(!single.contains(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))));
+    }
+
+    #[test]
+    fn parse_and_print_rule() {
+        let rule: AclRule = "deny ip 192.168.0.0/24".parse().unwrap();
+
+        assert_eq!(
+            rule,
+            AclRule::deny(AclMatcher::IpRange(
+                IpRange::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 24).unwrap()
+            ))
+        );
+        assert_eq!(rule.to_string(), "deny ip 192.168.0.0/24");
+
+        let rule: AclRule = "allow peer /tcp/ip/127.0.0.1/3434".parse().unwrap();
+        assert_eq!(rule.to_string().parse::<AclRule>().unwrap(), rule);
+
+        assert!("deny everything".parse::<AclRule>().is_err());
+    }
+
+    #[test]
+    fn matchers() {
+        let peer = Peer {
+            protocol: Protocol::Tcp,
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                port: 3434,
+            },
+        };
+
+        assert!(AclMatcher::Protocol(Protocol::Tcp).matches_peer(&peer));
+        assert!(!AclMatcher::Protocol(Protocol::Udp).matches_peer(&peer));
+        assert!(AclMatcher::IpRange("10.0.0.0/8".parse().unwrap()).matches_peer(&peer));
+        assert!(!AclMatcher::Node(PublicKey::new([0; 32])).matches_peer(&peer));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::ipc::stream::RouteWeaverStream;
+use futures_util::{ready, Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Largest chunk a single write hands to the daemon
+pub const MAX_WRITE_SIZE: usize = 64 * 1024;
+
+/// Wraps a [RouteWeaverStream] so it can be used as a plain byte stream, for things like hyper or tonic
+///
+/// Shutting down the writing half tells the daemon nothing more is coming while reading carries on, though the remote
+/// has no notion of a half open connection and only sees it end once the stream is dropped
+pub struct RouteWeaverStreamCompat {
+    stream: RouteWeaverStream,
+    /// Data left over from a message that did not fit in the last read
+    read_buffer: Vec<u8>,
+    read_offset: usize,
+}
+
+impl RouteWeaverStreamCompat {
+    pub fn new(stream: RouteWeaverStream) -> Self {
+        Self {
+            stream,
+            read_buffer: Vec::new(),
+            read_offset: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> RouteWeaverStream {
+        self.stream
+    }
+}
+
+impl From<RouteWeaverStream> for RouteWeaverStreamCompat {
+    fn from(stream: RouteWeaverStream) -> Self {
+        Self::new(stream)
+    }
+}
+
+impl AsyncRead for RouteWeaverStreamCompat {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Empty messages would otherwise look like the end of the stream
+        while self.read_offset >= self.read_buffer.len() {
+            match ready!(Pin::new(&mut self.stream).poll_next(cx)) {
+                Some(Ok(data)) => {
+                    self.read_buffer = data;
+                    self.read_offset = 0;
+                }
+                Some(Err(err)) => return Poll::Ready(Err(io::Error::other(err))),
+                None => return Poll::Ready(Ok(())),
+            }
+        }
+
+        let remaining = &self.read_buffer[self.read_offset..];
+        let amount = remaining.len().min(buf.remaining());
+        buf.put_slice(&remaining[..amount]);
+        self.read_offset += amount;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for RouteWeaverStreamCompat {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.stream).poll_ready(cx)).map_err(io::Error::other)?;
+
+        let amount = buf.len().min(MAX_WRITE_SIZE);
+        Pin::new(&mut self.stream)
+            .start_send(buf[..amount].to_vec())
+            .map_err(io::Error::other)?;
+
+        Poll::Ready(Ok(amount))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RouteWeaverStreamCompat;
+    use crate::ipc::{
+        stream::{
+            ClientBoundStreamIpc, RouteWeaverStream, ServerBoundStreamIpc, MAX_IN_FLIGHT_MESSAGES,
+        },
+        StreamAuthToken,
+    };
+    use std::time::Duration;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{UnixListener, UnixStream},
+        time::timeout,
+    };
+    use zeroize::Zeroizing;
+
+    async fn send(daemon: &mut UnixStream, message: ClientBoundStreamIpc) {
+        let encoded = bincode::serde::encode_to_vec(message, bincode::config::standard()).unwrap();
+        daemon.write_all(&encoded).await.unwrap();
+    }
+
+    /// Connects a stream to a stand in for the daemon, handling authentication
+    async fn connect(name: &str) -> (RouteWeaverStreamCompat, UnixStream) {
+        let path = std::env::temp_dir().join(format!(
+            "routeweaver-compat-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let (stream, daemon) = tokio::join!(
+            RouteWeaverStream::new(&path, StreamAuthToken::new([0; 32])),
+            async {
+                let (mut daemon, _) = listener.accept().await.unwrap();
+                let mut buffer = [0; 64];
+                let amount = daemon.read(&mut buffer).await.unwrap();
+                let (message, _): (ServerBoundStreamIpc, _) = bincode::serde::decode_from_slice(
+                    &buffer[..amount],
+                    bincode::config::standard(),
+                )
+                .unwrap();
+                assert!(matches!(message, ServerBoundStreamIpc::Auth { .. }));
+
+                send(&mut daemon, ClientBoundStreamIpc::AuthSuccess).await;
+                daemon
+            }
+        );
+        let _ = std::fs::remove_file(&path);
+
+        (RouteWeaverStreamCompat::new(stream.unwrap()), daemon)
+    }
+
+    #[tokio::test]
+    async fn partial_reads() {
+        let (mut stream, mut daemon) = connect("partial-reads").await;
+
+        send(
+            &mut daemon,
+            ClientBoundStreamIpc::Data {
+                data: Zeroizing::new(b"hello world".to_vec()),
+            },
+        )
+        .await;
+        send(
+            &mut daemon,
+            ClientBoundStreamIpc::Data {
+                data: Zeroizing::new(b"!".to_vec()),
+            },
+        )
+        .await;
+        drop(daemon);
+
+        let mut buffer = [0; 5];
+        let mut received = Vec::new();
+
+        loop {
+            let amount = stream.read(&mut buffer).await.unwrap();
+            if amount == 0 {
+                break;
+            }
+
+            assert!(amount <= buffer.len());
+            received.extend_from_slice(&buffer[..amount]);
+        }
+
+        assert_eq!(received, b"hello world!");
+    }
+
+    #[tokio::test]
+    async fn backpressure() {
+        let (mut stream, mut daemon) = connect("backpressure").await;
+
+        for _ in 0..MAX_IN_FLIGHT_MESSAGES {
+            stream.write_all(b"data").await.unwrap();
+        }
+
+        // Nothing has been confirmed, so this has to wait
+        assert!(
+            timeout(Duration::from_millis(100), stream.write_all(b"data"))
+                .await
+                .is_err()
+        );
+
+        send(&mut daemon, ClientBoundStreamIpc::DataSendingSuccess).await;
+
+        timeout(Duration::from_secs(1), stream.write_all(b"data"))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_keeps_reading() {
+        let (mut stream, mut daemon) = connect("shutdown").await;
+
+        stream.write_all(b"request").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert!(stream.write_all(b"more").await.is_err());
+
+        let mut buffer = [0; 64];
+        let mut received = Vec::new();
+        let mut messages = Vec::new();
+
+        while messages.len() < 2 {
+            let amount = daemon.read(&mut buffer).await.unwrap();
+            received.extend_from_slice(&buffer[..amount]);
+
+            while let Ok((message, used)) = bincode::serde::decode_from_slice::<
+                ServerBoundStreamIpc,
+                _,
+            >(&received, bincode::config::standard())
+            {
+                received.drain(..used);
+                messages.push(message);
+            }
+        }
+
+        assert!(
+            matches!(&messages[0], ServerBoundStreamIpc::Data { data } if data.as_slice() == b"request")
+        );
+        assert!(matches!(messages[1], ServerBoundStreamIpc::Shutdown));
+
+        send(
+            &mut daemon,
+            ClientBoundStreamIpc::Data {
+                data: Zeroizing::new(b"reply".to_vec()),
+            },
+        )
+        .await;
+        drop(daemon);
+
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+    }
+}
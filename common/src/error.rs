@@ -22,6 +22,20 @@ pub enum Error {
     UnexpectedIpcServerConnectionClose,
     #[error("Unexpected ipc server message")]
     UnexpectedIpcServerMessage,
+    #[error("Connection denied")]
+    ConnectionDenied,
+    #[error("Application already has a listener")]
+    ApplicationInUse,
+    #[error("Invalid acl rule")]
+    InvalidAclRule,
+    #[error("No such acl rule")]
+    NoSuchAclRule,
+    #[error("Protocol is not active")]
+    ProtocolNotActive,
+    #[error("Not connected")]
+    NotConnected,
+    #[error("Stream was shut down for writing")]
+    StreamShutDown,
     #[error("Bincode encoding error: {0}")]
     BincodeEncoding(#[from] bincode::error::EncodeError),
     #[error("Bincode decoding error: {0}")]
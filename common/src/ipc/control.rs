@@ -0,0 +1,342 @@
+use crate::{acl::AclRule, error::Error, ApplicationId, Peer, PublicKey};
+use bincode::error::DecodeError;
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{path::Path, time::Duration};
+use tokio::net::UnixStream;
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder, Framed},
+};
+
+struct ConnectionParser;
+
+impl Encoder<ServerBoundControlIpc> for ConnectionParser {
+    type Error = Error;
+
+    fn encode(
+        &mut self,
+        item: ServerBoundControlIpc,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        bincode::serde::encode_into_std_write(
+            &item,
+            &mut dst.writer(),
+            bincode::config::standard(),
+        )?;
+        Ok(())
+    }
+}
+
+impl Decoder for ConnectionParser {
+    type Item = ClientBoundControlIpc;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        match bincode::serde::decode_from_std_read(&mut src.reader(), bincode::config::standard()) {
+            Ok(item) => Ok(Some(item)),
+            Err(DecodeError::UnexpectedEnd { additional }) => {
+                src.reserve(additional);
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// How well the daemon thinks a link is doing
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LinkScore {
+    pub latency: Duration,
+    /// Between 0 and 1
+    pub failure_rate: f32,
+}
+
+/// Scores of the links to peers, and of the channels to nodes
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Scores {
+    pub peers: Vec<(Peer, LinkScore)>,
+    pub nodes: Vec<(PublicKey, LinkScore)>,
+}
+
+/// Where packets for a node go next
+///
+/// Neighbors show up as their own next hop, with the cost of the direct link as metric
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RouteEntry {
+    pub destination: PublicKey,
+    pub next_hop: PublicKey,
+    pub metric: u8,
+}
+
+/// Peer the daemon keeps reconnecting to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct DesiredPeer {
+    pub peer: Peer,
+    /// Configured peers are never given up on, unlike discovered ones
+    pub configured: bool,
+    pub connected: bool,
+    /// Attempts since it was last connected
+    pub failures: u32,
+    /// Time left until the next attempt, [Option::None] while connected
+    pub next_attempt: Option<Duration>,
+}
+
+/// Something that happened in the daemon
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ControlEvent {
+    PeerConnected { peer: Peer },
+    PeerDisconnected { peer: Peer },
+    ChannelEstablished { node: PublicKey },
+}
+
+/// Requests for managing the daemon itself, every one gets exactly one response
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ServerBoundControlIpc {
+    ShowPublicKey,
+    ListPeers,
+    ListHandshakes,
+    ListChannels,
+    ListRoutes,
+    ListLocalAddresses,
+    ListApplications,
+    ListScores,
+    ConnectPeer {
+        peer: Peer,
+    },
+    DisconnectPeer {
+        peer: Peer,
+    },
+    ListAclRules,
+    /// Rules are checked in order, [Option::None] puts it at the end
+    InsertAclRule {
+        index: Option<usize>,
+        rule: AclRule,
+    },
+    RemoveAclRule {
+        index: usize,
+    },
+    /// Turns the connection into a stream of [ClientBoundControlIpc::Event] after the response
+    SubscribeEvents,
+    ListDesiredPeers,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientBoundControlIpc {
+    Success,
+    PublicKey {
+        public_key: PublicKey,
+    },
+    Peers {
+        peers: Vec<Peer>,
+    },
+    Nodes {
+        nodes: Vec<PublicKey>,
+    },
+    Routes {
+        routes: Vec<RouteEntry>,
+    },
+    Applications {
+        applications: Vec<ApplicationId>,
+    },
+    Scores {
+        scores: Scores,
+    },
+    /// Nothing in the daemon handles the protocol of the peer
+    ProtocolNotActive,
+    NotConnected,
+    AclRules {
+        rules: Vec<AclRule>,
+    },
+    /// The rule referred to does not exist
+    NoSuchAclRule,
+    Event {
+        event: ControlEvent,
+    },
+    DesiredPeers {
+        peers: Vec<DesiredPeer>,
+    },
+}
+
+/// Connection to the daemons control socket
+pub struct RouteWeaverControl {
+    ipc_connection: Framed<UnixStream, ConnectionParser>,
+}
+
+impl RouteWeaverControl {
+    pub async fn connect(control_socket_path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self {
+            ipc_connection: Framed::new(
+                UnixStream::connect(control_socket_path).await?,
+                ConnectionParser,
+            ),
+        })
+    }
+
+    async fn request(
+        &mut self,
+        request: ServerBoundControlIpc,
+    ) -> Result<ClientBoundControlIpc, Error> {
+        self.ipc_connection.send(request).await?;
+
+        match self.ipc_connection.next().await {
+            Some(response) => response,
+            None => Err(Error::UnexpectedIpcServerConnectionClose),
+        }
+    }
+
+    /// Public key of the node the daemon runs
+    pub async fn public_key(&mut self) -> Result<PublicKey, Error> {
+        match self.request(ServerBoundControlIpc::ShowPublicKey).await? {
+            ClientBoundControlIpc::PublicKey { public_key } => Ok(public_key),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Peers we have a connection with
+    pub async fn peers(&mut self) -> Result<Vec<Peer>, Error> {
+        match self.request(ServerBoundControlIpc::ListPeers).await? {
+            ClientBoundControlIpc::Peers { peers } => Ok(peers),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Nodes we are in the middle of setting up a channel with
+    pub async fn handshakes(&mut self) -> Result<Vec<PublicKey>, Error> {
+        match self.request(ServerBoundControlIpc::ListHandshakes).await? {
+            ClientBoundControlIpc::Nodes { nodes } => Ok(nodes),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Nodes we have a channel with
+    pub async fn channels(&mut self) -> Result<Vec<PublicKey>, Error> {
+        match self.request(ServerBoundControlIpc::ListChannels).await? {
+            ClientBoundControlIpc::Nodes { nodes } => Ok(nodes),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    pub async fn routes(&mut self) -> Result<Vec<RouteEntry>, Error> {
+        match self.request(ServerBoundControlIpc::ListRoutes).await? {
+            ClientBoundControlIpc::Routes { routes } => Ok(routes),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Addresses the daemon can be reached at
+    pub async fn local_addresses(&mut self) -> Result<Vec<Peer>, Error> {
+        match self
+            .request(ServerBoundControlIpc::ListLocalAddresses)
+            .await?
+        {
+            ClientBoundControlIpc::Peers { peers } => Ok(peers),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Applications some service is listening on
+    pub async fn applications(&mut self) -> Result<Vec<ApplicationId>, Error> {
+        match self
+            .request(ServerBoundControlIpc::ListApplications)
+            .await?
+        {
+            ClientBoundControlIpc::Applications { applications } => Ok(applications),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    pub async fn scores(&mut self) -> Result<Scores, Error> {
+        match self.request(ServerBoundControlIpc::ListScores).await? {
+            ClientBoundControlIpc::Scores { scores } => Ok(scores),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Peers the daemon keeps reconnecting to, and how that is going
+    pub async fn desired_peers(&mut self) -> Result<Vec<DesiredPeer>, Error> {
+        match self
+            .request(ServerBoundControlIpc::ListDesiredPeers)
+            .await?
+        {
+            ClientBoundControlIpc::DesiredPeers { peers } => Ok(peers),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Asks the daemon to keep connected to a peer, this returns before the connection is actually made
+    pub async fn connect_peer(&mut self, peer: Peer) -> Result<(), Error> {
+        match self
+            .request(ServerBoundControlIpc::ConnectPeer { peer })
+            .await?
+        {
+            ClientBoundControlIpc::Success => Ok(()),
+            ClientBoundControlIpc::ProtocolNotActive => Err(Error::ProtocolNotActive),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Drops the connection to a peer and stops reconnecting to it
+    pub async fn disconnect_peer(&mut self, peer: Peer) -> Result<(), Error> {
+        match self
+            .request(ServerBoundControlIpc::DisconnectPeer { peer })
+            .await?
+        {
+            ClientBoundControlIpc::Success => Ok(()),
+            ClientBoundControlIpc::NotConnected => Err(Error::NotConnected),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    pub async fn acl_rules(&mut self) -> Result<Vec<AclRule>, Error> {
+        match self.request(ServerBoundControlIpc::ListAclRules).await? {
+            ClientBoundControlIpc::AclRules { rules } => Ok(rules),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    pub async fn insert_acl_rule(
+        &mut self,
+        index: Option<usize>,
+        rule: AclRule,
+    ) -> Result<(), Error> {
+        match self
+            .request(ServerBoundControlIpc::InsertAclRule { index, rule })
+            .await?
+        {
+            ClientBoundControlIpc::Success => Ok(()),
+            ClientBoundControlIpc::NoSuchAclRule => Err(Error::NoSuchAclRule),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    pub async fn remove_acl_rule(&mut self, index: usize) -> Result<(), Error> {
+        match self
+            .request(ServerBoundControlIpc::RemoveAclRule { index })
+            .await?
+        {
+            ClientBoundControlIpc::Success => Ok(()),
+            ClientBoundControlIpc::NoSuchAclRule => Err(Error::NoSuchAclRule),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }
+    }
+
+    /// Follows what the daemon is doing, no more requests can be made over this connection afterwards
+    pub async fn events(
+        mut self,
+    ) -> Result<impl Stream<Item = Result<ControlEvent, Error>>, Error> {
+        match self.request(ServerBoundControlIpc::SubscribeEvents).await? {
+            ClientBoundControlIpc::Success => {}
+            _ => return Err(Error::UnexpectedIpcServerMessage),
+        }
+
+        Ok(self.ipc_connection.map(|message| match message? {
+            ClientBoundControlIpc::Event { event } => Ok(event),
+            _ => Err(Error::UnexpectedIpcServerMessage),
+        }))
+    }
+}
@@ -2,6 +2,7 @@ use std::{path::PathBuf, sync::LazyLock};
 
 use serde::{Deserialize, Serialize};
 
+pub mod control;
 pub mod socket;
 pub mod stream;
 
@@ -15,6 +16,9 @@ pub static RPC_BASE_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
 
 pub static DAEMON_RPC_SOCKET: LazyLock<PathBuf> = LazyLock::new(|| RPC_BASE_DIR.join("ipc"));
 
+pub static DAEMON_CONTROL_SOCKET: LazyLock<PathBuf> =
+    LazyLock::new(|| RPC_BASE_DIR.join("control"));
+
 pub static SERVICE_RPC_BASE_DIRECTORY: LazyLock<PathBuf> =
     LazyLock::new(|| RPC_BASE_DIR.join("service"));
 
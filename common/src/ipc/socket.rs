@@ -1,6 +1,8 @@
 use crate::{error::Error, ApplicationId, PublicKey};
 use bincode::error::DecodeError;
-use futures_util::{SinkExt, Stream, StreamExt};
+use futures_util::{
+    future::BoxFuture, stream::FuturesUnordered, FutureExt, SinkExt, Stream, StreamExt,
+};
 use serde::{Deserialize, Serialize};
 use std::{
     path::{Path, PathBuf},
@@ -54,12 +56,16 @@ impl Decoder for ConnectionParser {
     }
 }
 
+/// Picked by the service, so it can tell which of its connection requests an answer is for
+pub type ConnectRequestId = u32;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerBoundSocketIpc {
     Listen {
         application_id: ApplicationId,
     },
     Connect {
+        request_id: ConnectRequestId,
         application_id: ApplicationId,
         destination: PublicKey,
     },
@@ -68,7 +74,21 @@ pub enum ServerBoundSocketIpc {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientBoundSocketIpc {
     Success,
+    /// The remote node accepted the connection request
     Stream {
+        request_id: ConnectRequestId,
+        stream_socket_path: PathBuf,
+        stream_auth_token: StreamAuthToken,
+    },
+    /// Another service is already listening on the application
+    Denied,
+    /// The remote node refused the connection request, or could not be reached
+    ConnectionDenied {
+        request_id: ConnectRequestId,
+    },
+    /// A remote node connected to the application we are listening on
+    IncomingConnection {
+        node: PublicKey,
         stream_socket_path: PathBuf,
         stream_auth_token: StreamAuthToken,
     },
@@ -76,6 +96,11 @@ pub enum ClientBoundSocketIpc {
 
 pub struct RouteWeaverSocket {
     ipc_connection: Framed<UnixStream, ConnectionParser>,
+    /// Incoming connections whose stream socket we are still authenticating with
+    pending_streams:
+        FuturesUnordered<BoxFuture<'static, Result<(PublicKey, RouteWeaverStream), Error>>>,
+    /// Whether the daemon closed the ipc connection
+    closed: bool,
 }
 
 impl RouteWeaverSocket {
@@ -84,11 +109,15 @@ impl RouteWeaverSocket {
         node: PublicKey,
         application: ApplicationId,
     ) -> Result<RouteWeaverStream, Error> {
+        // Every request gets an ipc connection of its own, so any id will do
+        const REQUEST_ID: ConnectRequestId = 0;
+
         let stream = UnixStream::connect(&ipc_server_path).await?;
         let mut ipc_connection = Framed::new(stream, ConnectionParser);
 
         ipc_connection
             .send(ServerBoundSocketIpc::Connect {
+                request_id: REQUEST_ID,
                 application_id: application,
                 destination: node,
             })
@@ -105,9 +134,13 @@ impl RouteWeaverSocket {
         match ipc_connection.next().await {
             // If we actually got the message
             Some(Ok(ClientBoundSocketIpc::Stream {
+                request_id: REQUEST_ID,
                 stream_socket_path,
                 stream_auth_token,
             })) => Ok(RouteWeaverStream::new(stream_socket_path, stream_auth_token).await?),
+            Some(Ok(ClientBoundSocketIpc::ConnectionDenied {
+                request_id: REQUEST_ID,
+            })) => Err(Error::ConnectionDenied),
             // If we got some other message
             Some(Ok(_)) => Err(Error::UnexpectedIpcServerMessage),
             Some(Err(err)) => Err(err),
@@ -131,19 +164,62 @@ impl RouteWeaverSocket {
 
         match ipc_connection.next().await {
             Some(Ok(ClientBoundSocketIpc::Success)) => {}
+            Some(Ok(ClientBoundSocketIpc::Denied)) => return Err(Error::ApplicationInUse),
             Some(Ok(_)) => return Err(Error::UnexpectedIpcServerMessage),
             Some(Err(e)) => return Err(e),
             None => return Err(Error::UnexpectedIpcServerConnectionClose),
         }
 
-        Ok(Self { ipc_connection })
+        Ok(Self {
+            ipc_connection,
+            pending_streams: FuturesUnordered::new(),
+            closed: false,
+        })
     }
 }
 
 impl Stream for RouteWeaverSocket {
     type Item = Result<(PublicKey, RouteWeaverStream), Error>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        todo!()
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Poll::Ready(Some(stream)) = self.pending_streams.poll_next_unpin(cx) {
+                return Poll::Ready(Some(stream));
+            }
+
+            if self.closed {
+                // Let whatever is still authenticating finish before ending
+                return if self.pending_streams.is_empty() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                };
+            }
+
+            match self.ipc_connection.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(ClientBoundSocketIpc::IncomingConnection {
+                    node,
+                    stream_socket_path,
+                    stream_auth_token,
+                }))) => {
+                    self.pending_streams.push(
+                        async move {
+                            Ok((
+                                node,
+                                RouteWeaverStream::new(stream_socket_path, stream_auth_token)
+                                    .await?,
+                            ))
+                        }
+                        .boxed(),
+                    );
+                }
+                Poll::Ready(Some(Ok(_))) => {
+                    return Poll::Ready(Some(Err(Error::UnexpectedIpcServerMessage)))
+                }
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => self.closed = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
     }
 }
@@ -1,29 +1,41 @@
 use super::{socket::RouteWeaverSocket, StreamAuthToken};
 use crate::{error::Error, ApplicationId, PublicKey};
 use bincode::error::DecodeError;
-use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use futures_util::{ready, Sink, SinkExt, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::VecDeque,
     path::Path,
     pin::Pin,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
 };
 use tokio::net::UnixStream;
 use tokio_util::{
     bytes::{Buf, BufMut, BytesMut},
     codec::{Decoder, Encoder, Framed},
 };
+use zeroize::Zeroizing;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ServerBoundStreamIpc {
-    Auth { token: StreamAuthToken },
-    Data { data: Vec<u8> },
+    Auth {
+        token: StreamAuthToken,
+    },
+    Data {
+        data: Vec<u8>,
+    },
+    /// Nothing more will be written, but whatever the remote sends is still read
+    Shutdown,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum ClientBoundStreamIpc {
     AuthSuccess,
     DataSendingSuccess,
+    /// Data the remote sent us
+    Data {
+        data: Zeroizing<Vec<u8>>,
+    },
 }
 
 struct ConnectionParser;
@@ -65,8 +77,23 @@ impl Decoder for ConnectionParser {
     }
 }
 
+/// How many messages can be waiting on the daemon to confirm they were sent before writing stalls
+pub const MAX_IN_FLIGHT_MESSAGES: usize = 16;
+
 pub struct RouteWeaverStream {
     ipc_connection: Framed<UnixStream, ConnectionParser>,
+    /// Data read off the ipc connection while waiting on something else
+    received: VecDeque<Vec<u8>>,
+    /// Messages handed to the daemon that it has not confirmed yet
+    in_flight: usize,
+    /// Whether the daemon closed the ipc connection
+    closed: bool,
+    /// Whether we told the daemon nothing more will be written
+    shut_down: bool,
+    /// Reading and writing can happen from different tasks, but only the last one to poll the ipc connection gets
+    /// woken by it, so whoever reads something meant for the other wakes them up
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
 }
 
 impl RouteWeaverStream {
@@ -100,15 +127,74 @@ impl RouteWeaverStream {
             None => return Err(Error::UnexpectedIpcServerConnectionClose),
         }
 
-        Ok(Self { ipc_connection })
+        Ok(Self {
+            ipc_connection,
+            received: VecDeque::new(),
+            in_flight: 0,
+            closed: false,
+            shut_down: false,
+            read_waker: None,
+            write_waker: None,
+        })
+    }
+
+    /// Reads one message off the ipc connection and files it away
+    fn poll_ipc(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match ready!(self.ipc_connection.poll_next_unpin(cx)) {
+            Some(Ok(ClientBoundStreamIpc::Data { data })) => {
+                // Whatever is handed out is the application's to look after, the copy read off the wire is wiped
+                self.received.push_back(data.to_vec());
+
+                if let Some(waker) = self.read_waker.take() {
+                    waker.wake();
+                }
+            }
+            Some(Ok(ClientBoundStreamIpc::DataSendingSuccess)) => {
+                self.in_flight = self.in_flight.saturating_sub(1);
+
+                if let Some(waker) = self.write_waker.take() {
+                    waker.wake();
+                }
+            }
+            Some(Ok(ClientBoundStreamIpc::AuthSuccess)) => {
+                return Poll::Ready(Err(Error::UnexpectedIpcServerMessage))
+            }
+            Some(Err(err)) => return Poll::Ready(Err(err)),
+            None => {
+                self.closed = true;
+
+                if let Some(waker) = self.read_waker.take() {
+                    waker.wake();
+                }
+                if let Some(waker) = self.write_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+
+        Poll::Ready(Ok(()))
     }
 }
 
 impl Stream for RouteWeaverStream {
     type Item = Result<Vec<u8>, Error>;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        todo!()
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(data) = self.received.pop_front() {
+                return Poll::Ready(Some(Ok(data)));
+            }
+
+            if self.closed {
+                return Poll::Ready(None);
+            }
+
+            self.read_waker = Some(cx.waker().clone());
+
+            if let Err(err) = ready!(self.poll_ipc(cx)) {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
     }
 }
 
@@ -116,30 +202,45 @@ impl Sink<Vec<u8>> for RouteWeaverStream {
     type Error = Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        if self.ipc_connection.poll_ready_unpin(cx).is_ready() {
-            return Poll::Ready(Ok(()));
+        if self.shut_down {
+            return Poll::Ready(Err(Error::StreamShutDown));
         }
 
-        Poll::Pending
+        // Hold off until the daemon has caught up with what we already gave it
+        while self.in_flight >= MAX_IN_FLIGHT_MESSAGES {
+            if self.closed {
+                return Poll::Ready(Err(Error::UnexpectedIpcServerConnectionClose));
+            }
+
+            self.write_waker = Some(cx.waker().clone());
+            ready!(self.poll_ipc(cx))?;
+        }
+
+        self.ipc_connection.poll_ready_unpin(cx)
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
         let message = ServerBoundStreamIpc::Data { data: item };
 
-        self.ipc_connection
-            .start_send_unpin(message)
-            .map_err(Error::from)
+        self.ipc_connection.start_send_unpin(message)?;
+        self.in_flight += 1;
+
+        Ok(())
     }
 
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.ipc_connection
-            .poll_flush_unpin(cx)
-            .map_err(Error::from)
+        self.ipc_connection.poll_flush_unpin(cx)
     }
 
+    /// Only ends our side, reading carries on until the remote closes. Dropping the stream closes all of it
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.ipc_connection
-            .poll_close_unpin(cx)
-            .map_err(Error::from)
+        if !self.shut_down {
+            ready!(self.ipc_connection.poll_ready_unpin(cx))?;
+            self.ipc_connection
+                .start_send_unpin(ServerBoundStreamIpc::Shutdown)?;
+            self.shut_down = true;
+        }
+
+        self.ipc_connection.poll_flush_unpin(cx)
     }
 }
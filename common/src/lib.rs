@@ -14,6 +14,8 @@ use zeroize::Zeroize;
 mod error;
 pub use error::Error as RouteWeaverCommonError;
 
+pub mod acl;
+pub mod compat;
 pub mod ipc;
 
 /// Context id to identify a connection
@@ -21,12 +23,21 @@ pub mod ipc;
 /// Unique between two nodes
 pub type ConnectionId = u32;
 
+/// Longest path a unix socket can be bound to
+pub const MAX_UNIX_PATH_LENGTH: usize = 108;
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Address {
     /// IP
     Ip { address: IpAddr, port: u16 },
     /// Bluetooth
     Bluetooth { address: [u8; 6], psm: u16 },
+    /// Unix socket
+    ///
+    /// Connections coming in from unnamed sockets get a number in place of a path so they can be told apart
+    Unix {
+        path: ArrayString<MAX_UNIX_PATH_LENGTH>,
+    },
 }
 
 impl From<SocketAddr> for Address {
@@ -70,7 +81,7 @@ impl TryFrom<Address> for SocketAddr {
     fn try_from(value: Address) -> Result<Self, Self::Error> {
         match value {
             Address::Ip { address, port } => Ok(SocketAddr::new(address, port)),
-            Address::Bluetooth { .. } => Err(Error::InvalidAddress),
+            Address::Bluetooth { .. } | Address::Unix { .. } => Err(Error::InvalidAddress),
         }
     }
 }
@@ -84,6 +95,7 @@ impl Display for Address {
                 "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{}",
                 address[0], address[1], address[2], address[3], address[4], address[5], psm
             ),
+            Address::Unix { path } => f.write_str(path),
         }
     }
 }
@@ -95,6 +107,8 @@ pub enum Protocol {
     Ws,
     Wss,
     Bluetooth,
+    Quic,
+    Unix,
 }
 
 impl FromStr for Protocol {
@@ -106,6 +120,8 @@ impl FromStr for Protocol {
             "udp" => Ok(Protocol::Udp),
             "ws" => Ok(Protocol::Ws),
             "wss" => Ok(Protocol::Wss),
+            "quic" => Ok(Protocol::Quic),
+            "unix" => Ok(Protocol::Unix),
             "bluetooth" => Ok(Protocol::Bluetooth),
             _ => Err(Error::InvalidProtocol),
         }
@@ -122,6 +138,8 @@ impl Display for Protocol {
                 Protocol::Tcp => "tcp",
                 Protocol::Ws => "ws",
                 Protocol::Wss => "wss",
+                Protocol::Quic => "quic",
+                Protocol::Unix => "unix",
                 Protocol::Bluetooth => "bluetooth",
             }
         )
@@ -139,6 +157,7 @@ impl Peer {
         match self.address {
             Address::Ip { address, .. } => address.is_loopback(),
             Address::Bluetooth { .. } => false,
+            Address::Unix { .. } => true,
         }
     }
 }
@@ -217,6 +236,16 @@ impl FromStr for Peer {
                         psm,
                     }
                 }
+                // The rest is the path as is, so absolute paths end up after a double slash
+                "path" => {
+                    if address.is_empty() {
+                        return Err(Error::InvalidAddress);
+                    }
+
+                    Address::Unix {
+                        path: ArrayString::from(address).map_err(|_| Error::InvalidAddress)?,
+                    }
+                }
                 _ => return Err(Error::InvalidAddress),
             },
         })
@@ -243,6 +272,7 @@ impl Display for Peer {
                     psm
                 )
             }
+            Address::Unix { path } => write!(f, "/{}/path/{}", self.protocol, path),
         }
     }
 }
@@ -329,7 +359,8 @@ mod tests {
 
     use url::Url;
 
-    use crate::{Address, Peer, Protocol};
+    use crate::{Address, Peer, Protocol, MAX_UNIX_PATH_LENGTH};
+    use arrayvec::ArrayString;
 
     #[test]
     fn parse_ipv4_peer() {
@@ -417,6 +448,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_quic_peer() {
+        let peer = "/quic/ip/192.168.1.2/3438".parse::<Peer>().unwrap();
+        assert_eq!(peer.protocol, Protocol::Quic);
+        assert_eq!(
+            peer.address,
+            Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)),
+                port: 3438
+            }
+        );
+        assert_eq!("/quic/ip/192.168.1.2/3438", peer.to_string());
+    }
+
+    #[test]
+    fn parse_unix_peer() {
+        let peer = "/unix/path//run/x.sock".parse::<Peer>().unwrap();
+        assert_eq!(peer.protocol, Protocol::Unix);
+        assert_eq!(
+            peer.address,
+            Address::Unix {
+                path: ArrayString::from("/run/x.sock").unwrap()
+            }
+        );
+        assert_eq!("/unix/path//run/x.sock", peer.to_string());
+
+        assert!("/unix/path/".parse::<Peer>().is_err());
+        assert!(
+            format!("/unix/path/{}", "a".repeat(MAX_UNIX_PATH_LENGTH + 1))
+                .parse::<Peer>()
+                .is_err()
+        );
+    }
+
     #[test]
     fn parse_bluetooth_peer() {
         let peer = "/bluetooth/bluetooth/00:11:22:33:44:55/66"
@@ -50,6 +50,29 @@ fn main() {
                 )
             )
         },
+        transport_quic: {
+            all(
+                feature = "transport-quic",
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    target_os = "freebsd",
+                    target_os = "openbsd",
+                    target_os = "windows"
+                )
+            )
+        },
+        transport_unix: {
+            all(
+                feature = "transport-unix",
+                any(
+                    target_os = "linux",
+                    target_os = "macos",
+                    target_os = "freebsd",
+                    target_os = "openbsd"
+                )
+            )
+        },
         transport_bluetooth: {
             all(
                 feature = "transport-bluetooth",
@@ -0,0 +1,174 @@
+use crate::{channel::lifetime::close_channel, noise::PresharedKey, state::ServerState};
+use routeweaver_common::{
+    acl::{AclAction, AclRule},
+    Peer, PublicKey,
+};
+use std::collections::HashSet;
+use tokio::sync::RwLock;
+
+/// Fixed at startup for private deployments, unlike the acl
+#[derive(Debug, Default)]
+pub struct MeshTrust {
+    /// Mixed into every handshake so only nodes knowing it can make channels with us
+    pub psk: Option<PresharedKey>,
+    /// The only nodes we make channels with, anyone is fine if not set
+    pub trusted_nodes: Option<HashSet<PublicKey>>,
+}
+
+impl MeshTrust {
+    pub fn is_node_trusted(&self, node: &PublicKey) -> bool {
+        self.trusted_nodes
+            .as_ref()
+            .is_none_or(|trusted_nodes| trusted_nodes.contains(node))
+    }
+}
+
+/// Ordered list of rules deciding who we talk to
+///
+/// The first rule that applies wins, and anything no rule applies to is allowed
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    rules: RwLock<Vec<AclRule>>,
+}
+
+impl AccessControl {
+    pub async fn is_peer_allowed(&self, peer: &Peer) -> bool {
+        self.rules
+            .read()
+            .await
+            .iter()
+            .find(|rule| rule.matcher.matches_peer(peer))
+            .is_none_or(|rule| rule.action == AclAction::Allow)
+    }
+
+    pub async fn is_node_allowed(&self, node: &PublicKey) -> bool {
+        self.rules
+            .read()
+            .await
+            .iter()
+            .find(|rule| rule.matcher.matches_node(node))
+            .is_none_or(|rule| rule.action == AclAction::Allow)
+    }
+
+    pub async fn rules(&self) -> Vec<AclRule> {
+        self.rules.read().await.clone()
+    }
+
+    /// Returns false if the index is past the end of the list
+    pub async fn insert(&self, index: Option<usize>, rule: AclRule) -> bool {
+        let mut rules = self.rules.write().await;
+        let index = index.unwrap_or(rules.len());
+
+        if index > rules.len() {
+            return false;
+        }
+
+        rules.insert(index, rule);
+        true
+    }
+
+    pub async fn remove(&self, index: usize) -> Option<AclRule> {
+        let mut rules = self.rules.write().await;
+
+        (index < rules.len()).then(|| rules.remove(index))
+    }
+}
+
+/// Drops connections and channels that the current rules no longer allow
+pub async fn enforce_acl(server_state: &ServerState) {
+    for peer in server_state.peer_tracker.peers().await {
+        if !server_state.access_control.is_peer_allowed(&peer).await {
+            tracing::info!("Disconnecting from {} as it is no longer allowed", peer);
+            server_state.peer_tracker.disconnect(&peer).await;
+        }
+    }
+
+    let mut nodes = Vec::new();
+    server_state
+        .transport_tracker
+        .scan_async(|node, _| nodes.push(*node))
+        .await;
+    server_state
+        .handshake_tracker
+        .scan_async(|node, _| nodes.push(*node))
+        .await;
+
+    for node in nodes {
+        if !server_state.access_control.is_node_allowed(&node).await {
+            tracing::info!("Dropping channel with {} as it is no longer allowed", node);
+            close_channel(server_state, node).await;
+            server_state.handshake_tracker.remove_async(&node).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccessControl;
+    use routeweaver_common::{
+        acl::{AclMatcher, AclRule},
+        Address, Peer, Protocol, PublicKey,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn peer(id: u8) -> Peer {
+        Peer {
+            protocol: Protocol::Tcp,
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, id)),
+                port: 3434,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn first_match_wins() {
+        let access_control = AccessControl::default();
+        access_control
+            .insert(None, AclRule::allow(AclMatcher::Peer(peer(1))))
+            .await;
+        access_control
+            .insert(
+                None,
+                AclRule::deny(AclMatcher::IpRange("10.0.0.0/8".parse().unwrap())),
+            )
+            .await;
+
+        assert!(access_control.is_peer_allowed(&peer(1)).await);
+        assert!(!access_control.is_peer_allowed(&peer(2)).await);
+        assert!(
+            access_control
+                .is_node_allowed(&PublicKey::new([1; 32]))
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn rules_change_at_runtime() {
+        let access_control = AccessControl::default();
+        let node = PublicKey::new([1; 32]);
+
+        assert!(
+            access_control
+                .insert(None, AclRule::deny(AclMatcher::Node(node)))
+                .await
+        );
+        assert!(!access_control.is_node_allowed(&node).await);
+
+        assert!(
+            !access_control
+                .insert(Some(5), AclRule::allow(AclMatcher::Node(node)))
+                .await
+        );
+        assert!(
+            access_control
+                .insert(Some(0), AclRule::allow(AclMatcher::Node(node)))
+                .await
+        );
+        assert!(access_control.is_node_allowed(&node).await);
+
+        assert!(access_control.remove(0).await.is_some());
+        assert!(access_control.remove(1).await.is_none());
+        assert!(!access_control.is_node_allowed(&node).await);
+    }
+}
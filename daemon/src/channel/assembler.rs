@@ -2,6 +2,8 @@ use crate::{
     proto::Message,
     transport::packet::{MessageId, MAX_PACKET_PAYLOAD_SIZE},
 };
+use bincode::error::DecodeError;
+use rangemap::RangeInclusiveSet;
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 use std::num::NonZero;
 
@@ -33,6 +35,13 @@ impl Default for PendingMessage {
     }
 }
 
+/// What has made it of a message so far, told back to its sender
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageProgress {
+    pub head: bool,
+    pub bodies: RangeInclusiveSet<u16>,
+}
+
 #[derive(Debug)]
 pub struct MessageAssembler {
     /// Wrapping id for the last message pulled out
@@ -55,13 +64,29 @@ impl Default for MessageAssembler {
 }
 
 impl MessageAssembler {
+    /// Where a message sits among the pending ones, the ring buffer wraps around so anything further out would
+    /// land on some other message
+    fn entry_index(&self, message_id: MessageId) -> Option<usize> {
+        let index = message_id.wrapping_sub(self.current_message_id) as usize;
+
+        (index < MARGIN_OF_OUT_OF_ORDER_ALLOWED).then_some(index)
+    }
+
     pub fn head(&mut self, message_id: MessageId, body_count: NonZero<u16>, compression: bool) {
-        let preassembled_message_entry_index = message_id.wrapping_sub(self.current_message_id);
+        let Some(preassembled_message_entry_index) = self.entry_index(message_id) else {
+            return;
+        };
 
         if let Some(entry) = self
             .pending_messages
-            .get_mut(preassembled_message_entry_index as usize)
+            .get_mut(preassembled_message_entry_index)
         {
+            // Anything bigger than this would not fit in what we track, and could make us allocate a lot
+            if body_count.get() as usize > MAX_BODIES_PER_MESSAGE {
+                entry.take();
+                return;
+            }
+
             let entry = entry.get_or_insert_with(PendingMessage::default);
 
             let calculated_bodies_size = body_count.get() as usize * MAX_PACKET_PAYLOAD_SIZE
@@ -71,7 +96,7 @@ impl MessageAssembler {
                     .unwrap_or(0);
 
             if entry.bodies.len() > calculated_bodies_size {
-                self.pending_messages[preassembled_message_entry_index as usize].take();
+                self.pending_messages[preassembled_message_entry_index].take();
                 return;
             }
 
@@ -83,22 +108,29 @@ impl MessageAssembler {
     }
 
     pub fn body(&mut self, message_id: MessageId, index: u16, data: Vec<u8>) {
-        let preassembled_message_entry_index = message_id.wrapping_sub(self.current_message_id);
+        let Some(preassembled_message_entry_index) = self.entry_index(message_id) else {
+            return;
+        };
 
         if let Some(entry) = self
             .pending_messages
-            .get_mut(preassembled_message_entry_index as usize)
+            .get_mut(preassembled_message_entry_index)
         {
+            if index as usize >= MAX_BODIES_PER_MESSAGE {
+                entry.take();
+                return;
+            }
+
             let entry = entry.get_or_insert_with(PendingMessage::default);
 
             if data.is_empty() {
-                self.pending_messages[preassembled_message_entry_index as usize].take();
+                self.pending_messages[preassembled_message_entry_index].take();
                 return;
             }
 
             // No overflowing
             if data.len() > MAX_PACKET_PAYLOAD_SIZE {
-                self.pending_messages[preassembled_message_entry_index as usize].take();
+                self.pending_messages[preassembled_message_entry_index].take();
                 return;
             }
 
@@ -107,7 +139,7 @@ impl MessageAssembler {
                 if let Some(final_partial_body_length) = entry.final_partial_body_length {
                     // Final data length was already set but now its different, discard
                     if final_partial_body_length.get() as usize != data.len() {
-                        self.pending_messages[preassembled_message_entry_index as usize].take();
+                        self.pending_messages[preassembled_message_entry_index].take();
                         return;
                     }
                 }
@@ -126,7 +158,7 @@ impl MessageAssembler {
 
             if let Some(total_bodies) = entry.total_bodies {
                 if total_bodies.get() <= index {
-                    self.pending_messages[preassembled_message_entry_index as usize].take();
+                    self.pending_messages[preassembled_message_entry_index].take();
                     return;
                 }
             }
@@ -151,14 +183,13 @@ impl MessageAssembler {
             let chunk = entry
                 .bodies
                 .chunks_mut(MAX_PACKET_PAYLOAD_SIZE)
-                .nth(index as usize)
-                .unwrap();
+                .nth(index as usize);
 
             // This would happen in the event that the remote is lying in the Head
-            if chunk.len() != data.len() {
-                self.pending_messages[preassembled_message_entry_index as usize].take();
+            let Some(chunk) = chunk.filter(|chunk| chunk.len() == data.len()) else {
+                self.pending_messages[preassembled_message_entry_index].take();
                 return;
-            }
+            };
 
             chunk.copy_from_slice(&data);
             entry.committed_bodies[index as usize] = true;
@@ -166,11 +197,13 @@ impl MessageAssembler {
     }
 
     pub fn is_finished(&self, message_id: MessageId) -> bool {
-        let preassembled_message_entry_index = message_id.wrapping_sub(self.current_message_id);
+        let Some(preassembled_message_entry_index) = self.entry_index(message_id) else {
+            return false;
+        };
 
         if let Some(entry) = self
             .pending_messages
-            .get(preassembled_message_entry_index as usize)
+            .get(preassembled_message_entry_index)
             .into_iter()
             .flatten()
             .next()
@@ -187,6 +220,47 @@ impl MessageAssembler {
         false
     }
 
+    /// Messages already pulled out count as having fully arrived, so a sender that missed hearing about it stops
+    ///
+    /// [Option::None] for ids too far ahead to say anything about
+    pub fn progress(&self, message_id: MessageId) -> Option<MessageProgress> {
+        let preassembled_message_entry_index = self.entry_index(message_id);
+
+        if let Some(entry) =
+            preassembled_message_entry_index.and_then(|index| self.pending_messages.get(index))
+        {
+            let Some(entry) = entry else {
+                return Some(MessageProgress {
+                    head: false,
+                    bodies: RangeInclusiveSet::new(),
+                });
+            };
+
+            return Some(MessageProgress {
+                head: entry.head_arrived,
+                bodies: entry
+                    .committed_bodies
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, committed)| **committed)
+                    .map(|(index, _)| index as u16..=index as u16)
+                    .collect(),
+            });
+        }
+
+        self.is_delivered(message_id).then(|| MessageProgress {
+            head: true,
+            bodies: RangeInclusiveSet::from_iter([0..=u16::MAX]),
+        })
+    }
+
+    /// If the message was already pulled out, going by half the id space being behind us
+    pub fn is_delivered(&self, message_id: MessageId) -> bool {
+        let behind = self.current_message_id.wrapping_sub(message_id);
+
+        behind != 0 && behind <= MessageId::MAX / 2
+    }
+
     fn next(&mut self) -> Option<(Vec<u8>, MessageId)> {
         if self.is_finished(self.current_message_id) {
             let entry = self.pending_messages.dequeue().flatten().unwrap();
@@ -206,10 +280,11 @@ impl MessageAssembler {
         None
     }
 
-    pub fn next_message(&mut self) -> Option<(MessageId, Message)> {
+    /// Next finished message, which may not decode if it's of a kind newer than we know of
+    pub fn next_message(&mut self) -> Option<(MessageId, Result<Message, DecodeError>)> {
         let (bodies, message_id) = self.next()?;
-        let (message, _) =
-            bincode::serde::decode_from_slice(&bodies, bincode::config::standard()).ok()?;
+        let message = bincode::serde::decode_from_slice(&bodies, bincode::config::standard())
+            .map(|(message, _)| message);
 
         Some((message_id, message))
     }
@@ -218,11 +293,12 @@ impl MessageAssembler {
 // Code for the payload tracker is not pretty so we form a bunch of tests
 #[cfg(test)]
 mod tests {
-    use super::MARGIN_OF_OUT_OF_ORDER_ALLOWED;
+    use super::{MARGIN_OF_OUT_OF_ORDER_ALLOWED, MAX_BODIES_PER_MESSAGE};
     use crate::{
         channel::assembler::MessageAssembler,
         transport::packet::{MessageId, MAX_PACKET_PAYLOAD_SIZE},
     };
+    use rangemap::RangeInclusiveSet;
     use std::num::NonZero;
 
     #[test]
@@ -399,6 +475,28 @@ mod tests {
         assert_eq!(None, tracker.next());
     }
 
+    #[test]
+    fn resent_message_outside_window() {
+        let mut tracker = MessageAssembler::default();
+        let body = vec![0, 1, 2, 3];
+
+        tracker.head(0, NonZero::new(1).unwrap(), false);
+        tracker.body(0, 0, body.clone());
+        assert_eq!(Some((body.clone(), 0)), tracker.next());
+
+        // Already pulled out, so this mustn't end up in place of some later message
+        tracker.head(0, NonZero::new(1).unwrap(), false);
+        tracker.body(0, 0, vec![4, 5]);
+
+        for i in 1..MARGIN_OF_OUT_OF_ORDER_ALLOWED as MessageId {
+            tracker.head(i, NonZero::new(1).unwrap(), false);
+            tracker.body(i, 0, body.clone());
+            assert_eq!(Some((body.clone(), i)), tracker.next());
+        }
+
+        assert_eq!(None, tracker.next());
+    }
+
     #[test]
     fn incorrect_first_message_correct_second_message() {
         let mut tracker = MessageAssembler::default();
@@ -444,4 +542,49 @@ mod tests {
 
         assert_eq!(None, tracker.next());
     }
+
+    #[test]
+    fn body_index_out_of_range() {
+        let mut tracker = MessageAssembler::default();
+
+        tracker.body(0, MAX_BODIES_PER_MESSAGE as u16, vec![0, 1, 2, 3]);
+        tracker.body(0, u16::MAX, vec![0, 1, 2, 3]);
+
+        assert_eq!(None, tracker.next());
+    }
+
+    #[test]
+    fn progress_of_partial_and_delivered_messages() {
+        let mut tracker = MessageAssembler::default();
+        let bodies = [vec![0; MAX_PACKET_PAYLOAD_SIZE], vec![0, 1, 2, 3]];
+
+        tracker.body(0, 1, bodies[1].clone());
+        let progress = tracker.progress(0).unwrap();
+        assert!(!progress.head);
+        assert_eq!(progress.bodies, RangeInclusiveSet::from_iter([1..=1]));
+
+        tracker.head(0, NonZero::new(bodies.len() as u16).unwrap(), false);
+        tracker.body(0, 0, bodies[0].clone());
+        assert_eq!(Some((bodies.concat(), 0)), tracker.next());
+
+        // Gone from the assembler, but the sender still has to hear it all arrived
+        let progress = tracker.progress(0).unwrap();
+        assert!(progress.head);
+        assert!(progress.bodies.contains(&0) && progress.bodies.contains(&1));
+        assert!(tracker.is_delivered(0));
+
+        assert!(!tracker.is_delivered(1));
+        assert!(tracker
+            .progress(MARGIN_OF_OUT_OF_ORDER_ALLOWED as MessageId + 1)
+            .is_none());
+    }
+
+    #[test]
+    fn head_body_count_out_of_range() {
+        let mut tracker = MessageAssembler::default();
+
+        tracker.head(0, NonZero::new(u16::MAX).unwrap(), false);
+
+        assert_eq!(None, tracker.next());
+    }
 }
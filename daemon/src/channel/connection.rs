@@ -0,0 +1,366 @@
+use super::writer::RequestWriteMessage;
+use crate::{
+    error::RouteWeaverError, ipc::socket::NewConnection, proto::Message, state::ServerState,
+};
+use routeweaver_common::{ApplicationId, ConnectionId, PublicKey};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep, timeout, Instant},
+};
+
+/// How often we tell the remote a connection is still alive
+pub const CONNECTION_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long a connection can go without hearing anything from the remote before it's considered dead
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long we wait on a remote to answer a connection request, this may include setting up a channel
+pub const CONNECTION_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Picked by whoever asks for a connection, and sent back in the answer so it can be matched up
+pub type RequestId = u32;
+
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    /// When we last heard anything from the remote over each connection
+    last_seen: scc::HashMap<(PublicKey, ConnectionId), Instant>,
+    /// Connection requests we sent that are waiting on an answer
+    pending_requests: scc::HashMap<(PublicKey, RequestId), oneshot::Sender<Option<NewConnection>>>,
+}
+
+impl ConnectionTracker {
+    /// Marks that the remote has just shown signs of life, returning false if the connection doesn't exist
+    pub async fn seen(&self, node: PublicKey, connection_id: ConnectionId) -> bool {
+        self.last_seen
+            .update_async(&(node, connection_id), |_, last_seen| {
+                *last_seen = Instant::now()
+            })
+            .await
+            .is_some()
+    }
+}
+
+/// Creates the plumbing for a connection so data coming in over it ends up in the returned [NewConnection]
+///
+/// Returns [Option::None] if this connection id is already in use with the node
+async fn open_connection(
+    server_state: &ServerState,
+    node: PublicKey,
+    connection_id: ConnectionId,
+) -> Option<NewConnection> {
+    let (sender, receiver) = mpsc::channel(100);
+
+    server_state
+        .request_receive_connection_data
+        .insert_async((node, connection_id), sender)
+        .await
+        .ok()?;
+
+    server_state
+        .connection_tracker
+        .last_seen
+        .upsert_async((node, connection_id), Instant::now())
+        .await;
+
+    Some(NewConnection {
+        node,
+        id: connection_id,
+        receiver,
+    })
+}
+
+/// Tears down a connection locally, telling the remote about it if asked to
+pub async fn close_connection(
+    server_state: &ServerState,
+    node: PublicKey,
+    connection_id: ConnectionId,
+    notify_remote: bool,
+) -> Result<(), RouteWeaverError> {
+    server_state
+        .request_receive_connection_data
+        .remove_async(&(node, connection_id))
+        .await;
+    server_state
+        .connection_tracker
+        .last_seen
+        .remove_async(&(node, connection_id))
+        .await;
+
+    if notify_remote {
+        notify_connection_closed(server_state, node, connection_id).await?;
+    }
+
+    Ok(())
+}
+
+/// Tells the remote to forget about a connection
+pub async fn notify_connection_closed(
+    server_state: &ServerState,
+    node: PublicKey,
+    connection_id: ConnectionId,
+) -> Result<(), RouteWeaverError> {
+    server_state
+        .request_write_message
+        .send(RequestWriteMessage {
+            notify_sent: None,
+            destination: node,
+            message: Message::ConnectionClose { connection_id },
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Asks a remote node to open a connection with one of its applications
+pub async fn request_connection(
+    server_state: &ServerState,
+    node: PublicKey,
+    application: ApplicationId,
+) -> Result<NewConnection, RouteWeaverError> {
+    let (mut sender, receiver) = oneshot::channel();
+
+    // Pick an unused id, collisions are only with our own requests to the same node
+    let request_id = loop {
+        let request_id = rand::random();
+
+        match server_state
+            .connection_tracker
+            .pending_requests
+            .insert_async((node, request_id), sender)
+            .await
+        {
+            Ok(()) => break request_id,
+            Err((_, returned)) => sender = returned,
+        }
+    };
+
+    // Get the channel going early, the writer would do it anyway but this saves a round of waiting
+    if !server_state.transport_tracker.contains_async(&node).await {
+        server_state.request_initiate_channel.send(node).await?;
+    }
+
+    server_state
+        .request_write_message
+        .send(RequestWriteMessage {
+            notify_sent: None,
+            destination: node,
+            message: Message::RequestConnection {
+                request_id,
+                application,
+            },
+        })
+        .await?;
+
+    let response = timeout(CONNECTION_REQUEST_TIMEOUT, receiver).await;
+
+    // Already gone if it was answered, otherwise a late answer gets treated like one we never asked for
+    server_state
+        .connection_tracker
+        .pending_requests
+        .remove_async(&(node, request_id))
+        .await;
+
+    match response {
+        Ok(Ok(Some(connection))) => Ok(connection),
+        Ok(Ok(None)) => Err(RouteWeaverError::ConnectionDenied),
+        Ok(Err(_)) | Err(_) => Err(RouteWeaverError::ConnectionFailed),
+    }
+}
+
+/// Handles a remote node wanting to talk to one of our applications
+pub async fn handle_request_connection(
+    server_state: Arc<ServerState>,
+    node: PublicKey,
+    request_id: RequestId,
+    application: ApplicationId,
+) -> Result<(), RouteWeaverError> {
+    let response = match server_state.application_tracker.get(&application).await {
+        Some(listener) => {
+            // Pick an unused id, we are the ones handing it out so collisions are only with ourselves
+            let connection = loop {
+                if let Some(connection) = open_connection(&server_state, node, rand::random()).await
+                {
+                    break connection;
+                }
+            };
+            let connection_id = connection.id;
+
+            // Don't wait on a busy application, the remote can always ask again
+            if listener.try_send(connection).is_ok() {
+                tracing::debug!(
+                    "Node {} opened connection {} to application {}",
+                    node,
+                    connection_id,
+                    application
+                );
+
+                Message::ConnectionAccepted {
+                    request_id,
+                    application,
+                    connection_id,
+                }
+            } else {
+                tracing::debug!(
+                    "Node {} tried connecting to application {}, but it is not accepting connections",
+                    node,
+                    application
+                );
+
+                close_connection(&server_state, node, connection_id, false).await?;
+                Message::ConnectionDenied {
+                    request_id,
+                    application,
+                }
+            }
+        }
+        None => {
+            tracing::debug!(
+                "Node {} tried connecting to application {}, but nothing is listening",
+                node,
+                application
+            );
+
+            Message::ConnectionDenied {
+                request_id,
+                application,
+            }
+        }
+    };
+
+    server_state
+        .request_write_message
+        .send(RequestWriteMessage {
+            notify_sent: None,
+            destination: node,
+            message: response,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Handles a remote node answering one of our connection requests
+pub async fn handle_connection_response(
+    server_state: Arc<ServerState>,
+    node: PublicKey,
+    request_id: RequestId,
+    application: ApplicationId,
+    connection_id: Option<ConnectionId>,
+) -> Result<(), RouteWeaverError> {
+    let request = server_state
+        .connection_tracker
+        .pending_requests
+        .remove_async(&(node, request_id))
+        .await
+        .map(|(_, request)| request);
+
+    let Some(connection_id) = connection_id else {
+        tracing::debug!(
+            "Node {} denied our connection to application {}",
+            node,
+            application
+        );
+
+        if let Some(request) = request {
+            let _ = request.send(None);
+        }
+
+        return Ok(());
+    };
+
+    let Some(request) = request else {
+        tracing::warn!(
+            "Node {} accepted connection {} to application {}, but we never asked for it",
+            node,
+            connection_id,
+            application
+        );
+
+        return notify_connection_closed(&server_state, node, connection_id).await;
+    };
+
+    let Some(connection) = open_connection(&server_state, node, connection_id).await else {
+        tracing::warn!(
+            "Node {} accepted connection {} to application {}, but that connection id is already in use",
+            node,
+            connection_id,
+            application
+        );
+
+        // Leave our own connection with that id alone, the remote just has to drop the new one
+        let _ = request.send(None);
+        return notify_connection_closed(&server_state, node, connection_id).await;
+    };
+
+    if request.send(Some(connection)).is_err() {
+        // Whoever asked for it gave up waiting
+        close_connection(&server_state, node, connection_id, true).await?;
+    }
+
+    Ok(())
+}
+
+/// Sends heartbeats for every live connection and cleans up the ones that died
+pub async fn connection_keeper(server_state: Arc<ServerState>) {
+    loop {
+        sleep(CONNECTION_HEARTBEAT_INTERVAL).await;
+
+        if let Err(err) = keep_connections(&server_state).await {
+            tracing::error!("Connection keeper stopping: {}", err);
+            return;
+        }
+    }
+}
+
+async fn keep_connections(server_state: &ServerState) -> Result<(), RouteWeaverError> {
+    let mut timed_out = Vec::new();
+    let mut alive = Vec::new();
+
+    server_state
+        .connection_tracker
+        .last_seen
+        .scan_async(|connection, last_seen| {
+            if last_seen.elapsed() > CONNECTION_TIMEOUT {
+                timed_out.push(*connection);
+            } else {
+                alive.push(*connection);
+            }
+        })
+        .await;
+
+    for (node, connection_id) in timed_out {
+        tracing::debug!("Connection {} with node {} timed out", connection_id, node);
+
+        close_connection(server_state, node, connection_id, true).await?;
+    }
+
+    for (node, connection_id) in alive {
+        // Nobody locally cares about this connection anymore
+        let abandoned = server_state
+            .request_receive_connection_data
+            .read_async(&(node, connection_id), |_, sender| sender.is_closed())
+            .await
+            .unwrap_or(true);
+
+        if abandoned {
+            tracing::debug!(
+                "Connection {} with node {} was abandoned locally",
+                connection_id,
+                node
+            );
+
+            close_connection(server_state, node, connection_id, true).await?;
+            continue;
+        }
+
+        server_state
+            .request_write_message
+            .send(RequestWriteMessage {
+                notify_sent: None,
+                destination: node,
+                message: Message::ConnectionHeartbeat { connection_id },
+            })
+            .await?;
+    }
+
+    Ok(())
+}
@@ -41,6 +41,13 @@ struct PayloadTracker {
     bodies_confirmed: RangeInclusiveSet<u16>,
 }
 
+impl PayloadTracker {
+    fn is_confirmed(&self) -> bool {
+        self.head_confirmed
+            && (0..self.body_count.get()).all(|index| self.bodies_confirmed.contains(&index))
+    }
+}
+
 #[derive(Default)]
 pub struct MessageDisassembler {
     packets: HashMap<MessageId, PayloadTracker>,
@@ -49,18 +56,21 @@ pub struct MessageDisassembler {
 }
 
 impl MessageDisassembler {
-    pub fn message(&mut self, message: Message) -> MessageId {
+    /// Queues up a message, compressing it if it's worth it and the remote can take it
+    pub fn message(&mut self, message: Message, allow_compression: bool) -> MessageId {
         let encoded_payload =
             bincode::serde::encode_to_vec(&message, bincode::config::standard()).unwrap();
 
-        let (encoded_payload, compression) = if should_message_be_compressed(&encoded_payload) {
-            (lz4_flex::compress_prepend_size(&encoded_payload), true)
-        } else {
-            (encoded_payload, false)
-        };
+        let (encoded_payload, compression) =
+            if allow_compression && should_message_be_compressed(&encoded_payload) {
+                (lz4_flex::compress_prepend_size(&encoded_payload), true)
+            } else {
+                (encoded_payload, false)
+            };
 
         let body_count = encoded_payload.len().div_ceil(MAX_PACKET_PAYLOAD_SIZE);
-        let message_id = self.highest_message_id.wrapping_add(1);
+        let message_id = self.highest_message_id;
+        self.highest_message_id = self.highest_message_id.wrapping_add(1);
 
         self.packets.insert(
             message_id,
@@ -94,13 +104,38 @@ impl MessageDisassembler {
     }
 
     pub fn is_message_confirmed(&self, message_id: MessageId) -> bool {
-        if let Some(tracker) = self.packets.get(&message_id) {
-            return tracker.head_confirmed
-                && (0..tracker.body_count.get())
-                    .all(|index| tracker.bodies_confirmed.contains(&index));
+        self.packets
+            .get(&message_id)
+            .is_some_and(PayloadTracker::is_confirmed)
+    }
+
+    /// Starts over for a remote that lost track of everything, numbering whatever it has not confirmed from 0 again
+    ///
+    /// Returns the old id of every message still pending alongside its new one
+    pub fn restart(&mut self) -> Vec<(MessageId, MessageId)> {
+        let mut packets = std::mem::take(&mut self.packets);
+        let mut renumbered = Vec::new();
+        let mut message_id = self.next_message_id;
+
+        while message_id != self.highest_message_id {
+            if let Some(mut tracker) = packets.remove(&message_id) {
+                if !tracker.is_confirmed() {
+                    let new_message_id = renumbered.len() as MessageId;
+
+                    tracker.head_confirmed = false;
+                    tracker.bodies_confirmed.clear();
+                    self.packets.insert(new_message_id, tracker);
+                    renumbered.push((message_id, new_message_id));
+                }
+            }
+
+            message_id = message_id.wrapping_add(1);
         }
 
-        false
+        self.next_message_id = 0;
+        self.highest_message_id = renumbered.len() as MessageId;
+
+        renumbered
     }
 
     pub fn payloads(&mut self) -> Option<(MessageId, Vec<MessagePayload>)> {
@@ -164,7 +199,7 @@ mod tests {
     #[test]
     fn nonconfirmed_message() {
         let mut tracker = MessageDisassembler::default();
-        tracker.message(Message::RequestPeerSuggestion);
+        tracker.message(Message::RequestPeerSuggestion, true);
 
         assert_eq!(
             tracker.payloads(),
@@ -191,7 +226,7 @@ mod tests {
     #[test]
     fn confirmed_head() {
         let mut tracker = MessageDisassembler::default();
-        tracker.message(Message::RequestPeerSuggestion);
+        tracker.message(Message::RequestPeerSuggestion, true);
         tracker.head_status(0, true);
 
         assert_eq!(
@@ -213,7 +248,7 @@ mod tests {
     #[test]
     fn confirmed_body() {
         let mut tracker = MessageDisassembler::default();
-        tracker.message(Message::RequestPeerSuggestion);
+        tracker.message(Message::RequestPeerSuggestion, true);
         tracker.body_status(0, [0..=0]);
 
         assert_eq!(
@@ -231,7 +266,7 @@ mod tests {
     #[test]
     fn confirmed_head_and_body() {
         let mut tracker = MessageDisassembler::default();
-        tracker.message(Message::RequestPeerSuggestion);
+        tracker.message(Message::RequestPeerSuggestion, true);
         tracker.head_status(0, true);
         tracker.body_status(0, [0..=0]);
 
@@ -241,8 +276,8 @@ mod tests {
     #[test]
     fn multiple_nonconfirmed_messages() {
         let mut tracker = MessageDisassembler::default();
-        tracker.message(Message::RequestPeerSuggestion);
-        tracker.message(Message::RequestPeerSuggestion);
+        tracker.message(Message::RequestPeerSuggestion, true);
+        tracker.message(Message::RequestPeerSuggestion, true);
 
         assert_eq!(
             tracker.payloads(),
@@ -290,4 +325,31 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn restart_resends_unconfirmed() {
+        let mut tracker = MessageDisassembler::default();
+        tracker.message(Message::RequestPeerSuggestion, true);
+        tracker.message(Message::RequestPeerSuggestion, true);
+        tracker.message(Message::RequestPeerSuggestion, true);
+
+        tracker.head_status(0, true);
+        tracker.body_status(0, [0..=0]);
+        tracker.payloads();
+        tracker.head_status(1, true);
+        tracker.head_status(2, true);
+        tracker.body_status(2, [0..=0]);
+
+        assert_eq!(tracker.restart(), vec![(1, 0)]);
+        assert!(matches!(
+            tracker.payloads(),
+            Some((0, payloads)) if payloads.len() == 2
+        ));
+
+        tracker.head_status(0, true);
+        tracker.body_status(0, [0..=0]);
+        tracker.payloads();
+        assert_eq!(tracker.payloads(), None);
+        assert_eq!(tracker.message(Message::RequestPeerSuggestion, true), 1);
+    }
 }
@@ -1,11 +1,21 @@
-use crate::{proto::Message, state::ServerState};
+use crate::{error::RouteWeaverError, proto::Message, state::ServerState};
 use routeweaver_common::PublicKey;
 use std::{sync::Arc, time::Duration};
 use tokio::time::sleep;
 
-use super::writer::RequestWriteMessage;
+use super::{
+    connection::{
+        close_connection, handle_connection_response, handle_request_connection,
+        notify_connection_closed,
+    },
+    writer::RequestWriteMessage,
+};
 
-pub async fn handle_message(server_state: Arc<ServerState>, node: PublicKey, message: Message) {
+pub async fn handle_message(
+    server_state: Arc<ServerState>,
+    node: PublicKey,
+    message: Message,
+) -> Result<(), RouteWeaverError> {
     match message {
         Message::RequestPeerSuggestion => {
             // For now "suggest" our local addresses. Very dumb but anything better isn't possible
@@ -22,14 +32,18 @@ pub async fn handle_message(server_state: Arc<ServerState>, node: PublicKey, mes
                     destination: node,
                     message: response,
                 })
-                .await
-                .unwrap();
+                .await?;
         }
         Message::PeerSuggestion { peers } => {
             // Spawn off a task that SLOWLY feeds these peers into the system, giving grace time so
             // a malicious node can't dos a set of nodes using us
             tokio::spawn(async move {
                 for peer in peers {
+                    if !server_state.access_control.is_peer_allowed(&peer).await {
+                        tracing::debug!("Node {} suggested denied peer {}", node, peer);
+                        continue;
+                    }
+
                     if let Some(initator) = server_state
                         .request_initiate_connection
                         .get_async(&peer.protocol)
@@ -37,7 +51,9 @@ pub async fn handle_message(server_state: Arc<ServerState>, node: PublicKey, mes
                     {
                         tracing::debug!("Attempting to connect to suggested peer {}", peer);
 
-                        initator.send(peer.address).await.unwrap();
+                        if initator.send(peer.address).await.is_err() {
+                            return;
+                        }
                     } else {
                         tracing::debug!(
                             "Got peer using protocol {} from node {}, but this protocol is not active",
@@ -50,20 +66,56 @@ pub async fn handle_message(server_state: Arc<ServerState>, node: PublicKey, mes
                 }
             });
         }
-        Message::RequestConnection { application } => todo!(),
+        Message::RequestConnection {
+            request_id,
+            application,
+        } => {
+            handle_request_connection(server_state, node, request_id, application).await?;
+        }
         Message::ConnectionAccepted {
+            request_id,
             application,
             connection_id,
-        } => todo!(),
-        Message::ConnectionDenied { application } => todo!(),
-        Message::ConnectionHeartbeat { connection_id } => todo!(),
+        } => {
+            handle_connection_response(
+                server_state,
+                node,
+                request_id,
+                application,
+                Some(connection_id),
+            )
+            .await?;
+        }
+        Message::ConnectionDenied {
+            request_id,
+            application,
+        } => {
+            handle_connection_response(server_state, node, request_id, application, None).await?;
+        }
+        Message::ConnectionHeartbeat { connection_id } => {
+            if !server_state
+                .connection_tracker
+                .seen(node, connection_id)
+                .await
+            {
+                tracing::debug!(
+                    "Node {} sent heartbeat for connection {}, but this connection does not exist",
+                    node,
+                    connection_id
+                );
+
+                // Let the remote know so it can stop holding onto it
+                notify_connection_closed(&server_state, node, connection_id).await?;
+            }
+        }
         Message::ConnectionClose { connection_id } => {
             if server_state
                 .request_receive_connection_data
-                .remove_async(&connection_id)
+                .contains_async(&(node, connection_id))
                 .await
-                .is_none()
             {
+                close_connection(&server_state, node, connection_id, false).await?;
+            } else {
                 tracing::warn!(
                     "Node {} tried closing connection {}, but this connection did not exist",
                     node,
@@ -77,19 +129,42 @@ pub async fn handle_message(server_state: Arc<ServerState>, node: PublicKey, mes
         } => {
             if let Some(entry) = server_state
                 .request_receive_connection_data
-                .get_async(&connection_id)
+                .get_async(&(node, connection_id))
                 .await
             {
+                server_state
+                    .connection_tracker
+                    .seen(node, connection_id)
+                    .await;
+
                 match entry.send(data).await {
                     Ok(_) => {
                         tracing::info!("Node {} sent data over connection {}", node, connection_id);
                     }
                     Err(_) => {
                         tracing::warn!("Node {} sent data over connection {}, but this connection does not exist", node, connection_id);
-                        let _ = entry.remove_entry();
+                        drop(entry);
+                        close_connection(&server_state, node, connection_id, true).await?;
                     }
                 }
             }
         }
+        Message::RouteAdvertisement { routes } => {
+            // Routes only mean anything coming from someone we can hand packets to directly
+            if !server_state.routing_table.is_neighbor(&node).await {
+                tracing::debug!(
+                    "Node {} advertised routes, but it is not our neighbor",
+                    node
+                );
+                return Ok(());
+            }
+
+            server_state
+                .routing_table
+                .update(&server_state.keys.public, node, routes)
+                .await;
+        }
     }
+
+    Ok(())
 }
@@ -1,10 +1,8 @@
 use crate::{
+    error::RouteWeaverError,
     noise::create_handshake_initiator,
     state::ServerState,
-    transport::{
-        packet::{Packet, PacketData},
-        router::RequestRoutePacket,
-    },
+    transport::handshake::{start_handshake, Handshake},
 };
 use routeweaver_common::PublicKey;
 use std::sync::Arc;
@@ -14,36 +12,28 @@ pub async fn channel_initiator(
     server_state: Arc<ServerState>,
     mut request_initiate_channel: mpsc::Receiver<PublicKey>,
 ) {
-    let mut buffer = vec![0; 128];
-
     while let Some(node) = request_initiate_channel.recv().await {
-        if server_state.handshake_tracker.contains_async(&node).await {
+        if !server_state.access_control.is_node_allowed(&node).await {
+            tracing::warn!("Not making a channel with denied node {}", node);
             continue;
         }
 
-        let mut handshake_state = create_handshake_initiator(&server_state.keys.private);
+        if !server_state.mesh_trust.is_node_trusted(&node) {
+            tracing::warn!("Not making a channel with untrusted node {}", node);
+            continue;
+        }
 
-        if let Err(err) = handshake_state.write_message(&[], &mut buffer) {
-            tracing::info!(
-                "Failed to write initial handshake message to {} due to {}",
-                node,
-                err
-            );
-        } else {
-            let packet = Packet {
-                source: server_state.keys.public,
-                destination: Some(node),
-                data: PacketData::Handshake(buffer.as_slice().try_into().unwrap()),
-            };
+        let handshake_state = create_handshake_initiator(
+            &server_state.keys.private,
+            server_state.mesh_trust.psk.as_ref(),
+        );
 
-            server_state
-                .request_route_packet
-                .send(RequestRoutePacket {
-                    origin: None,
-                    packet,
-                })
-                .await
-                .unwrap();
+        match start_handshake(&server_state, node, Handshake::new(handshake_state)).await {
+            Ok(()) => {}
+            Err(RouteWeaverError::ChannelClosed) => break,
+            Err(err) => {
+                tracing::info!("Failed to start handshake with {} due to {}", node, err);
+            }
         }
     }
 }
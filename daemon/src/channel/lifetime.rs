@@ -0,0 +1,385 @@
+use crate::{error::RouteWeaverError, noise::EpochKey, proto::Capabilities, state::ServerState};
+use routeweaver_common::PublicKey;
+use std::{mem::replace, sync::Arc, time::Duration};
+use tokio::time::{sleep, Instant};
+
+/// Which key a segment was encrypted with, counting up from the handshake with every rekey
+pub type Epoch = u32;
+
+/// How often channels are checked for having gone idle
+const CHANNEL_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Messages sent under one key before switching to a new one, regardless of time
+const REKEY_AFTER_MESSAGES: u64 = 1 << 20;
+/// How far ahead of ours the remote's epoch may be, in case every segment of the ones in between went missing
+const MAX_EPOCHS_AHEAD: Epoch = 4;
+/// Segments in a row that fail to decrypt before the channel is assumed to be from before the remote restarted
+const STALE_CHANNEL_FAILURES: u8 = 8;
+
+/// Which recent nonces of an epoch have been read, so copies of a segment get turned away
+#[derive(Default)]
+struct ReplayWindow {
+    /// One past the highest nonce read
+    next: u64,
+    /// Bit n is set if the nonce n before the highest was read
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn is_fresh(&self, nonce: u64) -> bool {
+        if nonce >= self.next {
+            return true;
+        }
+
+        // Anything from before the window can't be told apart from a copy
+        let age = self.next - 1 - nonce;
+        age < u64::BITS as u64 && self.seen & (1 << age) == 0
+    }
+
+    fn mark(&mut self, nonce: u64) {
+        if nonce >= self.next {
+            let shift = nonce - self.next + 1;
+            self.seen = if shift < u64::BITS as u64 {
+                self.seen << shift | 1
+            } else {
+                1
+            };
+            self.next = nonce + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - nonce);
+        }
+    }
+}
+
+/// Established channel with a node
+///
+/// Every segment says which epoch and nonce it was encrypted under, so they can be read no matter what order they
+/// show up in or whether the ones before made it
+pub struct Channel {
+    sending_key: EpochKey,
+    sending_epoch: Epoch,
+    sending_nonce: u64,
+    receiving_key: EpochKey,
+    receiving_epoch: Epoch,
+    receiving_window: ReplayWindow,
+    /// Key of the epoch before, for segments that were already on their way when the remote switched
+    previous_receiving: Option<(EpochKey, ReplayWindow)>,
+    /// What both ends agreed on when setting the channel up
+    pub capabilities: Capabilities,
+    /// Last time anything was sent or received over the channel
+    last_active: Instant,
+    last_rekey: Instant,
+    /// Segments in a row that could not be decrypted
+    decrypt_failures: u8,
+}
+
+impl Channel {
+    pub fn new(sending_key: EpochKey, receiving_key: EpochKey, capabilities: Capabilities) -> Self {
+        Self {
+            sending_key,
+            sending_epoch: 0,
+            sending_nonce: 0,
+            receiving_key,
+            receiving_epoch: 0,
+            receiving_window: ReplayWindow::default(),
+            previous_receiving: None,
+            capabilities,
+            last_active: Instant::now(),
+            last_rekey: Instant::now(),
+            decrypt_failures: 0,
+        }
+    }
+
+    /// Encrypts under the current epoch, returning the epoch and nonce the remote needs to read it
+    pub fn seal(
+        &mut self,
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<(Epoch, u64, usize), RouteWeaverError> {
+        let nonce = self.sending_nonce;
+        let amount = self.sending_key.encrypt(nonce, plaintext, out)?;
+        self.sending_nonce += 1;
+
+        Ok((self.sending_epoch, nonce, amount))
+    }
+
+    /// Decrypts a segment, following the remote onto a newer epoch once one of its segments proves it switched
+    pub fn open(
+        &mut self,
+        epoch: Epoch,
+        nonce: u64,
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, RouteWeaverError> {
+        if epoch == self.receiving_epoch {
+            return Self::open_with(
+                &self.receiving_key,
+                &mut self.receiving_window,
+                nonce,
+                ciphertext,
+                out,
+            );
+        }
+
+        if Some(epoch) == self.receiving_epoch.checked_sub(1) {
+            let Some((key, window)) = self.previous_receiving.as_mut() else {
+                return Err(snow::Error::Decrypt.into());
+            };
+
+            return Self::open_with(key, window, nonce, ciphertext, out);
+        }
+
+        if epoch <= self.receiving_epoch || epoch - self.receiving_epoch > MAX_EPOCHS_AHEAD {
+            return Err(snow::Error::Decrypt.into());
+        }
+
+        let mut previous_key = None;
+        let mut key = self.receiving_key.next();
+        for _ in 1..epoch - self.receiving_epoch {
+            let next = key.next();
+            previous_key = Some(replace(&mut key, next));
+        }
+
+        // Anyone can claim a newer epoch, so nothing changes until the segment actually decrypts
+        let mut window = ReplayWindow::default();
+        let amount = Self::open_with(&key, &mut window, nonce, ciphertext, out)?;
+
+        let current = (
+            replace(&mut self.receiving_key, key),
+            replace(&mut self.receiving_window, window),
+        );
+        self.previous_receiving = Some(match previous_key {
+            Some(previous_key) => (previous_key, ReplayWindow::default()),
+            None => current,
+        });
+        self.receiving_epoch = epoch;
+
+        Ok(amount)
+    }
+
+    fn open_with(
+        key: &EpochKey,
+        window: &mut ReplayWindow,
+        nonce: u64,
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, RouteWeaverError> {
+        if !window.is_fresh(nonce) {
+            return Err(RouteWeaverError::ReplayedSegment);
+        }
+
+        let amount = key.decrypt(nonce, ciphertext, out)?;
+        window.mark(nonce);
+
+        Ok(amount)
+    }
+
+    pub fn touch(&mut self) {
+        self.last_active = Instant::now();
+    }
+
+    pub fn decrypt_succeeded(&mut self) {
+        self.decrypt_failures = 0;
+        self.touch();
+    }
+
+    /// Returns true once enough segments in a row failed that the channel looks stale, and again for every as many
+    /// after that
+    pub fn decrypt_failed(&mut self) -> bool {
+        self.decrypt_failures += 1;
+
+        if self.decrypt_failures < STALE_CHANNEL_FAILURES {
+            return false;
+        }
+
+        self.decrypt_failures = 0;
+        true
+    }
+
+    pub fn is_idle(&self, idle_timeout: Duration) -> bool {
+        self.last_active.elapsed() >= idle_timeout
+    }
+
+    pub fn needs_rekey(&self, rekey_interval: Duration) -> bool {
+        self.last_rekey.elapsed() >= rekey_interval || self.sending_nonce >= REKEY_AFTER_MESSAGES
+    }
+
+    /// Out of epochs, so a new handshake is needed instead of a rekey
+    pub fn is_exhausted(&self) -> bool {
+        self.sending_epoch == Epoch::MAX
+    }
+
+    /// Switches to a new sending key, the remote follows as soon as it reads something sent under it
+    pub fn rekey_outgoing(&mut self) {
+        self.sending_key = self.sending_key.next();
+        self.sending_epoch += 1;
+        self.sending_nonce = 0;
+        self.last_rekey = Instant::now();
+    }
+}
+
+/// Tears down the channel with a node, along with everything still queued for it
+pub async fn close_channel(server_state: &ServerState, node: PublicKey) {
+    if server_state
+        .transport_tracker
+        .remove_async(&node)
+        .await
+        .is_some()
+    {
+        let _ = server_state.notification_channel_closed.send(node);
+    }
+}
+
+/// Closes channels nothing has gone over for a while
+pub async fn channel_expirer(server_state: Arc<ServerState>, idle_timeout: Duration) {
+    loop {
+        sleep(CHANNEL_EXPIRY_INTERVAL).await;
+
+        let mut expired = Vec::new();
+        server_state
+            .transport_tracker
+            .retain_async(|node, channel| {
+                if channel.is_idle(idle_timeout) {
+                    expired.push(*node);
+                    return false;
+                }
+
+                true
+            })
+            .await;
+
+        for node in expired {
+            tracing::debug!("Channel with node {} went idle, closing", node);
+            let _ = server_state.notification_channel_closed.send(node);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Epoch};
+    use crate::{
+        error::RouteWeaverError,
+        noise::{
+            create_handshake_initiator, create_handshake_responder, generate_keys, split_handshake,
+        },
+        proto::Capabilities,
+    };
+    use std::time::Duration;
+
+    fn channel_pair() -> (Channel, Channel) {
+        let mut initiator = create_handshake_initiator(&generate_keys().private, None);
+        let mut responder = create_handshake_responder(&generate_keys().private, None);
+        let mut message = [0; 128];
+        let mut payload = [0; 128];
+
+        for initiator_turn in [true, false, true] {
+            let (sender, receiver) = if initiator_turn {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+
+            let amount = sender.write_message(&[], &mut message).unwrap();
+            receiver
+                .read_message(&message[..amount], &mut payload)
+                .unwrap();
+        }
+
+        let (initiator_sending, initiator_receiving) = split_handshake(initiator).unwrap();
+        let (responder_sending, responder_receiving) = split_handshake(responder).unwrap();
+
+        (
+            Channel::new(initiator_sending, initiator_receiving, Capabilities::ours()),
+            Channel::new(responder_sending, responder_receiving, Capabilities::ours()),
+        )
+    }
+
+    /// Encrypted segment along with what it was tagged with
+    fn seal(channel: &mut Channel, plaintext: &[u8]) -> (Epoch, u64, Vec<u8>) {
+        let mut out = [0; 128];
+        let (epoch, nonce, amount) = channel.seal(plaintext, &mut out).unwrap();
+
+        (epoch, nonce, out[..amount].to_vec())
+    }
+
+    fn open(
+        channel: &mut Channel,
+        (epoch, nonce, data): &(Epoch, u64, Vec<u8>),
+    ) -> Option<Vec<u8>> {
+        let mut out = [0; 128];
+        let amount = channel.open(*epoch, *nonce, data, &mut out).ok()?;
+
+        Some(out[..amount].to_vec())
+    }
+
+    #[test]
+    fn rekey_keeps_channel_readable() {
+        let (mut sender, mut receiver) = channel_pair();
+
+        assert!(!sender.needs_rekey(Duration::from_secs(60)));
+        assert!(sender.needs_rekey(Duration::ZERO));
+
+        let before = seal(&mut sender, b"before");
+        sender.rekey_outgoing();
+        let lost = seal(&mut sender, b"lost");
+        let after = seal(&mut sender, b"after");
+        sender.rekey_outgoing();
+        let later = seal(&mut sender, b"later");
+
+        // The first segments of the new epochs never making it, or coming in late, doesn't matter
+        assert_eq!(open(&mut receiver, &after).unwrap(), b"after");
+        assert_eq!(open(&mut receiver, &later).unwrap(), b"later");
+        assert_eq!(open(&mut receiver, &lost).unwrap(), b"lost");
+        // Too far back to still have the key for
+        assert!(open(&mut receiver, &before).is_none());
+        assert!(!sender.is_exhausted());
+    }
+
+    #[test]
+    fn reads_out_of_order_but_only_once() {
+        let (mut sender, mut receiver) = channel_pair();
+        let segments: Vec<_> = (0..5u8).map(|index| seal(&mut sender, &[index])).collect();
+
+        for index in [3, 0, 4, 1, 2] {
+            assert_eq!(
+                open(&mut receiver, &segments[index]).unwrap(),
+                [index as u8]
+            );
+        }
+
+        let (epoch, nonce, data) = &segments[2];
+        let mut out = [0; 128];
+        assert!(matches!(
+            receiver.open(*epoch, *nonce, data, &mut out),
+            Err(RouteWeaverError::ReplayedSegment)
+        ));
+    }
+
+    #[test]
+    fn forged_epoch_changes_nothing() {
+        let (mut sender, mut receiver) = channel_pair();
+
+        for epoch in [1, 3, Epoch::MAX] {
+            assert!(open(&mut receiver, &(epoch, 0, vec![0; 32])).is_none());
+        }
+        assert!(open(&mut receiver, &(0, 0, vec![0; 3])).is_none());
+
+        let segment = seal(&mut sender, b"still fine");
+        assert_eq!(open(&mut receiver, &segment).unwrap(), b"still fine");
+    }
+
+    #[test]
+    fn stale_after_repeated_failures() {
+        let (mut channel, _) = channel_pair();
+
+        for _ in 1..super::STALE_CHANNEL_FAILURES {
+            assert!(!channel.decrypt_failed());
+        }
+        channel.decrypt_succeeded();
+
+        for _ in 1..super::STALE_CHANNEL_FAILURES {
+            assert!(!channel.decrypt_failed());
+        }
+        assert!(channel.decrypt_failed());
+    }
+}
@@ -1,6 +1,8 @@
 pub mod assembler;
+pub mod connection;
 pub mod disassembler;
 mod handle_message;
 pub mod initiate;
+pub mod lifetime;
 pub mod reader;
 pub mod writer;
@@ -1,14 +1,20 @@
 use crate::{
+    error::RouteWeaverError,
     state::ServerState,
     transport::packet::{MessagePayload, MessageSegment},
 };
 use routeweaver_common::PublicKey;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::mpsc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::sync::{broadcast::error::RecvError, mpsc};
 use zeroize::Zeroizing;
 
 use super::{
-    assembler::MessageAssembler, handle_message::handle_message, writer::RequestUpdateMessageStatus,
+    assembler::MessageAssembler,
+    handle_message::handle_message,
+    writer::{write_message_progress, RequestUpdateMessageStatus},
 };
 
 pub struct RequestDecodeMessageSegment {
@@ -21,52 +27,131 @@ pub async fn channel_read_message(
     mut request_decode_message_segment: mpsc::Receiver<RequestDecodeMessageSegment>,
 ) {
     let mut message_assemblers = HashMap::new();
-    let mut buffer = vec![0; u16::MAX as usize];
-
-    while let Some(RequestDecodeMessageSegment { source, segment }) =
-        request_decode_message_segment.recv().await
-    {
-        let Some(mut transport_state) = server_state.transport_tracker.get_async(&source).await
-        else {
-            server_state.request_initiate_channel.send(source).await;
+    let mut channel_closed = server_state.notification_channel_closed.subscribe();
+    let mut channel_reset = server_state.notification_channel_reset.subscribe();
+    let mut encryption_buffer = vec![0; u16::MAX as usize];
 
-            continue;
-        };
-        let message_assembler: &mut MessageAssembler =
-            message_assemblers.entry(source).or_default();
+    loop {
+        tokio::select! {
+            request = request_decode_message_segment.recv() => {
+                let Some(request) = request else {
+                    break;
+                };
 
-        match segment.payload {
-            // Head segment containing concrete information on the message
-            MessagePayload::Head {
-                body_count,
-                compression,
-            } => {
-                message_assembler.head(segment.id, body_count, compression);
+                if let Err(err) =
+                    decode_message_segment(&server_state, &mut message_assemblers, request, &mut encryption_buffer).await
+                {
+                    tracing::error!("Channel reader stopping: {}", err);
+                    break;
+                }
             }
-            // Body segment containing data
-            MessagePayload::Body { index, data } => {
-                message_assembler.body(segment.id, index, data);
+            node = channel_closed.recv() => match node {
+                Ok(node) => {
+                    message_assemblers.remove(&node);
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // Missed some, so only keep what still has a channel
+                    let mut nodes = HashSet::new();
+                    server_state.transport_tracker.scan_async(|node, _| {
+                        nodes.insert(*node);
+                    }).await;
+                    message_assemblers.retain(|node, _| nodes.contains(node));
+                }
+                Err(RecvError::Closed) => break,
+            },
+            // The remote starts its message ids over with the new channel
+            node = channel_reset.recv() => match node {
+                Ok(node) => {
+                    message_assemblers.remove(&node);
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
             }
-            // Confirms what actually made it so far
-            MessagePayload::MessageProgress {
-                confirmed_head,
-                confirmed_bodies,
-            } => {
-                server_state
-                    .request_update_message_status
-                    .send(RequestUpdateMessageStatus {
-                        message_id: segment.id,
-                        destination: source,
-                        head_status: confirmed_head,
-                        body_status: confirmed_bodies,
-                    })
-                    .await
-                    .unwrap();
+        }
+    }
+}
+
+async fn decode_message_segment(
+    server_state: &Arc<ServerState>,
+    message_assemblers: &mut HashMap<PublicKey, MessageAssembler>,
+    RequestDecodeMessageSegment { source, segment }: RequestDecodeMessageSegment,
+    encryption_buffer: &mut [u8],
+) -> Result<(), RouteWeaverError> {
+    if !server_state.transport_tracker.contains_async(&source).await {
+        server_state.request_initiate_channel.send(source).await?;
+
+        return Ok(());
+    }
+
+    let message_assembler = message_assemblers.entry(source).or_default();
+    let progress_before = message_assembler.progress(segment.id);
+    let is_message_part = matches!(
+        segment.payload,
+        MessagePayload::Head { .. } | MessagePayload::Body { .. }
+    );
+
+    match segment.payload {
+        // Head segment containing concrete information on the message
+        MessagePayload::Head {
+            body_count,
+            compression,
+        } => {
+            message_assembler.head(segment.id, body_count, compression);
+        }
+        // Body segment containing data
+        MessagePayload::Body { index, data } => {
+            message_assembler.body(segment.id, index, data);
+        }
+        // Confirms what actually made it so far
+        MessagePayload::MessageProgress {
+            confirmed_head,
+            confirmed_bodies,
+        } => {
+            server_state
+                .request_update_message_status
+                .send(RequestUpdateMessageStatus {
+                    message_id: segment.id,
+                    destination: source,
+                    head_status: confirmed_head,
+                    body_status: confirmed_bodies,
+                })
+                .await?;
+        }
+    }
+
+    while let Some((message_id, message)) = message_assembler.next_message() {
+        match message {
+            Ok(message) => handle_message(server_state.clone(), source, message).await?,
+            // Skipped rather than holding up everything after it
+            Err(err) => {
+                tracing::warn!(
+                    "Node {} sent message {} that could not be decoded, skipping: {}",
+                    source,
+                    message_id,
+                    err
+                );
             }
         }
+    }
 
-        while let Some((_, message)) = message_assembler.next_message() {
-            handle_message(server_state.clone(), source, message).await;
+    // Answering every segment would have the sender resend whatever is still missing each time, so only speak up
+    // once the message is done or when the sender is repeating itself
+    if is_message_part {
+        if let Some(progress) = message_assembler.progress(segment.id) {
+            if message_assembler.is_delivered(segment.id)
+                || Some(&progress) == progress_before.as_ref()
+            {
+                write_message_progress(
+                    server_state,
+                    source,
+                    segment.id,
+                    progress,
+                    encryption_buffer,
+                )
+                .await?;
+            }
         }
     }
+
+    Ok(())
 }
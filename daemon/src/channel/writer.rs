@@ -1,8 +1,9 @@
 use crate::{
+    error::RouteWeaverError,
     proto::Message,
     state::ServerState,
     transport::{
-        packet::{MessageId, MessageSegment, Packet, PacketData},
+        packet::{MessageId, MessagePayload, MessageSegment, Packet, PacketData},
         router::RequestRoutePacket,
     },
 };
@@ -10,13 +11,18 @@ use rangemap::{RangeInclusiveSet, RangeSet};
 use routeweaver_common::PublicKey;
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use tokio::{
-    sync::{mpsc, oneshot, Notify},
-    time::sleep,
+    sync::{broadcast::error::RecvError, mpsc, oneshot, Notify},
+    time::{sleep, Instant},
 };
 
-use super::disassembler::MessageDisassembler;
+use super::{
+    assembler::MessageProgress,
+    disassembler::MessageDisassembler,
+    lifetime::{close_channel, Channel},
+};
 
 pub struct RequestWriteMessageResponse {
+    /// Time from the message first going out to the remote confirming all of it
     pub time_taken: Duration,
 }
 
@@ -37,9 +43,14 @@ pub async fn channel_write_message(
     server_state: Arc<ServerState>,
     mut request_write_message: mpsc::Receiver<RequestWriteMessage>,
     mut request_update_message_status: mpsc::Receiver<RequestUpdateMessageStatus>,
+    rekey_interval: Duration,
 ) {
     let mut message_disassemblers = HashMap::new();
+    let mut channel_closed = server_state.notification_channel_closed.subscribe();
+    let mut channel_reset = server_state.notification_channel_reset.subscribe();
     let mut notify_callbacks = HashMap::default();
+    // When each message first went out, for measuring how long confirmation takes
+    let mut first_sent = HashMap::new();
     let mut encryption_buffer = vec![0; u16::MAX as usize];
 
     loop {
@@ -48,8 +59,15 @@ pub async fn channel_write_message(
             // Queue up message
             v = request_write_message.recv() => {
                 if let Some(RequestWriteMessage { notify_sent, destination, message }) = v {
+                    // Until there's a channel it isn't known what the remote can decompress
+                    let allow_compression = server_state
+                        .transport_tracker
+                        .read_async(&destination, |_, channel| channel.capabilities.supports_lz4())
+                        .await
+                        .unwrap_or(false);
+
                     handle_write_message(&mut message_disassemblers, &mut notify_callbacks,
-                        notify_sent, destination, message);
+                        notify_sent, destination, message, allow_compression);
                 } else {
                     break;
                 }
@@ -57,104 +75,302 @@ pub async fn channel_write_message(
             // Update the message status
             v = request_update_message_status.recv() => {
                 if let Some(RequestUpdateMessageStatus { message_id, destination, head_status, body_status }) = v {
-                    handle_update_message_status(&mut message_disassemblers, &mut notify_callbacks,
-                        message_id, destination, head_status, body_status);
+                    if let Some(time_taken) = handle_update_message_status(&mut message_disassemblers,
+                        &mut notify_callbacks, &mut first_sent, message_id, destination, head_status, body_status)
+                    {
+                        server_state.score_keeper.message_confirmed(destination, time_taken).await;
+                    }
                 } else {
                     break;
                 }
             }
+            // Forget whatever was queued for channels that are gone
+            v = channel_closed.recv() => match v {
+                Ok(node) => {
+                    message_disassemblers.remove(&node);
+                    notify_callbacks.retain(|(destination, _), _| *destination != node);
+                    first_sent.retain(|(destination, _), _| *destination != node);
+                }
+                // Anything missed here is still sent again once a new channel is up
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            // Send again whatever the remote had not confirmed before it lost its end
+            v = channel_reset.recv() => match v {
+                Ok(node) => handle_channel_reset(&mut message_disassemblers, &mut notify_callbacks,
+                    &mut first_sent, node),
+                // Nothing to go on to renumber with here, so those messages are stuck until the next reset
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
             // Occasional wakeup for writing
             _ = sleep(Duration::from_secs(1)) => {}
         }
 
-        // Go through and send again all queued messages
-        for (node, message_disassemblers) in message_disassemblers.iter_mut() {
-            if let Some((message_id, payloads)) = message_disassemblers.payloads() {
-                if let Some(mut transport_state) =
-                    server_state.transport_tracker.get_async(node).await
-                {
-                    for payload in payloads {
-                        let segment = MessageSegment {
-                            id: message_id,
-                            payload,
-                        };
-
-                        let amount = transport_state
-                            .write_message(
-                                &bincode::serde::encode_to_vec(
-                                    segment,
-                                    bincode::config::standard(),
-                                )
-                                .unwrap(),
-                                &mut encryption_buffer,
-                            )
-                            .unwrap();
-
-                        let packet = Packet {
-                            source: server_state.keys.public,
-                            destination: Some(*node),
-                            data: PacketData::MessageSegment(encryption_buffer[..amount].to_vec()),
-                        };
-
-                        server_state
-                            .request_route_packet
-                            .send(RequestRoutePacket {
-                                origin: None,
-                                packet,
-                            })
-                            .await
-                            .unwrap();
-                    }
-                } else {
-                    tracing::debug!("Tried sending message segments to node {}, but a channel for them doesn't exist. Requesting creation", node);
+        if let Err(err) = write_segments(
+            &server_state,
+            &mut message_disassemblers,
+            &mut first_sent,
+            &mut encryption_buffer,
+            rekey_interval,
+        )
+        .await
+        {
+            tracing::error!("Channel writer stopping: {}", err);
+            break;
+        }
+    }
+}
 
-                    server_state
-                        .request_initiate_channel
-                        .send(*node)
-                        .await
-                        .unwrap();
-                }
+/// Go through and send again all queued messages
+async fn write_segments(
+    server_state: &ServerState,
+    message_disassemblers: &mut HashMap<PublicKey, MessageDisassembler>,
+    first_sent: &mut HashMap<(PublicKey, MessageId), Instant>,
+    encryption_buffer: &mut [u8],
+    rekey_interval: Duration,
+) -> Result<(), RouteWeaverError> {
+    for (node, message_disassembler) in message_disassemblers.iter_mut() {
+        let Some((message_id, payloads)) = message_disassembler.payloads() else {
+            continue;
+        };
+
+        let Some(mut channel) = server_state.transport_tracker.get_async(node).await else {
+            tracing::debug!("Tried sending message segments to node {}, but a channel for them doesn't exist. Requesting creation", node);
+
+            server_state.request_initiate_channel.send(*node).await?;
+            continue;
+        };
+
+        if channel.is_exhausted() {
+            tracing::debug!("Channel with node {} ran out of keys, starting over", node);
+
+            drop(channel);
+            close_channel(server_state, *node).await;
+            server_state.request_initiate_channel.send(*node).await?;
+            continue;
+        }
+
+        first_sent
+            .entry((*node, message_id))
+            .or_insert_with(Instant::now);
+
+        let packets = payloads
+            .into_iter()
+            .map(|payload| {
+                seal_segment(
+                    server_state,
+                    &mut channel,
+                    *node,
+                    MessageSegment {
+                        id: message_id,
+                        payload,
+                    },
+                    encryption_buffer,
+                )
+            })
+            .collect::<Result<Vec<_>, RouteWeaverError>>();
+
+        let packets = match packets {
+            Ok(packets) => packets,
+            Err(err) => {
+                // The channel is in no state to be used anymore, the next round will set up a new one
+                tracing::error!(
+                    "Failed encrypting message segments for node {}: {}",
+                    node,
+                    err
+                );
+                let _ = channel.remove();
+                continue;
             }
+        };
+
+        if channel.needs_rekey(rekey_interval) {
+            tracing::debug!("Switching to a new key for node {}", node);
+            channel.rekey_outgoing();
+        }
+        channel.touch();
+
+        // Don't hold onto the channel while waiting on the router
+        drop(channel);
+
+        for packet in packets {
+            server_state
+                .request_route_packet
+                .send(RequestRoutePacket {
+                    origin: None,
+                    packet,
+                })
+                .await?;
         }
     }
+
+    Ok(())
+}
+
+/// Tells a node how much of one of its messages has made it, so it can stop sending that again
+pub async fn write_message_progress(
+    server_state: &ServerState,
+    node: PublicKey,
+    message_id: MessageId,
+    progress: MessageProgress,
+    encryption_buffer: &mut [u8],
+) -> Result<(), RouteWeaverError> {
+    // Whatever was sent will come again over the new channel, and get confirmed then
+    let Some(mut channel) = server_state.transport_tracker.get_async(&node).await else {
+        return Ok(());
+    };
+
+    let packet = seal_segment(
+        server_state,
+        &mut channel,
+        node,
+        MessageSegment {
+            id: message_id,
+            payload: MessagePayload::MessageProgress {
+                confirmed_head: progress.head,
+                confirmed_bodies: progress.bodies,
+            },
+        },
+        encryption_buffer,
+    );
+
+    let packet = match packet {
+        Ok(packet) => packet,
+        Err(err) => {
+            tracing::error!(
+                "Failed encrypting message progress for node {}: {}",
+                node,
+                err
+            );
+            let _ = channel.remove();
+            return Ok(());
+        }
+    };
+
+    channel.touch();
+    drop(channel);
+
+    server_state
+        .request_route_packet
+        .send(RequestRoutePacket {
+            origin: None,
+            packet,
+        })
+        .await?;
+
+    Ok(())
+}
+
+/// Encrypts a segment over the channel and wraps it up for the node
+fn seal_segment(
+    server_state: &ServerState,
+    channel: &mut Channel,
+    node: PublicKey,
+    segment: MessageSegment,
+    encryption_buffer: &mut [u8],
+) -> Result<Packet, RouteWeaverError> {
+    let (epoch, nonce, amount) = channel.seal(
+        &bincode::serde::encode_to_vec(segment, bincode::config::standard())?,
+        encryption_buffer,
+    )?;
+
+    Ok(Packet::new(
+        server_state.keys.public,
+        Some(node),
+        PacketData::MessageSegment {
+            epoch,
+            nonce,
+            data: encryption_buffer[..amount].to_vec(),
+        },
+    ))
 }
 
 #[inline]
 pub fn handle_write_message(
     message_disassemblers: &mut HashMap<PublicKey, MessageDisassembler>,
-    notify_callbacks: &mut HashMap<MessageId, oneshot::Sender<RequestWriteMessageResponse>>,
+    notify_callbacks: &mut HashMap<
+        (PublicKey, MessageId),
+        oneshot::Sender<RequestWriteMessageResponse>,
+    >,
     notify_sent: Option<oneshot::Sender<RequestWriteMessageResponse>>,
     destination: PublicKey,
     message: Message,
+    allow_compression: bool,
 ) {
     let message_disassembler = message_disassemblers.entry(destination).or_default();
-    let message_id = message_disassembler.message(message);
+    let message_id = message_disassembler.message(message, allow_compression);
 
     if let Some(notify_sent) = notify_sent {
-        notify_callbacks.insert(message_id, notify_sent);
+        notify_callbacks.insert((destination, message_id), notify_sent);
     }
 }
 
+/// Returns how long the message took to be confirmed, if this is what finished confirming it
 #[inline]
 pub fn handle_update_message_status(
     message_disassemblers: &mut HashMap<PublicKey, MessageDisassembler>,
-    notify_callbacks: &mut HashMap<MessageId, oneshot::Sender<RequestWriteMessageResponse>>,
+    notify_callbacks: &mut HashMap<
+        (PublicKey, MessageId),
+        oneshot::Sender<RequestWriteMessageResponse>,
+    >,
+    first_sent: &mut HashMap<(PublicKey, MessageId), Instant>,
     message_id: MessageId,
     destination: PublicKey,
     head_status: bool,
     body_status: RangeInclusiveSet<u16>,
+) -> Option<Duration> {
+    let message_disassembler = message_disassemblers.get_mut(&destination)?;
+
+    message_disassembler.head_status(message_id, head_status);
+    message_disassembler.body_status(message_id, body_status);
+
+    if !message_disassembler.is_message_confirmed(message_id) {
+        return None;
+    }
+
+    let time_taken = first_sent
+        .remove(&(destination, message_id))
+        .map(|first_sent| first_sent.elapsed());
+
+    if let Some(notify_callback) = notify_callbacks.remove(&(destination, message_id)) {
+        // Don't really care if anyone is actually listening on the other end
+        let _ = notify_callback.send(RequestWriteMessageResponse {
+            time_taken: time_taken.unwrap_or_default(),
+        });
+    }
+
+    time_taken
+}
+
+#[inline]
+pub fn handle_channel_reset(
+    message_disassemblers: &mut HashMap<PublicKey, MessageDisassembler>,
+    notify_callbacks: &mut HashMap<
+        (PublicKey, MessageId),
+        oneshot::Sender<RequestWriteMessageResponse>,
+    >,
+    first_sent: &mut HashMap<(PublicKey, MessageId), Instant>,
+    node: PublicKey,
 ) {
-    if let Some(message_disassembler) = message_disassemblers.get_mut(&destination) {
-        message_disassembler.head_status(message_id, head_status);
-        message_disassembler.body_status(message_id, body_status);
-
-        if message_disassembler.is_message_confirmed(message_id) {
-            if let Some(notify_callback) = notify_callbacks.remove(&message_id) {
-                // Don't really care if anyone is actually listening on the other end
-                let _ = notify_callback.send(RequestWriteMessageResponse {
-                    time_taken: Duration::default(),
-                });
-            }
-        }
+    let Some(message_disassembler) = message_disassemblers.get_mut(&node) else {
+        return;
+    };
+
+    // How long the remote was gone says nothing about the links to it
+    first_sent.retain(|(destination, _), _| *destination != node);
+
+    let renumbered: Vec<_> = message_disassembler
+        .restart()
+        .into_iter()
+        .filter_map(|(old_message_id, new_message_id)| {
+            notify_callbacks
+                .remove(&(node, old_message_id))
+                .map(|notify_callback| (new_message_id, notify_callback))
+        })
+        .collect();
+
+    for (message_id, notify_callback) in renumbered {
+        notify_callbacks.insert((node, message_id), notify_callback);
     }
 }
@@ -1,12 +1,21 @@
-use crate::noise::PrivateKey;
-use routeweaver_common::{Peer, Protocol, PublicKey};
-use serde::Deserialize;
+use crate::{
+    error::RouteWeaverError,
+    noise::{generate_keys, verify_keys, PresharedKey, PrivateKey},
+};
+use routeweaver_common::{acl::AclRule, Peer, Protocol, PublicKey};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
 use serde_with::serde_as;
 use serde_with::DisplayFromStr;
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
 
 #[serde_as]
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Keys {
     #[serde_as(as = "DisplayFromStr")]
     pub public: PublicKey,
@@ -14,7 +23,48 @@ pub struct Keys {
     pub private: PrivateKey,
 }
 
+impl Keys {
+    /// Reads keys from a file in the same format as the keys table of the config
+    pub fn load(path: &Path) -> Result<Self, RouteWeaverError> {
+        let keys: Keys = toml::from_str(&std::fs::read_to_string(path)?)?;
+        verify_keys(&keys)?;
+
+        Ok(keys)
+    }
+
+    /// Writes keys to a file only we can read, refusing to replace an existing one
+    pub fn save(&self, path: &Path) -> Result<(), RouteWeaverError> {
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        options
+            .open(path)?
+            .write_all(toml::to_string(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    /// Loads the key file, creating it on first start so the node keeps its identity across restarts
+    pub fn load_or_generate(path: &Path) -> Result<Self, RouteWeaverError> {
+        match Keys::load(path) {
+            Err(RouteWeaverError::Standard(err)) if err.kind() == ErrorKind::NotFound => {
+                tracing::info!("Generating new keys at {}", path.display());
+
+                let keys = generate_keys();
+                keys.save(path)?;
+
+                Ok(keys)
+            }
+            result => result,
+        }
+    }
+}
+
 #[serde_as]
+#[serde_inline_default]
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(default)]
@@ -23,15 +73,136 @@ pub struct Config {
     /// Makes the server not try to give peers its public key
     pub anonymous: bool,
     pub keys: Option<Keys>,
+    /// File to keep the keys in if [Self::keys] is not set
+    pub key_path: Option<PathBuf>,
+    /// Key every node of a private mesh shares, nodes without it can't make channels with us
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(default)]
+    pub psk: Option<PresharedKey>,
+    /// Setting this or [Self::trusted_nodes_directory] refuses channels with any other node
+    #[serde(default)]
+    #[serde_as(as = "HashSet<DisplayFromStr>")]
+    pub trusted_nodes: HashSet<PublicKey>,
+    /// Directory of files with a public key per line, lines starting with # are ignored
+    pub trusted_nodes_directory: Option<PathBuf>,
     #[serde(default)]
     #[serde_as(as = "HashSet<DisplayFromStr>")]
     pub initial_peers: HashSet<Peer>,
     #[serde(default)]
     #[serde_as(as = "HashSet<DisplayFromStr>")]
     pub initial_denied_peers: HashSet<Peer>,
+    /// Rules checked in order before anything in [Self::initial_denied_peers]
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub initial_acl: Vec<AclRule>,
+    /// Most peers connected at once, counting both the ones we connected to and the ones that connected to us
+    pub max_peers: Option<usize>,
+    #[serde(default)]
+    #[serde_as(as = "HashMap<DisplayFromStr, _>")]
+    pub max_peers_per_protocol: HashMap<Protocol, usize>,
+    /// Seconds a channel can go without anything being sent or received before it is closed
+    #[serde_inline_default(600)]
+    pub channel_idle_timeout: u64,
+    /// Seconds between switching a channel to a new key
+    #[serde_inline_default(120)]
+    pub channel_rekey_interval: u64,
     #[serde(default)]
     #[serde_as(as = "HashMap<DisplayFromStr, _>")]
     pub transport_config: HashMap<Protocol, toml::Value>,
     #[serde(default)]
     pub discovery_config: HashMap<String, toml::Value>,
 }
+
+impl Config {
+    /// Everything in [Self::trusted_nodes] and [Self::trusted_nodes_directory], or nothing if neither is set
+    pub fn load_trusted_nodes(&self) -> Result<Option<HashSet<PublicKey>>, RouteWeaverError> {
+        let Some(directory) = &self.trusted_nodes_directory else {
+            return Ok((!self.trusted_nodes.is_empty()).then(|| self.trusted_nodes.clone()));
+        };
+
+        let mut trusted_nodes = self.trusted_nodes.clone();
+
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+
+            for line in std::fs::read_to_string(entry.path())?.lines() {
+                let line = line.trim();
+
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+
+                trusted_nodes.insert(line.parse()?);
+            }
+        }
+
+        Ok(Some(trusted_nodes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, Keys};
+    use routeweaver_common::PublicKey;
+
+    #[test]
+    fn key_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("routeweaver-keys-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let keys = Keys::load_or_generate(&path).unwrap();
+        let loaded = Keys::load_or_generate(&path).unwrap();
+        assert_eq!(keys.public, loaded.public);
+        assert_eq!(keys.private.as_ref(), loaded.private.as_ref());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Never clobber a key file that is already there
+        assert!(keys.save(&path).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn trusted_nodes_directory() {
+        let directory =
+            std::env::temp_dir().join(format!("routeweaver-trusted-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir(&directory).unwrap();
+
+        let first = PublicKey::new([1; 32]);
+        let second = PublicKey::new([2; 32]);
+        let third = PublicKey::new([3; 32]);
+        std::fs::write(
+            directory.join("office"),
+            format!("# Office\n{}\n\n{}\n", first, second),
+        )
+        .unwrap();
+
+        let config: Config = toml::from_str(&format!(
+            "trusted_nodes = [\"{}\"]\ntrusted_nodes_directory = {:?}",
+            third, directory
+        ))
+        .unwrap();
+        let trusted_nodes = config.load_trusted_nodes().unwrap().unwrap();
+        assert_eq!(trusted_nodes.len(), 3);
+        assert!([first, second, third]
+            .iter()
+            .all(|node| trusted_nodes.contains(node)));
+
+        let config: Config = toml::from_str("").unwrap();
+        assert!(config.load_trusted_nodes().unwrap().is_none());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
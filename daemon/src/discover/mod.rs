@@ -1,4 +1,7 @@
-use crate::{state::ServerState, transport::driver::Transport};
+use crate::{
+    state::ServerState,
+    transport::{connection_manager::PeerOrigin, driver::Transport},
+};
 use driver::Discovery;
 use futures_util::StreamExt;
 use routeweaver_common::{Address, Peer, Protocol};
@@ -86,13 +89,10 @@ pub async fn discoverer<D: Discovery>(server_state: Arc<ServerState>, discovery:
                         continue;
                     }
 
-                    if let Some(sender) =
-                        server_state.request_initiate_connection.get(&peer.protocol)
-                    {
-                        sender.send(peer.address).await.unwrap();
-                    } else {
-                        tracing::warn!("Protocol {} not supported", peer.protocol);
-                    }
+                    server_state
+                        .connection_manager
+                        .want(peer, PeerOrigin::Discovered)
+                        .await;
                 }
                 Err(err) => {
                     tracing::warn!("Failed to discover peer: {}", err);
@@ -1,5 +1,6 @@
-use routeweaver_common::RouteWeaverCommonError;
+use routeweaver_common::{PublicKey, RouteWeaverCommonError};
 use thiserror::Error;
+use tokio::sync::mpsc::error::SendError;
 
 #[derive(Error, Debug)]
 pub enum RouteWeaverError {
@@ -23,6 +24,8 @@ pub enum RouteWeaverError {
     IncorrectHandshakeMessage,
     #[error("invalid transport message")]
     IncorrectTransportMessage,
+    #[error("segment was already read")]
+    ReplayedSegment,
     #[error("invalid address")]
     InvalidAddress,
     #[error("invalid protocol")]
@@ -35,6 +38,8 @@ pub enum RouteWeaverError {
     InvalidKey,
     #[error("connection failed")]
     ConnectionFailed,
+    #[error("connection denied")]
+    ConnectionDenied,
     #[error("invalid packet payload index {index}")]
     InvalidPacketPayloadIndex { index: u16 },
     #[error("missing head")]
@@ -43,4 +48,30 @@ pub enum RouteWeaverError {
     InvalidClientMessage,
     #[error("config parsing error: {0}")]
     ConfigParsing(#[from] toml::de::Error),
+    #[error("config serializing error: {0}")]
+    ConfigSerializing(#[from] toml::ser::Error),
+    #[error("public key does not belong to the private key, it should be {expected}")]
+    KeyMismatch { expected: PublicKey },
+    #[error("internal channel closed")]
+    ChannelClosed,
+    #[error("node claimed to be {claimed} but is {actual}")]
+    NodeMismatch {
+        claimed: PublicKey,
+        actual: PublicKey,
+    },
+    #[error("node {node} is not trusted")]
+    UntrustedNode { node: PublicKey },
+    #[error("invalid frame")]
+    InvalidFrame,
+    #[error("frame of {size} bytes is too large")]
+    FrameTooLarge { size: usize },
+    #[error("unsupported protocol version {version}")]
+    UnsupportedVersion { version: u8 },
+}
+
+// Internal tasks only go away when the daemon is shutting down
+impl<T> From<SendError<T>> for RouteWeaverError {
+    fn from(_: SendError<T>) -> Self {
+        RouteWeaverError::ChannelClosed
+    }
 }
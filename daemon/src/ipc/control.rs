@@ -0,0 +1,235 @@
+use crate::{
+    acl::enforce_acl, error::RouteWeaverError, state::ServerState,
+    transport::connection_manager::PeerOrigin,
+};
+use bincode::error::DecodeError;
+use bytes::{Buf, BufMut, BytesMut};
+use futures_util::{SinkExt, StreamExt};
+use routeweaver_common::ipc::{
+    control::{ClientBoundControlIpc, ControlEvent, ServerBoundControlIpc},
+    DAEMON_CONTROL_SOCKET,
+};
+use std::{ops::Deref, sync::Arc};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    sync::broadcast::error::RecvError,
+};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+struct ConnectionParser;
+
+impl Encoder<ClientBoundControlIpc> for ConnectionParser {
+    type Error = RouteWeaverError;
+
+    fn encode(
+        &mut self,
+        item: ClientBoundControlIpc,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        bincode::serde::encode_into_std_write(
+            &item,
+            &mut dst.writer(),
+            bincode::config::standard(),
+        )?;
+        Ok(())
+    }
+}
+
+impl Decoder for ConnectionParser {
+    type Item = ServerBoundControlIpc;
+    type Error = RouteWeaverError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        match bincode::serde::decode_from_std_read(&mut src.reader(), bincode::config::standard()) {
+            Ok(item) => Ok(Some(item)),
+            Err(DecodeError::UnexpectedEnd { additional }) => {
+                src.reserve(additional);
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+pub async fn control_handler(server_state: Arc<ServerState>) {
+    let control_socket = UnixListener::bind(DAEMON_CONTROL_SOCKET.deref()).unwrap();
+
+    loop {
+        match control_socket.accept().await {
+            Ok((stream, _)) => {
+                tracing::debug!("Accepted control connection");
+
+                let ipc_connection = Framed::new(stream, ConnectionParser);
+                tokio::spawn(connection_handler(server_state.clone(), ipc_connection));
+            }
+            Err(err) => {
+                tracing::error!("Error accepting control connection: {}", err);
+            }
+        }
+    }
+}
+
+async fn connection_handler(
+    server_state: Arc<ServerState>,
+    mut ipc_connection: Framed<UnixStream, ConnectionParser>,
+) {
+    while let Some(Ok(message)) = ipc_connection.next().await {
+        let response = match message {
+            ServerBoundControlIpc::ShowPublicKey => ClientBoundControlIpc::PublicKey {
+                public_key: server_state.keys.public,
+            },
+            ServerBoundControlIpc::SubscribeEvents => {
+                if ipc_connection
+                    .send(ClientBoundControlIpc::Success)
+                    .await
+                    .is_ok()
+                {
+                    forward_events(&server_state, &mut ipc_connection).await;
+                }
+
+                break;
+            }
+            ServerBoundControlIpc::ListPeers => ClientBoundControlIpc::Peers {
+                peers: server_state.peer_tracker.peers().await,
+            },
+            ServerBoundControlIpc::ListHandshakes => {
+                let mut nodes = Vec::new();
+                server_state
+                    .handshake_tracker
+                    .scan_async(|node, _| nodes.push(*node))
+                    .await;
+
+                ClientBoundControlIpc::Nodes { nodes }
+            }
+            ServerBoundControlIpc::ListChannels => {
+                let mut nodes = Vec::new();
+                server_state
+                    .transport_tracker
+                    .scan_async(|node, _| nodes.push(*node))
+                    .await;
+
+                ClientBoundControlIpc::Nodes { nodes }
+            }
+            ServerBoundControlIpc::ListRoutes => ClientBoundControlIpc::Routes {
+                routes: server_state.routing_table.routes().await,
+            },
+            ServerBoundControlIpc::ListLocalAddresses => ClientBoundControlIpc::Peers {
+                peers: server_state.local_address_tracker.iter().await.collect(),
+            },
+            ServerBoundControlIpc::ListApplications => ClientBoundControlIpc::Applications {
+                applications: server_state.application_tracker.applications().await,
+            },
+            ServerBoundControlIpc::ListScores => ClientBoundControlIpc::Scores {
+                scores: server_state.score_keeper.scores().await,
+            },
+            ServerBoundControlIpc::ListDesiredPeers => ClientBoundControlIpc::DesiredPeers {
+                peers: server_state
+                    .connection_manager
+                    .desired_peers(&server_state.peer_tracker.peers().await)
+                    .await,
+            },
+            ServerBoundControlIpc::ConnectPeer { peer } => {
+                if server_state
+                    .request_initiate_connection
+                    .contains_async(&peer.protocol)
+                    .await
+                {
+                    tracing::info!("Connecting to {} as asked over the control socket", peer);
+
+                    server_state
+                        .connection_manager
+                        .want(peer, PeerOrigin::Configured)
+                        .await;
+
+                    ClientBoundControlIpc::Success
+                } else {
+                    ClientBoundControlIpc::ProtocolNotActive
+                }
+            }
+            ServerBoundControlIpc::DisconnectPeer { peer } => {
+                // Otherwise it would just get reconnected
+                let forgotten = server_state.connection_manager.forget(&peer).await;
+
+                if server_state.peer_tracker.disconnect(&peer).await || forgotten {
+                    tracing::info!(
+                        "Disconnecting from {} as asked over the control socket",
+                        peer
+                    );
+
+                    ClientBoundControlIpc::Success
+                } else {
+                    ClientBoundControlIpc::NotConnected
+                }
+            }
+            ServerBoundControlIpc::ListAclRules => ClientBoundControlIpc::AclRules {
+                rules: server_state.access_control.rules().await,
+            },
+            ServerBoundControlIpc::InsertAclRule { index, rule } => {
+                if server_state.access_control.insert(index, rule).await {
+                    tracing::info!("Added acl rule \"{}\"", rule);
+                    enforce_acl(&server_state).await;
+
+                    ClientBoundControlIpc::Success
+                } else {
+                    ClientBoundControlIpc::NoSuchAclRule
+                }
+            }
+            ServerBoundControlIpc::RemoveAclRule { index } => {
+                match server_state.access_control.remove(index).await {
+                    Some(rule) => {
+                        tracing::info!("Removed acl rule \"{}\"", rule);
+                        enforce_acl(&server_state).await;
+
+                        ClientBoundControlIpc::Success
+                    }
+                    None => ClientBoundControlIpc::NoSuchAclRule,
+                }
+            }
+        };
+
+        if ipc_connection.send(response).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Streams events to the client until either side goes away
+async fn forward_events(
+    server_state: &ServerState,
+    ipc_connection: &mut Framed<UnixStream, ConnectionParser>,
+) {
+    let mut peer_connected = server_state.notification_new_peer_connection.subscribe();
+    let mut peer_disconnected = server_state.notification_peer_disconnected.subscribe();
+    let mut channel_established = server_state.notification_handshaked_node.subscribe();
+
+    loop {
+        let event = tokio::select! {
+            peer = peer_connected.recv() => peer.map(|peer| ControlEvent::PeerConnected { peer }),
+            peer = peer_disconnected.recv() => peer.map(|peer| ControlEvent::PeerDisconnected { peer }),
+            node = channel_established.recv() => node.map(|node| ControlEvent::ChannelEstablished { node }),
+            // Nothing is expected from the client anymore, so this is only ever it going away
+            _ = ipc_connection.next() => break,
+        };
+
+        let event = match event {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!("Control client fell behind, skipped {} events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => break,
+        };
+
+        if ipc_connection
+            .send(ClientBoundControlIpc::Event { event })
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
@@ -1,14 +1,24 @@
-use routeweaver_common::ipc::{DAEMON_RPC_SOCKET, RPC_BASE_DIR};
-use socket::{socket_handler, ApplicationTracker};
+use crate::state::ServerState;
+use control::control_handler;
+use routeweaver_common::ipc::{
+    ACTIVE_STREAM_DIRECTORY, DAEMON_CONTROL_SOCKET, DAEMON_RPC_SOCKET, RPC_BASE_DIR,
+};
+use socket::socket_handler;
 use std::{ops::Deref, sync::Arc};
 use tokio::fs::{create_dir_all, remove_file};
 
-mod socket;
+mod control;
+pub mod socket;
+mod stream;
 
-pub async fn ipc_server() {
-    let application_tracker = Arc::new(ApplicationTracker::default());
+pub async fn ipc_server(server_state: Arc<ServerState>) {
     create_dir_all(RPC_BASE_DIR.deref()).await.unwrap();
+    create_dir_all(ACTIVE_STREAM_DIRECTORY.deref())
+        .await
+        .unwrap();
     let _ = remove_file(DAEMON_RPC_SOCKET.deref()).await;
+    let _ = remove_file(DAEMON_CONTROL_SOCKET.deref()).await;
 
-    socket_handler(application_tracker).await;
+    tokio::spawn(control_handler(server_state.clone()));
+    socket_handler(server_state).await;
 }
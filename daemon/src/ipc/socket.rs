@@ -1,13 +1,14 @@
-use crate::error::RouteWeaverError;
+use super::stream::open_stream;
+use crate::{channel::connection::request_connection, error::RouteWeaverError, state::ServerState};
 use bincode::error::DecodeError;
 use bytes::{Buf, BufMut, BytesMut};
-use futures_util::StreamExt;
+use futures_util::{SinkExt, StreamExt};
 use routeweaver_common::{
     ipc::{
         socket::{ClientBoundSocketIpc, ServerBoundSocketIpc},
         DAEMON_RPC_SOCKET,
     },
-    ApplicationId, ConnectionId,
+    ApplicationId, ConnectionId, PublicKey,
 };
 use std::{ops::Deref, sync::Arc};
 use tokio::{
@@ -15,6 +16,7 @@ use tokio::{
     sync::mpsc,
 };
 use tokio_util::codec::{Decoder, Encoder, Framed};
+use zeroize::Zeroizing;
 
 struct ConnectionParser;
 
@@ -55,15 +57,48 @@ impl Decoder for ConnectionParser {
     }
 }
 
+#[derive(Debug)]
 pub struct NewConnection {
+    pub node: PublicKey,
     pub id: ConnectionId,
-    pub receiver: mpsc::Receiver<Vec<u8>>,
+    pub receiver: mpsc::Receiver<Zeroizing<Vec<u8>>>,
 }
 
 #[derive(Debug, Default)]
 pub struct ApplicationTracker(scc::HashMap<ApplicationId, mpsc::Sender<NewConnection>>);
 
-pub async fn socket_handler(application_tracker: Arc<ApplicationTracker>) {
+impl ApplicationTracker {
+    /// Registers a listener for an application, returning false if something is already listening
+    pub async fn listen(
+        &self,
+        application_id: ApplicationId,
+        sender: mpsc::Sender<NewConnection>,
+    ) -> bool {
+        self.0.insert_async(application_id, sender).await.is_ok()
+    }
+
+    pub async fn get(&self, application_id: &ApplicationId) -> Option<mpsc::Sender<NewConnection>> {
+        self.0
+            .read_async(application_id, |_, sender| sender.clone())
+            .await
+    }
+
+    pub async fn applications(&self) -> Vec<ApplicationId> {
+        let mut applications = Vec::new();
+
+        self.0
+            .scan_async(|application_id, _| applications.push(*application_id))
+            .await;
+
+        applications
+    }
+
+    pub async fn remove(&self, application_id: &ApplicationId) {
+        self.0.remove_async(application_id).await;
+    }
+}
+
+pub async fn socket_handler(server_state: Arc<ServerState>) {
     let ipc_socket = UnixListener::bind(DAEMON_RPC_SOCKET.deref()).unwrap();
 
     loop {
@@ -72,10 +107,7 @@ pub async fn socket_handler(application_tracker: Arc<ApplicationTracker>) {
                 tracing::debug!("Accepted connection");
 
                 let ipc_connection = Framed::new(stream, ConnectionParser);
-                tokio::spawn(connection_handler(
-                    application_tracker.clone(),
-                    ipc_connection,
-                ));
+                tokio::spawn(connection_handler(server_state.clone(), ipc_connection));
             }
             Err(err) => {
                 tracing::error!("Error accepting connection: {}", err);
@@ -85,48 +117,131 @@ pub async fn socket_handler(application_tracker: Arc<ApplicationTracker>) {
 }
 
 async fn connection_handler(
-    application_tracker: Arc<ApplicationTracker>,
+    server_state: Arc<ServerState>,
     mut ipc_connection: Framed<UnixStream, ConnectionParser>,
 ) {
-    while let Some(Ok(message)) = ipc_connection.next().await {
-        match message {
-            ServerBoundSocketIpc::Listen { application_id } => {
-                let (sender, receiver) = mpsc::channel(100);
-
-                if application_tracker
-                    .0
-                    .insert_async(application_id, sender)
-                    .await
-                    .is_err()
-                {
-                    tracing::error!(
-                        "Service tried to listen while another service is listening on {}",
-                        application_id
-                    );
-                } else {
-                    tokio::spawn(connection_listener(
-                        application_tracker.clone(),
-                        receiver,
+    let mut listening = Vec::new();
+    // Connections for every application this service listens on end up here
+    let (new_connections_tx, mut new_connections_rx) = mpsc::channel(100);
+    // Answers to connection requests, sent as soon as the remote decides so they can come in any order
+    let (connect_responses_tx, mut connect_responses_rx) = mpsc::channel(100);
+
+    loop {
+        tokio::select! {
+            message = ipc_connection.next() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+
+                match message {
+                    ServerBoundSocketIpc::Listen { application_id } => {
+                        let response = if server_state
+                            .application_tracker
+                            .listen(application_id, new_connections_tx.clone())
+                            .await
+                        {
+                            listening.push(application_id);
+                            ClientBoundSocketIpc::Success
+                        } else {
+                            tracing::error!(
+                                "Service tried to listen while another service is listening on {}",
+                                application_id
+                            );
+                            ClientBoundSocketIpc::Denied
+                        };
+
+                        if ipc_connection.send(response).await.is_err() {
+                            break;
+                        }
+                    }
+                    ServerBoundSocketIpc::Connect {
+                        request_id,
                         application_id,
-                        false,
-                    ));
+                        destination,
+                    } => {
+                        tracing::debug!(
+                            "Service is connecting to application {} on node {}",
+                            application_id,
+                            destination
+                        );
+
+                        // The request itself is fine, now it's up to the remote
+                        if ipc_connection
+                            .send(ClientBoundSocketIpc::Success)
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+
+                        // Answering can take a while, so don't hold up anything else the service is doing
+                        let server_state = server_state.clone();
+                        let connect_responses_tx = connect_responses_tx.clone();
+                        tokio::spawn(async move {
+                            let response =
+                                match request_connection(&server_state, destination, application_id).await {
+                                    Ok(connection) => open_stream(server_state.clone(), connection).await,
+                                    Err(err) => Err(err),
+                                };
+
+                            let response = match response {
+                                Ok((stream_socket_path, stream_auth_token)) => ClientBoundSocketIpc::Stream {
+                                    request_id,
+                                    stream_socket_path,
+                                    stream_auth_token,
+                                },
+                                Err(err) => {
+                                    tracing::debug!(
+                                        "Failed connecting to application {} on node {}: {}",
+                                        application_id,
+                                        destination,
+                                        err
+                                    );
+
+                                    ClientBoundSocketIpc::ConnectionDenied { request_id }
+                                }
+                            };
+
+                            let _ = connect_responses_tx.send(response).await;
+                        });
+                    }
+                }
+            }
+            Some(response) = connect_responses_rx.recv() => {
+                if ipc_connection.send(response).await.is_err() {
+                    break;
+                }
+            }
+            Some(connection) = new_connections_rx.recv() => {
+                let node = connection.node;
+
+                match open_stream(server_state.clone(), connection).await {
+                    Ok((stream_socket_path, stream_auth_token)) => {
+                        if ipc_connection
+                            .send(ClientBoundSocketIpc::IncomingConnection {
+                                node,
+                                stream_socket_path,
+                                stream_auth_token,
+                            })
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        tracing::warn!("Failed to hand connection from node {} to service: {}", node, err);
+                    }
                 }
             }
-            ServerBoundSocketIpc::Connect {
-                application_id,
-                destination,
-            } => todo!(),
         }
     }
-}
 
-async fn connection_listener(
-    application_tracker: Arc<ApplicationTracker>,
-    mut new_connections: mpsc::Receiver<NewConnection>,
-    application_id: ApplicationId,
-    single: bool,
-) {
-    application_tracker.0.remove_async(&application_id).await;
-
-    while let Some(NewConnection { id, receiver }) = new_connections.recv().await {}
+    // The service went away, so stop routing connections to it
+    for application_id in listening {
+        server_state
+            .application_tracker
+            .remove(&application_id)
+            .await;
+    }
 }
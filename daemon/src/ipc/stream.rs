@@ -0,0 +1,233 @@
+use super::socket::NewConnection;
+use crate::{
+    channel::{connection::close_connection, writer::RequestWriteMessage},
+    error::RouteWeaverError,
+    proto::Message,
+    state::ServerState,
+};
+use bincode::error::DecodeError;
+use bytes::{Buf, BufMut, BytesMut};
+use data_encoding::HEXLOWER;
+use futures_util::{stream::FuturesOrdered, SinkExt, StreamExt};
+use routeweaver_common::ipc::{
+    stream::{ClientBoundStreamIpc, ServerBoundStreamIpc},
+    StreamAuthToken, ACTIVE_STREAM_DIRECTORY,
+};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::{fs::remove_file, net::UnixListener, sync::oneshot, time::timeout};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use zeroize::Zeroizing;
+
+/// How long the service has to show up on a stream socket before we give up on it
+const STREAM_ACCEPT_TIMEOUT: Duration = Duration::from_secs(10);
+
+struct ConnectionParser;
+
+impl Encoder<ClientBoundStreamIpc> for ConnectionParser {
+    type Error = RouteWeaverError;
+
+    fn encode(
+        &mut self,
+        item: ClientBoundStreamIpc,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        bincode::serde::encode_into_std_write(
+            &item,
+            &mut dst.writer(),
+            bincode::config::standard(),
+        )?;
+        Ok(())
+    }
+}
+
+impl Decoder for ConnectionParser {
+    type Item = ServerBoundStreamIpc;
+    type Error = RouteWeaverError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        match bincode::serde::decode_from_std_read(&mut src.reader(), bincode::config::standard()) {
+            Ok(item) => Ok(Some(item)),
+            Err(DecodeError::UnexpectedEnd { additional }) => {
+                src.reserve(additional);
+                Ok(None)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+/// Creates a socket a service can pick up a connection from, returning where it is and the token needed to use it
+pub async fn open_stream(
+    server_state: Arc<ServerState>,
+    connection: NewConnection,
+) -> Result<(PathBuf, StreamAuthToken), RouteWeaverError> {
+    let stream_socket_path =
+        ACTIVE_STREAM_DIRECTORY.join(HEXLOWER.encode(&rand::random::<[u8; 16]>()));
+    let stream_auth_token = rand::random();
+
+    let listener = match UnixListener::bind(&stream_socket_path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            let _ = close_connection(&server_state, connection.node, connection.id, true).await;
+            return Err(err.into());
+        }
+    };
+
+    tokio::spawn(stream_handler(
+        server_state,
+        listener,
+        stream_socket_path.clone(),
+        StreamAuthToken::new(stream_auth_token),
+        connection,
+    ));
+
+    Ok((stream_socket_path, StreamAuthToken::new(stream_auth_token)))
+}
+
+async fn stream_handler(
+    server_state: Arc<ServerState>,
+    listener: UnixListener,
+    stream_socket_path: PathBuf,
+    stream_auth_token: StreamAuthToken,
+    mut connection: NewConnection,
+) {
+    let accepted = timeout(STREAM_ACCEPT_TIMEOUT, listener.accept()).await;
+
+    // Only ever one service gets to use this, so get rid of it right away
+    drop(listener);
+    let _ = remove_file(&stream_socket_path).await;
+
+    let Ok(Ok((stream, _))) = accepted else {
+        tracing::debug!(
+            "Service never picked up connection {} with node {}",
+            connection.id,
+            connection.node
+        );
+
+        let _ = close_connection(&server_state, connection.node, connection.id, true).await;
+        return;
+    };
+
+    let mut ipc_connection = Framed::new(stream, ConnectionParser);
+
+    match ipc_connection.next().await {
+        Some(Ok(ServerBoundStreamIpc::Auth { token })) if token == stream_auth_token => {}
+        _ => {
+            tracing::warn!(
+                "Service failed to authenticate for connection {} with node {}",
+                connection.id,
+                connection.node
+            );
+
+            let _ = close_connection(&server_state, connection.node, connection.id, true).await;
+            return;
+        }
+    }
+
+    if ipc_connection
+        .send(ClientBoundStreamIpc::AuthSuccess)
+        .await
+        .is_err()
+    {
+        let _ = close_connection(&server_state, connection.node, connection.id, true).await;
+        return;
+    }
+
+    let mut pending_confirmations = FuturesOrdered::new();
+    // Whether the service said it won't write anymore, in which case only the remote is left to hear from
+    let mut service_shut_down = false;
+
+    // Whether the service went away, as opposed to the remote
+    let service_closed = loop {
+        tokio::select! {
+            message = ipc_connection.next() => {
+                match message {
+                    Some(Ok(ServerBoundStreamIpc::Data { .. })) if service_shut_down => {
+                        tracing::warn!("Service wrote after shutting down connection {}", connection.id);
+                        break true;
+                    }
+                    Some(Ok(ServerBoundStreamIpc::Data { data })) => {
+                        let (notify_sent, confirmation) = oneshot::channel();
+
+                        if server_state
+                            .request_write_message
+                            .send(RequestWriteMessage {
+                                notify_sent: Some(notify_sent),
+                                destination: connection.node,
+                                message: Message::ConnectionData {
+                                    connection_id: connection.id,
+                                    data: Zeroizing::new(data),
+                                },
+                            })
+                            .await
+                            .is_err()
+                        {
+                            break true;
+                        }
+
+                        pending_confirmations.push_back(confirmation);
+                    }
+                    Some(Ok(ServerBoundStreamIpc::Shutdown)) => {
+                        tracing::debug!("Service is done writing to connection {}", connection.id);
+                        service_shut_down = true;
+                    }
+                    Some(Ok(ServerBoundStreamIpc::Auth { .. })) => {
+                        tracing::warn!("Service tried authenticating twice for connection {}", connection.id);
+                        break true;
+                    }
+                    Some(Err(err)) => {
+                        tracing::warn!("Error reading from service for connection {}: {}", connection.id, err);
+                        break true;
+                    }
+                    None => break true,
+                }
+            }
+            Some(confirmation) = pending_confirmations.next(), if !pending_confirmations.is_empty() => {
+                // The service holds off writing until we confirm, so losing data has to end the stream
+                let Ok(response) = confirmation else {
+                    tracing::debug!("Failed sending data over connection {}", connection.id);
+                    break true;
+                };
+
+                tracing::trace!(
+                    "Data over connection {} was confirmed after {:?}",
+                    connection.id,
+                    response.time_taken
+                );
+
+                if ipc_connection.send(ClientBoundStreamIpc::DataSendingSuccess).await.is_err() {
+                    break true;
+                }
+            }
+            data = connection.receiver.recv() => {
+                match data {
+                    Some(data) => {
+                        if ipc_connection.send(ClientBoundStreamIpc::Data { data }).await.is_err() {
+                            break true;
+                        }
+                    }
+                    // The connection got torn down, either by the remote or by timing out
+                    None => break false,
+                }
+            }
+        }
+    };
+
+    tracing::debug!(
+        "Connection {} with node {} closed",
+        connection.id,
+        connection.node
+    );
+
+    let _ = close_connection(
+        &server_state,
+        connection.node,
+        connection.id,
+        service_closed,
+    )
+    .await;
+}
@@ -1,24 +1,39 @@
+use acl::MeshTrust;
 use channel::{
-    initiate::channel_initiator, reader::channel_read_message, writer::channel_write_message,
+    connection::connection_keeper,
+    initiate::channel_initiator,
+    lifetime::channel_expirer,
+    reader::{channel_read_message, RequestDecodeMessageSegment},
+    writer::{channel_write_message, RequestUpdateMessageStatus, RequestWriteMessage},
 };
-use clap::Parser;
-use config::Config;
+use clap::{Parser, Subcommand};
+use config::{Config, Keys};
 use discover::{
     announcer, discoverer,
     driver::{udp_multicast::UdpMulticastDiscovery, Discovery},
     local_address_refresher,
 };
 use ipc::ipc_server;
-use noise::generate_keys;
-use routeweaver_common::Protocol;
+use noise::{generate_keys, verify_keys};
+use routeweaver_common::{
+    acl::{AclMatcher, AclRule},
+    Address, Protocol, PublicKey,
+};
 use state::ServerState;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{signal::ctrl_c, sync::mpsc};
 use transport::{
-    accepter::accepter, driver::Transport, handshake_continue::handshake_continue,
-    initiate::connection_initiator, router::packet_router,
+    accepter::accepter,
+    connection_manager::{reconnector, ConnectionLimits, ConnectionManager, PeerOrigin},
+    driver::Transport,
+    handshake::handshake_keeper,
+    initiate::connection_initiator,
+    misbehaviour::misbehaviour_keeper,
+    router::{packet_router, RequestRoutePacket},
+    routing_table::route_advertiser,
 };
 
+mod acl;
 mod channel;
 mod config;
 mod discover;
@@ -26,16 +41,29 @@ mod error;
 mod ipc;
 mod noise;
 mod proto;
+#[cfg(test)]
+mod simulation;
 mod state;
 
 // mod runtime;
 mod transport;
 
+#[derive(Subcommand, Debug)]
+pub enum CliActions {
+    /// Prints a freshly generated keys table for the config
+    Keygen,
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, subcommand_negates_reqs = true)]
 pub struct Cli {
+    #[arg(short, long, required = true)]
+    config_location: Option<PathBuf>,
+    /// Overrides key_path from the config
     #[arg(short, long)]
-    config_location: PathBuf,
+    key_file: Option<PathBuf>,
+    #[clap(subcommand)]
+    action: Option<CliActions>,
 }
 
 #[tokio::main]
@@ -44,35 +72,97 @@ async fn main() {
 
     let cli = Cli::parse();
 
+    if let Some(CliActions::Keygen) = cli.action {
+        #[derive(serde::Serialize)]
+        struct KeysTable {
+            keys: Keys,
+        }
+
+        print!(
+            "{}",
+            toml::to_string(&KeysTable {
+                keys: generate_keys()
+            })
+            .unwrap()
+        );
+        return;
+    }
+
+    let config_location = cli.config_location.unwrap();
     let config =
-        toml::from_str::<Config>(&std::fs::read_to_string(&cli.config_location).unwrap()).unwrap();
+        toml::from_str::<Config>(&std::fs::read_to_string(&config_location).unwrap()).unwrap();
 
-    let keys = config.keys.unwrap_or_else(generate_keys);
-    let (request_route_packet_tx, request_route_packet_rx) = mpsc::channel(100);
-    let (request_write_message_tx, request_write_message_rx) = mpsc::channel(100);
-    let (request_initiate_channel_tx, request_initiate_channel_rx) = mpsc::channel(100);
-    let (request_update_message_status_tx, request_update_message_status_rx) = mpsc::channel(100);
-    let (request_decode_message_segment_tx, request_decode_message_segment_rx) = mpsc::channel(100);
+    let trusted_nodes = match config.load_trusted_nodes() {
+        Ok(trusted_nodes) => trusted_nodes,
+        Err(err) => {
+            tracing::error!("Could not load trusted nodes: {}", err);
+            return;
+        }
+    };
 
-    let server_state = Arc::new(ServerState::new(
-        config.anonymous,
-        keys,
-        request_route_packet_tx,
-        request_write_message_tx,
-        request_initiate_channel_tx,
-        request_decode_message_segment_tx,
-        request_update_message_status_tx,
-    ));
+    let keys = match (config.keys, cli.key_file.or(config.key_path)) {
+        (Some(keys), _) => verify_keys(&keys).map(|_| keys),
+        (None, Some(key_path)) => Keys::load_or_generate(&key_path),
+        (None, None) => {
+            tracing::warn!(
+                "No keys or key path configured, this node will get a new identity on restart"
+            );
+            Ok(generate_keys())
+        }
+    };
+
+    let keys = match keys {
+        Ok(keys) => keys,
+        Err(err) => {
+            tracing::error!("Could not load keys: {}", err);
+            return;
+        }
+    };
+    let (server_state, request_receivers) = create_server_state(config.anonymous, keys);
+    let server_state = Arc::new(ServerState {
+        mesh_trust: MeshTrust {
+            psk: config.psk,
+            trusted_nodes,
+        },
+        connection_manager: ConnectionManager::new(ConnectionLimits {
+            max_peers: config.max_peers,
+            max_peers_per_protocol: config.max_peers_per_protocol,
+        }),
+        ..server_state
+    });
 
     tracing::info!("Starting RouteWeaver v{}", env!("CARGO_PKG_VERSION"));
     tracing::info!("This nodes public key is {}", server_state.keys.public);
 
+    for rule in config.initial_acl {
+        server_state.access_control.insert(None, rule).await;
+    }
+
+    for denied_peer in config.initial_denied_peers {
+        // The port is only where they were listening, so the whole address gets denied
+        let matcher = match denied_peer.address {
+            Address::Ip { address, .. } => AclMatcher::IpRange(address.into()),
+            Address::Bluetooth { .. } | Address::Unix { .. } => AclMatcher::Peer(denied_peer),
+        };
+
+        server_state
+            .access_control
+            .insert(None, AclRule::deny(matcher))
+            .await;
+    }
+
     #[cfg(transport_tcp)]
     setup_transport::<transport::driver::tcp::TcpTransport>(
         server_state.clone(),
         &config.transport_config,
     )
     .await;
+    #[cfg(transport_udp)]
+    setup_transport::<transport::driver::udp::UdpTransport>(
+        server_state.clone(),
+        &config.transport_config,
+    )
+    .await;
     #[cfg(transport_ws)]
     setup_transport::<transport::driver::ws::WsTransport>(
         server_state.clone(),
@@ -85,6 +175,18 @@ async fn main() {
         &config.transport_config,
     )
     .await;
+    #[cfg(transport_quic)]
+    setup_transport::<transport::driver::quic::QuicTransport>(
+        server_state.clone(),
+        &config.transport_config,
+    )
+    .await;
+    #[cfg(transport_unix)]
+    setup_transport::<transport::driver::unix::UnixTransport>(
+        server_state.clone(),
+        &config.transport_config,
+    )
+    .await;
 
     if let Some(config) = config.discovery_config.get(UdpMulticastDiscovery::ID) {
         let discovery = Arc::new(
@@ -97,33 +199,22 @@ async fn main() {
         tokio::spawn(discoverer(server_state.clone(), discovery));
     }
 
-    tokio::spawn(packet_router(server_state.clone(), request_route_packet_rx));
-    tokio::spawn(channel_write_message(
+    spawn_node_tasks(
         server_state.clone(),
-        request_write_message_rx,
-        request_update_message_status_rx,
-    ));
-    tokio::spawn(channel_read_message(
-        server_state.clone(),
-        request_decode_message_segment_rx,
-    ));
-    tokio::spawn(channel_initiator(
-        server_state.clone(),
-        request_initiate_channel_rx,
-    ));
-    tokio::spawn(handshake_continue(server_state.clone()));
+        request_receivers,
+        Duration::from_secs(config.channel_rekey_interval),
+        Duration::from_secs(config.channel_idle_timeout),
+    );
 
     for initial_peer in config.initial_peers {
-        if let Some(sender) = server_state
-            .request_initiate_connection
-            .get(&initial_peer.protocol)
-        {
-            sender.send(initial_peer.address).await.unwrap();
-        }
+        server_state
+            .connection_manager
+            .want(initial_peer, PeerOrigin::Configured)
+            .await;
     }
 
     if !config.routing_only {
-        ipc_server().await;
+        ipc_server(server_state.clone()).await;
     } else {
         ctrl_c().await.unwrap();
     }
@@ -131,31 +222,107 @@ async fn main() {
     tracing::info!("Shutting down");
 }
 
+/// Receiving ends of the queues handed to [ServerState::new]
+struct RequestReceivers {
+    request_route_packet: mpsc::Receiver<RequestRoutePacket>,
+    request_write_message: mpsc::Receiver<RequestWriteMessage>,
+    request_initiate_channel: mpsc::Receiver<PublicKey>,
+    request_update_message_status: mpsc::Receiver<RequestUpdateMessageStatus>,
+    request_decode_message_segment: mpsc::Receiver<RequestDecodeMessageSegment>,
+}
+
+fn create_server_state(anonymous: bool, keys: Keys) -> (ServerState, RequestReceivers) {
+    let (request_route_packet_tx, request_route_packet_rx) = mpsc::channel(100);
+    let (request_write_message_tx, request_write_message_rx) = mpsc::channel(100);
+    let (request_initiate_channel_tx, request_initiate_channel_rx) = mpsc::channel(100);
+    let (request_update_message_status_tx, request_update_message_status_rx) = mpsc::channel(100);
+    let (request_decode_message_segment_tx, request_decode_message_segment_rx) = mpsc::channel(100);
+
+    (
+        ServerState::new(
+            anonymous,
+            keys,
+            request_route_packet_tx,
+            request_write_message_tx,
+            request_initiate_channel_tx,
+            request_decode_message_segment_tx,
+            request_update_message_status_tx,
+        ),
+        RequestReceivers {
+            request_route_packet: request_route_packet_rx,
+            request_write_message: request_write_message_rx,
+            request_initiate_channel: request_initiate_channel_rx,
+            request_update_message_status: request_update_message_status_rx,
+            request_decode_message_segment: request_decode_message_segment_rx,
+        },
+    )
+}
+
+/// Starts everything a node runs no matter which transports and discovery methods it has
+fn spawn_node_tasks(
+    server_state: Arc<ServerState>,
+    request_receivers: RequestReceivers,
+    channel_rekey_interval: Duration,
+    channel_idle_timeout: Duration,
+) {
+    tokio::spawn(packet_router(
+        server_state.clone(),
+        request_receivers.request_route_packet,
+    ));
+    tokio::spawn(route_advertiser(server_state.clone()));
+    tokio::spawn(channel_write_message(
+        server_state.clone(),
+        request_receivers.request_write_message,
+        request_receivers.request_update_message_status,
+        channel_rekey_interval,
+    ));
+    tokio::spawn(channel_read_message(
+        server_state.clone(),
+        request_receivers.request_decode_message_segment,
+    ));
+    tokio::spawn(channel_expirer(server_state.clone(), channel_idle_timeout));
+    tokio::spawn(channel_initiator(
+        server_state.clone(),
+        request_receivers.request_initiate_channel,
+    ));
+    tokio::spawn(handshake_keeper(server_state.clone()));
+    tokio::spawn(misbehaviour_keeper(server_state.clone()));
+    tokio::spawn(connection_keeper(server_state.clone()));
+    tokio::spawn(reconnector(server_state));
+}
+
 async fn setup_transport<T: Transport>(
     server_state: Arc<ServerState>,
     config: &HashMap<Protocol, toml::Value>,
 ) {
     if let Some(config) = config.get(&T::PROTOCOL) {
-        let transport = Arc::new(T::from_config(config.clone()).await.unwrap());
-        let (request_initiate_connection_tx, request_initiate_connection_rx) = mpsc::channel(10);
-
-        server_state
-            .request_initiate_connection
-            .insert_async(T::PROTOCOL, request_initiate_connection_tx)
-            .await
-            .expect("Transport should be unique");
-
-        tokio::spawn(accepter(server_state.clone(), transport.clone()));
-
-        tokio::spawn(local_address_refresher(
-            server_state.clone(),
-            transport.clone(),
-        ));
-
-        tokio::spawn(connection_initiator(
-            server_state.clone(),
-            transport.clone(),
-            request_initiate_connection_rx,
-        ));
+        start_transport(
+            server_state,
+            Arc::new(T::from_config(config.clone()).await.unwrap()),
+        )
+        .await;
     }
 }
+
+async fn start_transport<T: Transport>(server_state: Arc<ServerState>, transport: Arc<T>) {
+    let (request_initiate_connection_tx, request_initiate_connection_rx) = mpsc::channel(10);
+
+    server_state
+        .request_initiate_connection
+        .insert_async(T::PROTOCOL, request_initiate_connection_tx)
+        .await
+        .expect("Transport should be unique");
+
+    tokio::spawn(accepter(server_state.clone(), transport.clone()));
+
+    tokio::spawn(local_address_refresher(
+        server_state.clone(),
+        transport.clone(),
+    ));
+
+    tokio::spawn(connection_initiator(
+        server_state,
+        transport,
+        request_initiate_connection_rx,
+    ));
+}
@@ -2,13 +2,25 @@ use crate::{config::Keys, error::RouteWeaverError};
 use data_encoding::HEXLOWER_PERMISSIVE;
 use routeweaver_common::PublicKey;
 use serde::{Deserialize, Serialize};
-use snow::{params::NoiseParams, HandshakeState};
+use snow::{
+    error::StateProblem,
+    params::NoiseParams,
+    resolvers::{CryptoResolver, DefaultResolver},
+    types::Cipher,
+    HandshakeState,
+};
 use std::{fmt::Display, str::FromStr, sync::LazyLock};
-use zeroize::ZeroizeOnDrop;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
+
+const CIPHER_KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
 
 // Pattern used, unlikely to change
 static NOISE_PATTERN: LazyLock<NoiseParams> =
     LazyLock::new(|| "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap());
+// Same as above but mixing in a key every node of the mesh was given beforehand
+static NOISE_PSK_PATTERN: LazyLock<NoiseParams> =
+    LazyLock::new(|| "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s".parse().unwrap());
 
 pub fn generate_keys() -> Keys {
     let keypair = snow::Builder::new(NOISE_PATTERN.clone())
@@ -21,18 +33,118 @@ pub fn generate_keys() -> Keys {
     }
 }
 
-pub fn create_handshake_responder(key: &PrivateKey) -> HandshakeState {
-    snow::Builder::new(NOISE_PATTERN.clone())
-        .local_private_key(key.as_ref())
-        .build_responder()
-        .unwrap()
+/// Public key that goes with a private key
+pub fn derive_public_key(private: &PrivateKey) -> PublicKey {
+    let mut dh = DefaultResolver
+        .resolve_dh(&NOISE_PATTERN.dh)
+        .expect("Default resolver supports the pattern");
+    dh.set(private.as_ref());
+
+    PublicKey::new(dh.pubkey().try_into().unwrap())
+}
+
+/// Makes sure the public key was not mistyped or mixed up with another node's
+pub fn verify_keys(keys: &Keys) -> Result<(), RouteWeaverError> {
+    let expected = derive_public_key(&keys.private);
+
+    if keys.public != expected {
+        return Err(RouteWeaverError::KeyMismatch { expected });
+    }
+
+    Ok(())
+}
+
+fn handshake_builder<'a>(key: &'a PrivateKey, psk: Option<&'a PresharedKey>) -> snow::Builder<'a> {
+    match psk {
+        Some(psk) => snow::Builder::new(NOISE_PSK_PATTERN.clone())
+            .local_private_key(key.as_ref())
+            .psk(3, psk.as_ref()),
+        None => snow::Builder::new(NOISE_PATTERN.clone()).local_private_key(key.as_ref()),
+    }
+}
+
+pub fn create_handshake_responder(key: &PrivateKey, psk: Option<&PresharedKey>) -> HandshakeState {
+    handshake_builder(key, psk).build_responder().unwrap()
+}
+
+pub fn create_handshake_initiator(key: &PrivateKey, psk: Option<&PresharedKey>) -> HandshakeState {
+    handshake_builder(key, psk).build_initiator().unwrap()
+}
+
+/// Key for one direction of a channel, only ever used for a single epoch
+pub struct EpochKey(Box<dyn Cipher>);
+
+impl EpochKey {
+    fn new(key: &[u8]) -> Self {
+        let mut cipher = DefaultResolver
+            .resolve_cipher(&NOISE_PATTERN.cipher)
+            .expect("Default resolver supports the pattern");
+        cipher.set(key);
+
+        Self(cipher)
+    }
+
+    /// Key for the epoch after this one, made the way noise rekeys so this key can't be worked out from it
+    pub fn next(&self) -> Self {
+        let mut key = Zeroizing::new([0; CIPHER_KEY_LENGTH + TAG_LENGTH]);
+        self.0
+            .encrypt(u64::MAX, &[], &[0; CIPHER_KEY_LENGTH], &mut key[..]);
+
+        Self::new(&key[..CIPHER_KEY_LENGTH])
+    }
+
+    pub fn encrypt(
+        &self,
+        nonce: u64,
+        plaintext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, RouteWeaverError> {
+        // The last nonce is what rekeying uses
+        if nonce == u64::MAX || plaintext.len() + TAG_LENGTH > out.len() {
+            return Err(snow::Error::Input.into());
+        }
+
+        Ok(self.0.encrypt(nonce, &[], plaintext, out))
+    }
+
+    pub fn decrypt(
+        &self,
+        nonce: u64,
+        ciphertext: &[u8],
+        out: &mut [u8],
+    ) -> Result<usize, RouteWeaverError> {
+        // The cipher itself panics on anything too short to even hold the tag
+        if nonce == u64::MAX
+            || ciphertext.len() < TAG_LENGTH
+            || ciphertext.len() - TAG_LENGTH > out.len()
+        {
+            return Err(snow::Error::Decrypt.into());
+        }
+
+        Ok(self.0.decrypt(nonce, &[], ciphertext, out)?)
+    }
 }
 
-pub fn create_handshake_initiator(key: &PrivateKey) -> HandshakeState {
-    snow::Builder::new(NOISE_PATTERN.clone())
-        .local_private_key(key.as_ref())
-        .build_initiator()
-        .unwrap()
+/// Splits a finished handshake into the keys for sending and receiving over the channel
+pub fn split_handshake(
+    mut handshake_state: HandshakeState,
+) -> Result<(EpochKey, EpochKey), RouteWeaverError> {
+    if !handshake_state.is_handshake_finished() {
+        return Err(snow::Error::State(StateProblem::HandshakeNotFinished).into());
+    }
+
+    let (initiator, responder) = handshake_state.dangerously_get_raw_split();
+    let (initiator, responder) = (Zeroizing::new(initiator), Zeroizing::new(responder));
+    let (initiator, responder) = (
+        EpochKey::new(initiator.as_ref()),
+        EpochKey::new(responder.as_ref()),
+    );
+
+    if handshake_state.is_initiator() {
+        Ok((initiator, responder))
+    } else {
+        Ok((responder, initiator))
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, ZeroizeOnDrop)]
@@ -69,3 +181,93 @@ impl FromStr for PrivateKey {
         ))
     }
 }
+
+/// Secret shared by every node allowed into the mesh
+#[derive(Debug, ZeroizeOnDrop)]
+pub struct PresharedKey([u8; 32]);
+
+impl AsRef<[u8]> for PresharedKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for PresharedKey {
+    type Err = RouteWeaverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(PresharedKey(
+            HEXLOWER_PERMISSIVE
+                .decode(s.as_bytes())
+                .map_err(|_| RouteWeaverError::InvalidKey)?
+                .try_into()
+                .map_err(|_| RouteWeaverError::InvalidKey)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        create_handshake_initiator, create_handshake_responder, generate_keys, verify_keys,
+        PresharedKey,
+    };
+    use crate::config::Keys;
+
+    /// Runs all three messages of the handshake, returning if both sides were happy
+    fn handshake(
+        initiator_psk: Option<&PresharedKey>,
+        responder_psk: Option<&PresharedKey>,
+    ) -> bool {
+        let initiator_keys = generate_keys();
+        let responder_keys = generate_keys();
+        let mut initiator = create_handshake_initiator(&initiator_keys.private, initiator_psk);
+        let mut responder = create_handshake_responder(&responder_keys.private, responder_psk);
+        let mut message = [0; 128];
+        let mut payload = [0; 128];
+
+        for initiator_turn in [true, false, true] {
+            let (sender, receiver) = if initiator_turn {
+                (&mut initiator, &mut responder)
+            } else {
+                (&mut responder, &mut initiator)
+            };
+
+            let Ok(amount) = sender.write_message(&[], &mut message) else {
+                return false;
+            };
+
+            if receiver
+                .read_message(&message[..amount], &mut payload)
+                .is_err()
+            {
+                return false;
+            }
+        }
+
+        initiator.is_handshake_finished() && responder.is_handshake_finished()
+    }
+
+    #[test]
+    fn preshared_key_handshake() {
+        let psk: PresharedKey = "11".repeat(32).parse().unwrap();
+        let other_psk: PresharedKey = "22".repeat(32).parse().unwrap();
+
+        assert!(handshake(None, None));
+        assert!(handshake(Some(&psk), Some(&psk)));
+        assert!(!handshake(Some(&psk), Some(&other_psk)));
+        assert!(!handshake(None, Some(&psk)));
+    }
+
+    #[test]
+    fn generated_keys_verify() {
+        let keys = generate_keys();
+        assert!(verify_keys(&keys).is_ok());
+
+        let mismatched = Keys {
+            public: generate_keys().public,
+            private: keys.private,
+        };
+        assert!(verify_keys(&mismatched).is_err());
+    }
+}
@@ -1,8 +1,74 @@
-use routeweaver_common::{ApplicationId, ConnectionId, Peer};
+use crate::{channel::connection::RequestId, transport::routing_table::Metric};
+use routeweaver_common::{ApplicationId, ConnectionId, Peer, PublicKey};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use zeroize::Zeroizing;
 
+/// Version of the wire protocol, bumped whenever [Message] or the packets carrying it change shape
+///
+/// Nodes from before there was a hello count as the first version, which packets have changed shape from since. The
+/// framing around packets on stream transports has a version of its own, see [crate::transport::driver::FRAME_VERSION]
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Oldest version of the wire protocol still spoken
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+/// Messages compressed with lz4
+pub const COMPRESSION_LZ4: u8 = 1 << 0;
+/// Takes part in routing through [Message::RouteAdvertisement]
+pub const FEATURE_ROUTE_ADVERTISEMENTS: u8 = 1 << 0;
+
+/// What a node supports, told to peers when connecting and to nodes when setting up a channel
+///
+/// The layout must never change so any two versions can read each other's, anything new goes in as another bit
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Newest protocol version spoken, or the one agreed on once negotiated
+    pub version: u8,
+    pub min_version: u8,
+    /// Bits of the compression algorithms understood, unknown ones are simply never in common
+    pub compression: u8,
+    /// Bits of the optional features supported
+    pub features: u8,
+}
+
+impl Capabilities {
+    pub const fn ours() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            compression: COMPRESSION_LZ4,
+            features: FEATURE_ROUTE_ADVERTISEMENTS,
+        }
+    }
+
+    /// Newest protocol version both sides speak, if there is any
+    pub fn common_version(&self, remote: &Self) -> Option<u8> {
+        let version = self.version.min(remote.version);
+
+        (version >= self.min_version.max(remote.min_version)).then_some(version)
+    }
+
+    /// What both sides can use, [Option::None] if there is no protocol version both speak
+    pub fn negotiate(&self, remote: &Self) -> Option<Self> {
+        let version = self.common_version(remote)?;
+
+        Some(Self {
+            version,
+            min_version: version,
+            compression: self.compression & remote.compression,
+            features: self.features & remote.features,
+        })
+    }
+
+    pub fn supports_lz4(&self) -> bool {
+        self.compression & COMPRESSION_LZ4 != 0
+    }
+
+    pub fn supports_route_advertisements(&self) -> bool {
+        self.features & FEATURE_ROUTE_ADVERTISEMENTS != 0
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     /// Requests that a remote node gives us its "best peers"
@@ -13,15 +79,18 @@ pub enum Message {
     },
     /// Requests that a remote node opens a connection with us over said application
     RequestConnection {
+        request_id: RequestId,
         application: ApplicationId,
     },
-    /// Last connection was accepted
+    /// Connection request with this id was accepted
     ConnectionAccepted {
+        request_id: RequestId,
         application: ApplicationId,
         connection_id: ConnectionId,
     },
-    /// Last connection was denied
+    /// Connection request with this id was denied
     ConnectionDenied {
+        request_id: RequestId,
         application: ApplicationId,
     },
     ConnectionHeartbeat {
@@ -34,4 +103,43 @@ pub enum Message {
         connection_id: ConnectionId,
         data: Zeroizing<Vec<u8>>,
     },
+    /// Every node the sender can reach and how far away it is, only ever sent between neighbors
+    RouteAdvertisement {
+        routes: HashMap<PublicKey, Metric>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Capabilities, COMPRESSION_LZ4, FEATURE_ROUTE_ADVERTISEMENTS};
+
+    #[test]
+    fn negotiates_common_ground() {
+        let ours = Capabilities {
+            version: 3,
+            min_version: 2,
+            compression: COMPRESSION_LZ4,
+            features: FEATURE_ROUTE_ADVERTISEMENTS,
+        };
+        let newer = Capabilities {
+            version: 5,
+            min_version: 3,
+            compression: COMPRESSION_LZ4 | 1 << 7,
+            features: 0,
+        };
+
+        let negotiated = ours.negotiate(&newer).unwrap();
+        assert_eq!(negotiated.version, 3);
+        assert!(negotiated.supports_lz4());
+        assert!(!negotiated.supports_route_advertisements());
+        assert_eq!(negotiated.compression, COMPRESSION_LZ4);
+        assert_eq!(negotiated, newer.negotiate(&ours).unwrap());
+
+        let too_new = Capabilities {
+            min_version: 4,
+            ..newer
+        };
+        assert!(ours.negotiate(&too_new).is_none());
+        assert_eq!(ours.common_version(&too_new), None);
+    }
 }
@@ -0,0 +1,486 @@
+//! Runs whole networks of nodes inside one runtime, wired together over [MemoryTransport]
+
+use crate::{
+    channel::writer::RequestWriteMessage,
+    create_server_state,
+    noise::generate_keys,
+    proto::Message,
+    spawn_node_tasks, start_transport,
+    state::ServerState,
+    transport::{
+        connection_manager::PeerOrigin,
+        driver::memory::{LinkConditions, MemoryNetwork, MemoryTransport},
+        driver::Transport,
+    },
+};
+use routeweaver_common::{Address, ConnectionId, Peer, PublicKey};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{sleep, timeout},
+};
+use zeroize::Zeroizing;
+
+const CHANNEL_REKEY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CHANNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 10);
+/// How often conditions are checked on while waiting for them
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Simulation {
+    pub network: Arc<MemoryNetwork>,
+    pub nodes: Vec<Arc<ServerState>>,
+}
+
+impl Simulation {
+    /// Starts up nodes that know nothing of each other yet
+    pub async fn new(count: usize) -> Self {
+        let network = Arc::new(MemoryNetwork::default());
+        let mut nodes = Vec::with_capacity(count);
+
+        for index in 0..count {
+            let (server_state, request_receivers) = create_server_state(false, generate_keys());
+            let server_state = Arc::new(server_state);
+
+            spawn_node_tasks(
+                server_state.clone(),
+                request_receivers,
+                CHANNEL_REKEY_INTERVAL,
+                CHANNEL_IDLE_TIMEOUT,
+            );
+            start_transport(
+                server_state.clone(),
+                Arc::new(network.transport(Self::address(index)).await),
+            )
+            .await;
+
+            nodes.push(server_state);
+        }
+
+        Self { network, nodes }
+    }
+
+    pub fn address(index: usize) -> Address {
+        Address::Ip {
+            address: IpAddr::V4(Ipv4Addr::from(0x0a000001 + index as u32)),
+            port: 3434,
+        }
+    }
+
+    pub fn peer(index: usize) -> Peer {
+        Peer {
+            address: Self::address(index),
+            protocol: MemoryTransport::PROTOCOL,
+        }
+    }
+
+    pub fn key(&self, index: usize) -> PublicKey {
+        self.nodes[index].keys.public
+    }
+
+    /// Has one node keep a connection up with another, like it was in its config
+    pub async fn connect(&self, from: usize, to: usize) {
+        self.nodes[from]
+            .connection_manager
+            .want(Self::peer(to), PeerOrigin::Configured)
+            .await;
+    }
+
+    pub async fn set_link(&self, a: usize, b: usize, conditions: LinkConditions) {
+        self.network
+            .set_link(Self::address(a), Self::address(b), conditions)
+            .await;
+    }
+
+    pub async fn partition(&self, a: usize, b: usize) {
+        let conditions = self.network.link(Self::address(a), Self::address(b)).await;
+
+        self.set_link(
+            a,
+            b,
+            LinkConditions {
+                partitioned: true,
+                ..conditions
+            },
+        )
+        .await;
+    }
+
+    pub async fn heal(&self, a: usize, b: usize) {
+        let conditions = self.network.link(Self::address(a), Self::address(b)).await;
+
+        self.set_link(
+            a,
+            b,
+            LinkConditions {
+                partitioned: false,
+                ..conditions
+            },
+        )
+        .await;
+    }
+
+    pub async fn has_channel(&self, from: usize, to: usize) -> bool {
+        self.nodes[from]
+            .transport_tracker
+            .contains_async(&self.key(to))
+            .await
+    }
+
+    pub async fn next_hop(&self, from: usize, to: usize) -> Option<Peer> {
+        self.nodes[from].routing_table.next_hop(&self.key(to)).await
+    }
+
+    /// Has a node take data sent over a connection from another, as if an application was on the other end
+    pub async fn listen(
+        &self,
+        on: usize,
+        from: usize,
+        connection_id: ConnectionId,
+    ) -> mpsc::Receiver<Zeroizing<Vec<u8>>> {
+        let (data_tx, data_rx) = mpsc::channel(100);
+
+        self.nodes[on]
+            .request_receive_connection_data
+            .upsert_async((self.key(from), connection_id), data_tx)
+            .await;
+
+        data_rx
+    }
+
+    /// Sends a message, returning once the destination confirmed all of it arrived
+    pub async fn send(&self, from: usize, to: usize, message: Message) -> Option<Duration> {
+        let (notify_sent_tx, notify_sent_rx) = oneshot::channel();
+
+        self.nodes[from]
+            .request_write_message
+            .send(RequestWriteMessage {
+                notify_sent: Some(notify_sent_tx),
+                destination: self.key(to),
+                message,
+            })
+            .await
+            .ok()?;
+
+        notify_sent_rx
+            .await
+            .ok()
+            .map(|response| response.time_taken)
+    }
+}
+
+/// Checks on a condition until it holds, giving up after a while
+pub async fn wait_for<F: Future<Output = bool>>(
+    within: Duration,
+    mut condition: impl FnMut() -> F,
+) -> bool {
+    timeout(within, async {
+        while !condition().await {
+            sleep(POLL_INTERVAL).await;
+        }
+    })
+    .await
+    .is_ok()
+}
+
+mod tests {
+    use super::{wait_for, Simulation};
+    use crate::{
+        noise::{create_handshake_initiator, generate_keys},
+        proto::{Capabilities, Message},
+        transport::{
+            driver::{memory::LinkConditions, Transport},
+            handshake::HandshakeMessage,
+            packet::{Packet, PacketData},
+        },
+    };
+    use futures_util::SinkExt;
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+    use zeroize::Zeroizing;
+
+    const WITHIN: Duration = Duration::from_secs(120);
+
+    fn data(connection_id: u32, data: &[u8]) -> Message {
+        Message::ConnectionData {
+            connection_id,
+            data: Zeroizing::new(data.to_vec()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn neighbors_handshake_and_deliver() {
+        let simulation = Simulation::new(2).await;
+        simulation.connect(0, 1).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 1).await.is_some()
+            })
+            .await,
+            "Neighbors never learned of each other"
+        );
+
+        let mut received = simulation.listen(1, 0, 7).await;
+        let sent = timeout(WITHIN, simulation.send(0, 1, data(7, b"hello")))
+            .await
+            .unwrap();
+        assert!(sent.is_some());
+        assert!(simulation.has_channel(0, 1).await);
+        assert!(simulation.has_channel(1, 0).await);
+        assert_eq!(*received.recv().await.unwrap(), b"hello");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routes_across_line() {
+        let simulation = Simulation::new(4).await;
+        for index in 0..3 {
+            simulation.connect(index, index + 1).await;
+        }
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 3).await == Some(Simulation::peer(1))
+            })
+            .await,
+            "No route showed up across the line"
+        );
+
+        let mut received = simulation.listen(3, 0, 1).await;
+        let sent = timeout(WITHIN, simulation.send(0, 3, data(1, b"far away")))
+            .await
+            .unwrap();
+        assert!(sent.is_some());
+        assert_eq!(*received.recv().await.unwrap(), b"far away");
+
+        // Only ever went through the middle
+        assert!(simulation.has_channel(3, 0).await);
+        assert!(
+            !simulation.nodes[0]
+                .routing_table
+                .is_neighbor(&simulation.key(3))
+                .await
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delivers_over_lossy_slow_links() {
+        let simulation = Simulation::new(3).await;
+        simulation.connect(0, 1).await;
+        simulation.connect(1, 2).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 2).await.is_some()
+            })
+            .await
+        );
+
+        for (a, b) in [(0, 1), (1, 2)] {
+            simulation
+                .set_link(
+                    a,
+                    b,
+                    LinkConditions {
+                        loss: 0.05,
+                        latency: Duration::from_millis(50),
+                        partitioned: false,
+                    },
+                )
+                .await;
+        }
+
+        let mut received = simulation.listen(2, 0, 3).await;
+        for index in 0..5u8 {
+            let sent = timeout(WITHIN, simulation.send(0, 2, data(3, &[index])))
+                .await
+                .unwrap();
+            assert!(sent.is_some(), "Message {} never got confirmed", index);
+        }
+
+        let mut arrived = Vec::new();
+        while let Ok(data) = received.try_recv() {
+            arrived.extend_from_slice(&data);
+        }
+        // Whatever arrived without being confirmed yet is sent again, so repeats are fine but nothing may go missing
+        // or come out of order
+        arrived.dedup();
+        assert_eq!(arrived, [0, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reroutes_around_partition() {
+        let simulation = Simulation::new(3).await;
+        simulation.connect(0, 1).await;
+        simulation.connect(1, 2).await;
+        simulation.connect(0, 2).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 2).await == Some(Simulation::peer(2))
+            })
+            .await
+        );
+
+        simulation.partition(0, 2).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 2).await == Some(Simulation::peer(1))
+            })
+            .await,
+            "Never found the way around the partition"
+        );
+
+        let mut received = simulation.listen(2, 0, 9).await;
+        let sent = timeout(WITHIN, simulation.send(0, 2, data(9, b"around")))
+            .await
+            .unwrap();
+        assert!(sent.is_some());
+        assert_eq!(*received.recv().await.unwrap(), b"around");
+
+        simulation.heal(0, 2).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 2).await == Some(Simulation::peer(2))
+            })
+            .await,
+            "Direct link never came back"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn impostor_hint_keeps_existing_link() {
+        let simulation = Simulation::new(2).await;
+
+        // Connecting from the higher key makes the real link the one a duplicate would win over
+        let (target, neighbor) = if simulation.key(0).as_ref() > simulation.key(1).as_ref() {
+            (0, 1)
+        } else {
+            (1, 0)
+        };
+        simulation.connect(target, neighbor).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(target, neighbor).await.is_some()
+            })
+            .await
+        );
+
+        let mut disconnected = simulation.nodes[target]
+            .notification_peer_disconnected
+            .subscribe();
+
+        // Claims to be the neighbor over a connection of its own, without having its key
+        let impostor = simulation.network.transport(Simulation::address(2)).await;
+        let (_reader, Some(mut writer)) = impostor
+            .connect(&Simulation::address(target))
+            .await
+            .unwrap()
+        else {
+            unreachable!()
+        };
+
+        let mut buffer = [0; 128];
+        let amount = create_handshake_initiator(&generate_keys().private, None)
+            .write_message(&[], &mut buffer)
+            .unwrap();
+
+        for data in [
+            PacketData::Hello(Capabilities::ours()),
+            PacketData::Handshake(HandshakeMessage::try_from(&buffer[..amount]).unwrap()),
+        ] {
+            writer
+                .send(Packet::new(simulation.key(neighbor), None, data))
+                .await
+                .unwrap();
+        }
+
+        sleep(Duration::from_secs(30)).await;
+
+        while let Ok(peer) = disconnected.try_recv() {
+            assert_ne!(
+                peer,
+                Simulation::peer(neighbor),
+                "Impostor got the real link dropped"
+            );
+        }
+        assert_eq!(
+            simulation.next_hop(target, neighbor).await,
+            Some(Simulation::peer(neighbor))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forged_segments_keep_channel() {
+        let simulation = Simulation::new(2).await;
+        simulation.connect(0, 1).await;
+
+        assert!(
+            wait_for(WITHIN, || async {
+                simulation.next_hop(0, 1).await.is_some()
+            })
+            .await
+        );
+
+        let mut received = simulation.listen(1, 0, 4).await;
+        let sent = timeout(WITHIN, simulation.send(0, 1, data(4, b"before")))
+            .await
+            .unwrap();
+        assert!(sent.is_some());
+        assert_eq!(*received.recv().await.unwrap(), b"before");
+
+        // Nothing the real node sends gets through, so a new channel could never be set up
+        simulation.partition(0, 1).await;
+
+        let forger = simulation.network.transport(Simulation::address(2)).await;
+        let (_reader, Some(mut writer)) = forger.connect(&Simulation::address(0)).await.unwrap()
+        else {
+            unreachable!()
+        };
+
+        writer
+            .send(Packet::new(
+                generate_keys().public,
+                None,
+                PacketData::Hello(Capabilities::ours()),
+            ))
+            .await
+            .unwrap();
+
+        for nonce in 0..32 {
+            writer
+                .send(Packet::new(
+                    simulation.key(1),
+                    Some(simulation.key(0)),
+                    PacketData::MessageSegment {
+                        epoch: 0,
+                        nonce,
+                        data: vec![0; 64],
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+
+        sleep(Duration::from_secs(5)).await;
+        assert!(
+            simulation.has_channel(0, 1).await,
+            "Forged segments tore down the channel"
+        );
+
+        simulation.heal(0, 1).await;
+
+        let sent = timeout(WITHIN, simulation.send(0, 1, data(4, b"after")))
+            .await
+            .unwrap();
+        assert!(sent.is_some());
+        assert_eq!(*received.recv().await.unwrap(), b"after");
+    }
+}
@@ -1,16 +1,24 @@
 use crate::{
+    acl::{AccessControl, MeshTrust},
     channel::{
         assembler::MessageAssembler,
+        connection::ConnectionTracker,
         disassembler::MessageDisassembler,
+        lifetime::Channel,
         reader::RequestDecodeMessageSegment,
         writer::{RequestUpdateMessageStatus, RequestWriteMessage},
     },
     config::Keys,
     discover::LocalAddressTracker,
-    transport::{packet::Packet, router::RequestRoutePacket, setup_connection::PeerTracker},
+    ipc::socket::ApplicationTracker,
+    transport::{
+        connection_manager::ConnectionManager, handshake::Handshake,
+        misbehaviour::MisbehaviourTracker, packet::Packet, reader::SeenPacketTracker,
+        router::RequestRoutePacket, routing_table::RoutingTable, score_keeper::ScoreKeeper,
+        setup_connection::PeerTracker,
+    },
 };
 use routeweaver_common::{Address, ConnectionId, Peer, Protocol, PublicKey};
-use snow::{HandshakeState, TransportState};
 use tokio::sync::{
     broadcast,
     mpsc::{self, Sender},
@@ -23,14 +31,32 @@ pub struct ServerState {
     pub anonymous: bool,
     /// Server keys
     pub keys: Keys,
+    /// Restrictions on which nodes may make channels with us at all
+    pub mesh_trust: MeshTrust,
     /// Tracks the servers current local addresses
     pub local_address_tracker: LocalAddressTracker,
     /// Tracks the states of active handshakes
-    pub handshake_tracker: scc::HashMap<PublicKey, HandshakeState>,
+    pub handshake_tracker: scc::HashMap<PublicKey, Handshake>,
     /// Tracks the states of active channels
-    pub transport_tracker: scc::HashMap<PublicKey, TransportState>,
+    pub transport_tracker: scc::HashMap<PublicKey, Channel>,
     /// Tracks currently connected peers
     pub peer_tracker: PeerTracker,
+    /// Peers we want to stay connected to
+    pub connection_manager: ConnectionManager,
+    /// Tracks which neighbor gets us closest to every node
+    pub routing_table: RoutingTable,
+    /// Tracks how well links to peers and nodes are performing
+    pub score_keeper: ScoreKeeper,
+    /// Tracks recently seen packets to drop duplicates
+    pub seen_packet_tracker: SeenPacketTracker,
+    /// Tracks penalties and bans for peers and nodes that misbehave
+    pub misbehaviour_tracker: MisbehaviourTracker,
+    /// Operator rules on which peers and nodes we talk to
+    pub access_control: AccessControl,
+    /// Tracks applications listening for connections
+    pub application_tracker: ApplicationTracker,
+    /// Tracks the liveness of connections and our outstanding connection requests
+    pub connection_tracker: ConnectionTracker,
     // Requests that a connection be made to a given peer
     pub request_initiate_connection: scc::HashMap<Protocol, mpsc::Sender<Address>>,
     pub request_initiate_channel: mpsc::Sender<PublicKey>,
@@ -44,11 +70,18 @@ pub struct ServerState {
     pub request_decode_message_segment: mpsc::Sender<RequestDecodeMessageSegment>,
     pub request_update_message_status: mpsc::Sender<RequestUpdateMessageStatus>,
     /// Requests that the ipc server for applications processes some data
-    pub request_receive_connection_data: scc::HashMap<ConnectionId, Sender<Zeroizing<Vec<u8>>>>,
+    pub request_receive_connection_data:
+        scc::HashMap<(PublicKey, ConnectionId), Sender<Zeroizing<Vec<u8>>>>,
     /// Notifies listeners that a new peer has connected, useful for reconsidering routing tables
     pub notification_new_peer_connection: broadcast::Sender<Peer>,
+    /// Notifies a connection with a peer is gone
+    pub notification_peer_disconnected: broadcast::Sender<Peer>,
     /// Notifies a node has been successfully handshaked
     pub notification_handshaked_node: broadcast::Sender<PublicKey>,
+    /// Notifies a channel is gone, so anything kept per node for it can be dropped
+    pub notification_channel_closed: broadcast::Sender<PublicKey>,
+    /// Notifies a channel was replaced because the remote lost its end, so what was in flight has to start over
+    pub notification_channel_reset: broadcast::Sender<PublicKey>,
 }
 
 impl ServerState {
@@ -64,10 +97,19 @@ impl ServerState {
         Self {
             anonymous,
             keys,
+            mesh_trust: MeshTrust::default(),
             local_address_tracker: LocalAddressTracker::default(),
             handshake_tracker: scc::HashMap::default(),
             transport_tracker: scc::HashMap::default(),
             peer_tracker: PeerTracker::default(),
+            connection_manager: ConnectionManager::default(),
+            routing_table: RoutingTable::default(),
+            score_keeper: ScoreKeeper::default(),
+            seen_packet_tracker: SeenPacketTracker::default(),
+            misbehaviour_tracker: MisbehaviourTracker::default(),
+            access_control: AccessControl::default(),
+            application_tracker: ApplicationTracker::default(),
+            connection_tracker: ConnectionTracker::default(),
             request_initiate_connection: scc::HashMap::default(),
             request_write_packet: scc::HashMap::default(),
             request_route_packet,
@@ -77,7 +119,10 @@ impl ServerState {
             request_update_message_status,
             request_receive_connection_data: scc::HashMap::default(),
             notification_new_peer_connection: broadcast::channel(100).0,
+            notification_peer_disconnected: broadcast::channel(100).0,
             notification_handshaked_node: broadcast::channel(100).0,
+            notification_channel_closed: broadcast::channel(100).0,
+            notification_channel_reset: broadcast::channel(100).0,
         }
     }
 }
@@ -13,6 +13,20 @@ pub async fn accepter<T: Transport>(server_state: Arc<ServerState>, transport: A
                     protocol: T::PROTOCOL,
                 };
 
+                if !server_state.access_control.is_peer_allowed(&peer).await {
+                    tracing::debug!("Refusing connection from {} as it is denied", peer);
+                    continue;
+                }
+
+                if !server_state
+                    .connection_manager
+                    .limits
+                    .has_room(&server_state.peer_tracker.peers().await, peer.protocol)
+                {
+                    tracing::debug!("Refusing connection from {}, too many peers", peer);
+                    continue;
+                }
+
                 tracing::debug!("Accepted connection from {}", peer);
 
                 finalize_peer_connection(server_state.clone(), reader, writer, peer, false).await;
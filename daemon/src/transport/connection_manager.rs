@@ -0,0 +1,321 @@
+use crate::state::ServerState;
+use routeweaver_common::{ipc::control::DesiredPeer, Peer, Protocol};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    sync::broadcast::error::RecvError,
+    time::{interval, Instant},
+};
+
+/// Wait before the first reconnect, doubled on every failure after
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(2);
+/// Longest wait between reconnects
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(300);
+/// How often peers are checked for being due another attempt
+const RECONNECT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Failures in a row after which a discovered peer is assumed to be gone for good
+const MAX_DISCOVERED_FAILURES: u32 = 8;
+
+/// Where we heard of a peer from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerOrigin {
+    /// From the config or the control socket, these are never given up on
+    Configured,
+    Discovered,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Desired {
+    origin: PeerOrigin,
+    /// Attempts since the last time we were connected
+    failures: u32,
+    /// [Option::None] while connected
+    next_attempt: Option<Instant>,
+}
+
+/// Caps on how many peers we stay connected to
+#[derive(Debug, Default)]
+pub struct ConnectionLimits {
+    pub max_peers: Option<usize>,
+    pub max_peers_per_protocol: HashMap<Protocol, usize>,
+}
+
+impl ConnectionLimits {
+    /// Whether another connection over a protocol fits next to the ones already there
+    pub fn has_room(&self, connected: &[Peer], protocol: Protocol) -> bool {
+        if self.max_peers.is_some_and(|max| connected.len() >= max) {
+            return false;
+        }
+
+        self.max_peers_per_protocol
+            .get(&protocol)
+            .is_none_or(|max| {
+                connected
+                    .iter()
+                    .filter(|peer| peer.protocol == protocol)
+                    .count()
+                    < *max
+            })
+    }
+}
+
+/// Keeps the peers we want connected, reconnecting with backoff when they drop
+#[derive(Debug, Default)]
+pub struct ConnectionManager {
+    desired: scc::HashMap<Peer, Desired>,
+    pub limits: ConnectionLimits,
+}
+
+impl ConnectionManager {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Self {
+            desired: scc::HashMap::default(),
+            limits,
+        }
+    }
+
+    /// Starts keeping a peer connected, trying right away
+    pub async fn want(&self, peer: Peer, origin: PeerOrigin) {
+        match self.desired.entry_async(peer).await {
+            scc::hash_map::Entry::Occupied(mut entry) => {
+                if origin == PeerOrigin::Configured {
+                    entry.get_mut().origin = origin;
+                }
+            }
+            scc::hash_map::Entry::Vacant(entry) => {
+                tracing::debug!("Keeping {} connected", peer);
+
+                entry.insert_entry(Desired {
+                    origin,
+                    failures: 0,
+                    next_attempt: Some(Instant::now()),
+                });
+            }
+        }
+    }
+
+    /// Stops reconnecting to a peer, returning false if it was not being kept connected
+    pub async fn forget(&self, peer: &Peer) -> bool {
+        self.desired.remove_async(peer).await.is_some()
+    }
+
+    async fn connected(&self, peer: &Peer) {
+        self.desired
+            .update_async(peer, |_, desired| {
+                desired.failures = 0;
+                desired.next_attempt = None;
+            })
+            .await;
+    }
+
+    async fn disconnected(&self, peer: &Peer) {
+        self.desired
+            .update_async(peer, |_, desired| {
+                desired.next_attempt = Some(Instant::now() + backoff(desired.failures));
+            })
+            .await;
+    }
+
+    /// Peers due another attempt, with their next one pushed back
+    ///
+    /// Anything picked is added to the connected peers, so limits account for attempts made in the same round
+    async fn due(&self, connected: &mut Vec<Peer>) -> Vec<Peer> {
+        let now = Instant::now();
+        let mut due = Vec::new();
+
+        self.desired
+            .retain_async(|peer, desired| {
+                if connected.contains(peer) {
+                    return true;
+                }
+
+                if desired
+                    .next_attempt
+                    .is_some_and(|next_attempt| next_attempt > now)
+                    || !self.limits.has_room(connected, peer.protocol)
+                {
+                    return true;
+                }
+
+                if desired.origin == PeerOrigin::Discovered
+                    && desired.failures >= MAX_DISCOVERED_FAILURES
+                {
+                    tracing::debug!("Giving up on discovered peer {}", peer);
+                    return false;
+                }
+
+                desired.next_attempt = Some(now + backoff(desired.failures));
+                desired.failures += 1;
+                connected.push(*peer);
+                due.push(*peer);
+
+                true
+            })
+            .await;
+
+        due
+    }
+
+    pub async fn desired_peers(&self, connected: &[Peer]) -> Vec<DesiredPeer> {
+        let now = Instant::now();
+        let mut desired_peers = Vec::new();
+
+        self.desired
+            .scan_async(|peer, desired| {
+                desired_peers.push(DesiredPeer {
+                    peer: *peer,
+                    configured: desired.origin == PeerOrigin::Configured,
+                    connected: connected.contains(peer),
+                    failures: desired.failures,
+                    next_attempt: desired
+                        .next_attempt
+                        .map(|next_attempt| next_attempt.saturating_duration_since(now)),
+                })
+            })
+            .await;
+
+        desired_peers
+    }
+}
+
+/// Doubles with every failure up to [RECONNECT_MAX_DELAY], jittered so peers that dropped together don't all come
+/// back at once
+fn backoff(failures: u32) -> Duration {
+    let delay = RECONNECT_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(RECONNECT_MAX_DELAY);
+
+    delay.mul_f32(0.5 + rand::random::<f32>())
+}
+
+/// Sends the peers we want connected that aren't off to the transports, following connects and disconnects
+pub async fn reconnector(server_state: Arc<ServerState>) {
+    let mut peer_connected = server_state.notification_new_peer_connection.subscribe();
+    let mut peer_disconnected = server_state.notification_peer_disconnected.subscribe();
+    let mut check = interval(RECONNECT_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            peer = peer_connected.recv() => match peer {
+                Ok(peer) => server_state.connection_manager.connected(&peer).await,
+                // Attempts are only ever made for peers not connected, so nothing is lost here
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            peer = peer_disconnected.recv() => match peer {
+                Ok(peer) => server_state.connection_manager.disconnected(&peer).await,
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            _ = check.tick() => {
+                let mut connected = server_state.peer_tracker.peers().await;
+
+                for peer in server_state.connection_manager.due(&mut connected).await {
+                    let Some(initiator) = server_state
+                        .request_initiate_connection
+                        .read_async(&peer.protocol, |_, initiator| initiator.clone())
+                        .await
+                    else {
+                        tracing::warn!("Protocol {} not supported", peer.protocol);
+                        continue;
+                    };
+
+                    tracing::debug!("Trying to connect to {}", peer);
+
+                    if initiator.send(peer.address).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        backoff, ConnectionLimits, ConnectionManager, PeerOrigin, MAX_DISCOVERED_FAILURES,
+        RECONNECT_BASE_DELAY, RECONNECT_MAX_DELAY,
+    };
+    use routeweaver_common::{Address, Peer, Protocol};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    fn peer(protocol: Protocol, id: u8) -> Peer {
+        Peer {
+            protocol,
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, id)),
+                port: 3434,
+            },
+        }
+    }
+
+    #[test]
+    fn backoff_grows_and_caps() {
+        assert!(backoff(0) <= RECONNECT_BASE_DELAY.mul_f32(1.5));
+        assert!(backoff(3) >= RECONNECT_BASE_DELAY * 4);
+        assert!(backoff(u32::MAX) <= RECONNECT_MAX_DELAY.mul_f32(1.5));
+        assert!(backoff(u32::MAX) >= RECONNECT_MAX_DELAY / 2);
+    }
+
+    #[tokio::test]
+    async fn respects_limits() {
+        let manager = ConnectionManager::new(ConnectionLimits {
+            max_peers: Some(3),
+            max_peers_per_protocol: HashMap::from([(Protocol::Tcp, 1)]),
+        });
+
+        for id in 1..=3 {
+            manager
+                .want(peer(Protocol::Tcp, id), PeerOrigin::Configured)
+                .await;
+            manager
+                .want(peer(Protocol::Ws, id), PeerOrigin::Configured)
+                .await;
+        }
+
+        let mut connected = vec![peer(Protocol::Ws, 1)];
+        let due = manager.due(&mut connected).await;
+
+        assert_eq!(due.len(), 2);
+        assert_eq!(connected.len(), 3);
+        assert_eq!(
+            due.iter()
+                .filter(|peer| peer.protocol == Protocol::Tcp)
+                .count(),
+            1
+        );
+        assert!(!due.contains(&peer(Protocol::Ws, 1)));
+
+        // Nothing is due again until the backoff runs out
+        let mut connected = Vec::new();
+        let due_again = manager.due(&mut connected).await;
+        assert!(due_again.iter().all(|peer| !due.contains(peer)));
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_discovered_peers() {
+        let manager = ConnectionManager::default();
+        let discovered = peer(Protocol::Tcp, 1);
+        let configured = peer(Protocol::Tcp, 2);
+
+        manager.want(discovered, PeerOrigin::Discovered).await;
+        manager.want(configured, PeerOrigin::Configured).await;
+
+        for failures in [discovered, configured] {
+            manager
+                .desired
+                .update_async(&failures, |_, desired| {
+                    desired.failures = MAX_DISCOVERED_FAILURES
+                })
+                .await;
+        }
+
+        let due = manager.due(&mut Vec::new()).await;
+        assert_eq!(due, vec![configured]);
+        assert!(!manager.forget(&discovered).await);
+        assert!(manager.forget(&configured).await);
+    }
+}
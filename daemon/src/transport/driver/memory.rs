@@ -0,0 +1,254 @@
+use super::{Transport, TransportReader, TransportWriter};
+use crate::{error::RouteWeaverError, transport::packet::Packet};
+use futures_util::{Sink, Stream};
+use routeweaver_common::{Address, Protocol};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    sync::{mpsc, Mutex as AsyncMutex},
+    time::{sleep_until, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+/// What happens to packets going between two nodes, the same both ways
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LinkConditions {
+    /// Chance of any one packet getting dropped, from 0 to 1
+    pub loss: f64,
+    /// How long packets take to show up on the other end
+    pub latency: Duration,
+    /// Nothing gets through, and any connections between the two are cut
+    pub partitioned: bool,
+}
+
+type MemoryConnection = (MemoryReader, MemoryWriter);
+
+/// Everything connecting in-memory transports to each other, one per simulated network
+#[derive(Default)]
+pub struct MemoryNetwork {
+    listeners: scc::HashMap<Address, mpsc::Sender<(MemoryConnection, Address)>>,
+    /// Kept under both directions
+    links: scc::HashMap<(Address, Address), LinkConditions>,
+    /// Open connections and who they are between, for cutting them on a partition
+    connections: Mutex<Vec<((Address, Address), CancellationToken)>>,
+}
+
+impl MemoryNetwork {
+    /// Puts a new transport on the network, listening on the address
+    pub async fn transport(self: &Arc<Self>, address: Address) -> MemoryTransport {
+        let (incoming_tx, incoming_rx) = mpsc::channel(10);
+
+        self.listeners.upsert_async(address, incoming_tx).await;
+
+        MemoryTransport {
+            network: self.clone(),
+            address,
+            incoming: AsyncMutex::new(incoming_rx),
+        }
+    }
+
+    pub async fn set_link(&self, a: Address, b: Address, conditions: LinkConditions) {
+        self.links.upsert_async((a, b), conditions).await;
+        self.links.upsert_async((b, a), conditions).await;
+
+        if conditions.partitioned {
+            self.connections
+                .lock()
+                .unwrap()
+                .retain(|(link, cancellation_token)| {
+                    if *link == (a, b) || *link == (b, a) {
+                        cancellation_token.cancel();
+                        return false;
+                    }
+
+                    !cancellation_token.is_cancelled()
+                });
+        }
+    }
+
+    pub async fn link(&self, a: Address, b: Address) -> LinkConditions {
+        self.links
+            .read_async(&(a, b), |_, conditions| *conditions)
+            .await
+            .unwrap_or_default()
+    }
+
+    /// One direction of a connection, packets go through the link conditions on their way over
+    fn pipe(
+        self: &Arc<Self>,
+        from: Address,
+        to: Address,
+        cancellation_token: CancellationToken,
+    ) -> (MemoryWriter, MemoryReader) {
+        let (sent_tx, mut sent_rx) = mpsc::unbounded_channel::<Packet>();
+        let (delayed_tx, mut delayed_rx) = mpsc::unbounded_channel();
+        let (arrived_tx, arrived_rx) = mpsc::unbounded_channel();
+
+        let network = self.clone();
+        let relay_cancellation_token = cancellation_token.clone();
+        tokio::spawn(async move {
+            loop {
+                let packet = tokio::select! {
+                    packet = sent_rx.recv() => packet,
+                    _ = relay_cancellation_token.cancelled() => None,
+                };
+                let Some(packet) = packet else {
+                    break;
+                };
+
+                let conditions = network.link(from, to).await;
+                if conditions.partitioned || rand::random::<f64>() < conditions.loss {
+                    continue;
+                }
+
+                if delayed_tx
+                    .send((Instant::now() + conditions.latency, packet))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        // Delivered in order like a stream would, even if the latency changed in between
+        tokio::spawn(async move {
+            loop {
+                let delayed = tokio::select! {
+                    delayed = delayed_rx.recv() => delayed,
+                    _ = cancellation_token.cancelled() => None,
+                };
+                let Some((deliver_at, packet)) = delayed else {
+                    break;
+                };
+
+                tokio::select! {
+                    _ = sleep_until(deliver_at) => {}
+                    _ = cancellation_token.cancelled() => break,
+                }
+
+                if arrived_tx.send(packet).is_err() {
+                    break;
+                }
+            }
+        });
+
+        (MemoryWriter(sent_tx), MemoryReader(arrived_rx))
+    }
+}
+
+pub struct MemoryReader(mpsc::UnboundedReceiver<Packet>);
+
+impl Stream for MemoryReader {
+    type Item = Result<Packet, RouteWeaverError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|packet| packet.map(Ok))
+    }
+}
+
+pub struct MemoryWriter(mpsc::UnboundedSender<Packet>);
+
+impl Sink<Packet> for MemoryWriter {
+    type Error = RouteWeaverError;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        self.0
+            .send(item)
+            .map_err(|_| RouteWeaverError::ConnectionFailed)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Transport that only reaches other nodes in the same process, for simulating networks in tests
+pub struct MemoryTransport {
+    network: Arc<MemoryNetwork>,
+    address: Address,
+    incoming: AsyncMutex<mpsc::Receiver<(MemoryConnection, Address)>>,
+}
+
+impl Transport for MemoryTransport {
+    // Stands in for tcp, so peers look like any other to the rest of the daemon
+    const PROTOCOL: Protocol = Protocol::Tcp;
+
+    async fn from_config(_config: toml::Value) -> Result<Self, RouteWeaverError> {
+        // Only ever made through [MemoryNetwork::transport]
+        Err(RouteWeaverError::InvalidProtocol)
+    }
+
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<(Option<impl TransportReader>, Option<impl TransportWriter>), RouteWeaverError>
+    {
+        if self.network.link(self.address, *address).await.partitioned {
+            return Err(RouteWeaverError::ConnectionFailed);
+        }
+
+        let listener = self
+            .network
+            .listeners
+            .read_async(address, |_, listener| listener.clone())
+            .await
+            .ok_or(RouteWeaverError::ConnectionFailed)?;
+
+        let cancellation_token = CancellationToken::new();
+        let (our_writer, their_reader) =
+            self.network
+                .pipe(self.address, *address, cancellation_token.clone());
+        let (their_writer, our_reader) =
+            self.network
+                .pipe(*address, self.address, cancellation_token.clone());
+
+        self.network
+            .connections
+            .lock()
+            .unwrap()
+            .push(((self.address, *address), cancellation_token));
+
+        listener
+            .send(((their_reader, their_writer), self.address))
+            .await
+            .map_err(|_| RouteWeaverError::ConnectionFailed)?;
+
+        Ok((Some(our_reader), Some(our_writer)))
+    }
+
+    async fn accept(
+        &self,
+    ) -> Result<
+        (
+            (Option<impl TransportReader>, Option<impl TransportWriter>),
+            Address,
+        ),
+        RouteWeaverError,
+    > {
+        let ((reader, writer), address) = self
+            .incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(RouteWeaverError::ConnectionFailed)?;
+
+        Ok(((Some(reader), Some(writer)), address))
+    }
+
+    async fn local_addresses(&self) -> Result<impl Iterator<Item = Address>, RouteWeaverError> {
+        Ok(std::iter::once(self.address))
+    }
+}
@@ -1,14 +1,22 @@
 #[cfg(transport_bluetooth)]
 pub mod bluetooth;
+#[cfg(test)]
+pub mod memory;
+#[cfg(transport_quic)]
+pub mod quic;
 #[cfg(transport_tcp)]
 pub mod tcp;
+#[cfg(transport_udp)]
+pub mod udp;
+#[cfg(transport_unix)]
+pub mod unix;
 #[cfg(transport_ws)]
 pub mod ws;
 #[cfg(transport_wss)]
 pub mod wss;
 
 use crate::error::RouteWeaverError;
-use crate::transport::packet::Packet;
+use crate::transport::packet::{Packet, MAX_PACKET_PAYLOAD_SIZE};
 use bytes::BytesMut;
 use futures_util::{Sink, Stream};
 use routeweaver_common::{Address, Peer, Protocol};
@@ -67,6 +75,23 @@ pub trait Transport: Sized + Send + Sync + 'static {
     ) -> impl Future<Output = Result<impl Iterator<Item = Address> + Send, RouteWeaverError>> + Send;
 }
 
+/// Start of every frame on the stream transports, so a connection from something that isn't a daemon fails fast
+const FRAME_MAGIC: [u8; 2] = *b"RW";
+/// Version of the framing itself, only bumped if the header changes
+///
+/// What goes inside is covered by [crate::proto::PROTOCOL_VERSION] and worked out over the hello, so the framing has
+/// to stay readable for a peer speaking a newer protocol to get to say which versions it speaks
+pub const FRAME_VERSION: u8 = 1;
+/// Oldest version we still understand
+pub const MIN_FRAME_VERSION: u8 = 1;
+/// Magic, version and a big endian [u32] length
+const FRAME_HEADER_SIZE: usize = FRAME_MAGIC.len() + 1 + 4;
+/// Largest packet a frame may carry, the payload plus room for the segment, noise and packet headers
+pub const MAX_FRAME_SIZE: usize = MAX_PACKET_PAYLOAD_SIZE + 2048;
+
+/// Length delimited framing for [Packet]s over stream transports
+///
+/// Each frame is a [FRAME_HEADER_SIZE] byte header followed by the bincode encoded packet
 #[derive(Default, Debug)]
 pub struct PacketEncoderDecoder;
 
@@ -75,22 +100,50 @@ impl Decoder for PacketEncoderDecoder {
     type Error = RouteWeaverError;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.is_empty() {
+        if src.len() < FRAME_HEADER_SIZE {
+            src.reserve(FRAME_HEADER_SIZE - src.len());
             return Ok(None);
         }
 
-        // On purpose our packets don't have any magic bytes
+        if src[..FRAME_MAGIC.len()] != FRAME_MAGIC {
+            return Err(RouteWeaverError::InvalidFrame);
+        }
 
-        match bincode::serde::decode_from_std_read(&mut src.reader(), bincode::config::standard()) {
-            Ok(packet) => Ok(Some(packet)),
-            Err(bincode_error) => {
-                match &bincode_error {
-                    // Return Ok(None) if its a unexpected EOF
-                    bincode::error::DecodeError::UnexpectedEnd { .. } => Ok(None),
-                    _ => Err(bincode_error.into()),
-                }
-            }
+        let version = src[FRAME_MAGIC.len()];
+        if !(MIN_FRAME_VERSION..=FRAME_VERSION).contains(&version) {
+            return Err(RouteWeaverError::UnsupportedVersion { version });
+        }
+
+        let length = u32::from_be_bytes(
+            src[FRAME_MAGIC.len() + 1..FRAME_HEADER_SIZE]
+                .try_into()
+                .unwrap(),
+        ) as usize;
+
+        if length > MAX_FRAME_SIZE {
+            return Err(RouteWeaverError::FrameTooLarge { size: length });
+        }
+
+        // Wait for the rest without looking at what's there so far
+        if src.len() < FRAME_HEADER_SIZE + length {
+            src.reserve(FRAME_HEADER_SIZE + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(FRAME_HEADER_SIZE);
+        let frame = src.split_to(length);
+
+        let (packet, amount) = bincode::serde::decode_from_slice(
+            &frame,
+            bincode::config::standard().with_limit::<MAX_FRAME_SIZE>(),
+        )?;
+
+        // Trailing garbage means the length and the packet disagree
+        if amount != length {
+            return Err(RouteWeaverError::InvalidFrame);
         }
+
+        Ok(Some(packet))
     }
 }
 
@@ -98,12 +151,113 @@ impl Encoder<Packet> for PacketEncoderDecoder {
     type Error = RouteWeaverError;
 
     fn encode(&mut self, item: Packet, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let start = dst.len();
+
+        dst.put_slice(&FRAME_MAGIC);
+        dst.put_u8(FRAME_VERSION);
+        // Filled in once the packet is written
+        dst.put_u32(0);
+
         bincode::serde::encode_into_std_write(
             &item,
             &mut dst.writer(),
             bincode::config::standard(),
-        )?;
+        )
+        .inspect_err(|_| dst.truncate(start))?;
+
+        let length = dst.len() - start - FRAME_HEADER_SIZE;
+        if length > MAX_FRAME_SIZE {
+            dst.truncate(start);
+            return Err(RouteWeaverError::FrameTooLarge { size: length });
+        }
+
+        dst[start + FRAME_MAGIC.len() + 1..start + FRAME_HEADER_SIZE]
+            .copy_from_slice(&(length as u32).to_be_bytes());
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PacketEncoderDecoder, FRAME_HEADER_SIZE, MAX_FRAME_SIZE};
+    use crate::{
+        error::RouteWeaverError,
+        transport::packet::{Packet, PacketData, MAX_PACKET_PAYLOAD_SIZE},
+    };
+    use bytes::BytesMut;
+    use routeweaver_common::PublicKey;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn packet(size: usize) -> Packet {
+        Packet::new(
+            PublicKey::new([1; 32]),
+            Some(PublicKey::new([2; 32])),
+            PacketData::MessageSegment {
+                epoch: 0,
+                nonce: 0,
+                data: vec![3; size],
+            },
+        )
+    }
+
+    #[test]
+    fn decodes_split_frames() {
+        let mut codec = PacketEncoderDecoder;
+        let mut encoded = BytesMut::new();
+
+        codec.encode(packet(10), &mut encoded).unwrap();
+        codec
+            .encode(packet(u16::MAX as usize), &mut encoded)
+            .unwrap();
+
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+
+        for chunk in encoded.chunks(1000) {
+            src.extend_from_slice(chunk);
+
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                decoded.push(packet);
+            }
+        }
+
+        assert!(src.is_empty());
+        assert_eq!(decoded.len(), 2);
+        assert!(
+            matches!(&decoded[1].data, PacketData::MessageSegment { data, .. } if data.len() == u16::MAX as usize)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        let mut codec = PacketEncoderDecoder;
+
+        let mut src = BytesMut::from(&b"XX\x01\x00\x00\x00\x01\x00"[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RouteWeaverError::InvalidFrame)
+        ));
+
+        let mut src = BytesMut::from(&b"RW\xff\x00\x00\x00\x01\x00"[..]);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RouteWeaverError::UnsupportedVersion { version: 0xff })
+        ));
+
+        // Refused from the header alone, before any of it arrives
+        let mut src = BytesMut::from(&b"RW\x01"[..]);
+        src.extend_from_slice(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        assert_eq!(src.len(), FRAME_HEADER_SIZE);
+        assert!(matches!(
+            codec.decode(&mut src),
+            Err(RouteWeaverError::FrameTooLarge { .. })
+        ));
+
+        let mut dst = BytesMut::new();
+        assert!(codec
+            .encode(packet(MAX_PACKET_PAYLOAD_SIZE * 2), &mut dst)
+            .is_err());
+        assert!(dst.is_empty());
+    }
+}
@@ -0,0 +1,315 @@
+use super::{PacketEncoderDecoder, Transport, TransportReader, TransportWriter};
+use crate::error::RouteWeaverError;
+use quinn::{
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+    ClientConfig, Connection, Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime,
+    TransportConfig,
+};
+use routeweaver_common::{Address, Protocol};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, SignatureScheme,
+};
+use serde::Deserialize;
+use serde_inline_default::serde_inline_default;
+use socket2::Socket;
+use std::{
+    collections::HashSet,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use sysinfo::Networks;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+/// Sent during the TLS handshake so we don't end up talking to some other QUIC service
+const ALPN: &[u8] = b"routeweaver";
+/// Name put in our certificate and asked for when connecting, nothing checks it
+const SERVER_NAME: &str = "routeweaver";
+/// Keeps NAT mappings open and notices dead connections without waiting on the idle timeout
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[serde_inline_default]
+#[derive(Clone, Deserialize, Debug)]
+pub struct QuicTransportConfig {
+    #[serde_inline_default(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))]
+    pub listen_address: IpAddr,
+    #[serde_inline_default(3438)]
+    pub listen_port: u16,
+}
+
+/// Every connection, incoming and outgoing, goes over the one endpoint so they all share a socket
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    listen_port: u16,
+}
+
+impl Transport for QuicTransport {
+    const PROTOCOL: Protocol = Protocol::Quic;
+
+    async fn from_config(config: toml::Value) -> Result<Self, RouteWeaverError> {
+        let config = QuicTransportConfig::deserialize(config)?;
+
+        let listen_address = match config.listen_address {
+            IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+            IpAddr::V6(ip) => IpAddr::V6(ip),
+        };
+
+        let socket = Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
+
+        socket.bind(&SocketAddr::new(listen_address, config.listen_port).into())?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let transport_config = Arc::new(transport_config()?);
+
+        let mut endpoint = Endpoint::new(
+            EndpointConfig::default(),
+            Some(server_config(provider.clone(), transport_config.clone())?),
+            socket.into(),
+            Arc::new(TokioRuntime),
+        )?;
+        endpoint.set_default_client_config(client_config(provider, transport_config)?);
+
+        Ok(Self {
+            endpoint,
+            listen_port: config.listen_port,
+        })
+    }
+
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<(Option<impl TransportReader>, Option<impl TransportWriter>), RouteWeaverError>
+    {
+        let socket_addr = match SocketAddr::try_from(*address)? {
+            // Our socket is ipv6 only as far as addresses go
+            SocketAddr::V4(socket_addr) => SocketAddr::new(
+                IpAddr::V6(socket_addr.ip().to_ipv6_mapped()),
+                socket_addr.port(),
+            ),
+            socket_addr => socket_addr,
+        };
+
+        let connecting = self
+            .endpoint
+            .connect(socket_addr, SERVER_NAME)
+            .map_err(|err| {
+                tracing::debug!("Failed connecting to {}: {}", socket_addr, err);
+                RouteWeaverError::ConnectionFailed
+            })?;
+
+        let connection = match connecting.into_0rtt() {
+            // We talked to them before, so the first packets can go out before the handshake is done. Replays of
+            // these are harmless as everything that matters is protected by the noise channel on top
+            Ok((connection, _)) => connection,
+            Err(connecting) => connecting.await.map_err(|err| {
+                tracing::debug!("Failed connecting to {}: {}", socket_addr, err);
+                RouteWeaverError::ConnectionFailed
+            })?,
+        };
+
+        let (send, receive) = connection.open_bi().await.map_err(|err| {
+            tracing::debug!("Failed opening stream to {}: {}", socket_addr, err);
+            RouteWeaverError::ConnectionFailed
+        })?;
+
+        Ok((
+            Some(FramedRead::new(receive, PacketEncoderDecoder)),
+            Some(FramedWrite::new(send, PacketEncoderDecoder)),
+        ))
+    }
+
+    async fn accept(
+        &self,
+    ) -> Result<
+        (
+            (Option<impl TransportReader>, Option<impl TransportWriter>),
+            Address,
+        ),
+        RouteWeaverError,
+    > {
+        // Only ever runs out once the endpoint is closed
+        let incoming = self
+            .endpoint
+            .accept()
+            .await
+            .ok_or(RouteWeaverError::ConnectionFailed)?;
+
+        let connection = accept_connection(incoming).await.map_err(|err| {
+            tracing::debug!("Failed accepting connection: {}", err);
+            RouteWeaverError::ConnectionFailed
+        })?;
+
+        let address = connection.remote_address().into();
+        let (send, receive) = connection.accept_bi().await.map_err(|err| {
+            tracing::debug!("Failed accepting stream from {}: {}", address, err);
+            RouteWeaverError::ConnectionFailed
+        })?;
+
+        Ok((
+            (
+                Some(FramedRead::new(receive, PacketEncoderDecoder)),
+                Some(FramedWrite::new(send, PacketEncoderDecoder)),
+            ),
+            address,
+        ))
+    }
+
+    async fn local_addresses(&self) -> Result<impl Iterator<Item = Address>, RouteWeaverError> {
+        let mut ip_addrs = HashSet::new();
+        let networks = Networks::new_with_refreshed_list();
+
+        for (_, network_data) in &networks {
+            ip_addrs.extend(
+                network_data
+                    .ip_networks()
+                    .iter()
+                    .map(|ip_network| ip_network.addr),
+            );
+        }
+
+        Ok(ip_addrs
+            .into_iter()
+            .filter(|ip_addr| !ip_addr.is_loopback())
+            .map(|ip_addr| Address::Ip {
+                address: ip_addr,
+                port: self.listen_port,
+            }))
+    }
+}
+
+async fn accept_connection(incoming: quinn::Incoming) -> Result<Connection, io::Error> {
+    let connecting = incoming.accept()?;
+
+    // Lets a resuming remote send right away, this always works for incoming connections
+    Ok(match connecting.into_0rtt() {
+        Ok((connection, _)) => connection,
+        Err(connecting) => connecting.await?,
+    })
+}
+
+fn transport_config() -> Result<TransportConfig, RouteWeaverError> {
+    let mut transport_config = TransportConfig::default();
+
+    transport_config
+        .keep_alive_interval(Some(KEEP_ALIVE_INTERVAL))
+        .max_idle_timeout(Some(
+            IdleTimeout::try_from(IDLE_TIMEOUT).map_err(io::Error::other)?,
+        ));
+
+    Ok(transport_config)
+}
+
+/// Certificate is made up on the spot, as who the remote is gets proven by the noise handshake instead
+fn server_config(
+    provider: Arc<CryptoProvider>,
+    transport_config: Arc<TransportConfig>,
+) -> Result<ServerConfig, RouteWeaverError> {
+    let certificate = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])
+        .map_err(io::Error::other)?;
+    let key = PrivatePkcs8KeyDer::from(certificate.key_pair.serialize_der());
+
+    let mut crypto = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .and_then(|builder| {
+            builder
+                .with_no_client_auth()
+                .with_single_cert(vec![certificate.cert.into()], key.into())
+        })
+        .map_err(io::Error::other)?;
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.max_early_data_size = u32::MAX;
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(
+        QuicServerConfig::try_from(crypto).map_err(io::Error::other)?,
+    ));
+    server_config
+        .transport_config(transport_config)
+        // Laptops hop between networks, their connections should follow along
+        .migration(true);
+
+    Ok(server_config)
+}
+
+fn client_config(
+    provider: Arc<CryptoProvider>,
+    transport_config: Arc<TransportConfig>,
+) -> Result<ClientConfig, RouteWeaverError> {
+    let mut crypto = rustls::ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(io::Error::other)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCertificate(provider)))
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    // Sessions are remembered by the config, so reconnecting can skip a round trip
+    crypto.enable_early_data = true;
+
+    let mut client_config = ClientConfig::new(Arc::new(
+        QuicClientConfig::try_from(crypto).map_err(io::Error::other)?,
+    ));
+    client_config.transport_config(transport_config);
+
+    Ok(client_config)
+}
+
+/// Takes whatever certificate the remote has, only making sure it actually holds the key for it
+#[derive(Debug)]
+struct AnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
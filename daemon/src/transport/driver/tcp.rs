@@ -61,7 +61,7 @@ impl Transport for TcpTransport {
         address: &Address,
     ) -> Result<(Option<impl TransportReader>, Option<impl TransportWriter>), RouteWeaverError>
     {
-        let socket_addr: SocketAddr = address.clone().try_into().unwrap();
+        let socket_addr = SocketAddr::try_from(*address)?;
 
         Ok(TcpStream::connect(socket_addr).await.map(|stream| {
             let (read, write) = stream.into_split();
@@ -0,0 +1,496 @@
+use super::{Transport, TransportReader, TransportWriter};
+use crate::{
+    error::RouteWeaverError,
+    transport::packet::{Packet, MAX_PACKET_PAYLOAD_SIZE},
+};
+use futures_util::{ready, Sink, Stream};
+use routeweaver_common::{Address, Protocol};
+use serde::Deserialize;
+use serde_inline_default::serde_inline_default;
+use socket2::Socket;
+use std::{
+    collections::HashSet,
+    future::Future,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use sysinfo::Networks;
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex,
+    },
+    time::{sleep, Instant, Sleep},
+};
+
+/// Largest datagram we will send or accept
+///
+/// Room for a full [MAX_PACKET_PAYLOAD_SIZE] body plus the packet header and noise overhead, while staying under the
+/// maximum UDP payload size for both IPv4 and IPv6
+pub const MAX_DATAGRAM_SIZE: usize = MAX_PACKET_PAYLOAD_SIZE + 512;
+
+#[serde_inline_default]
+#[derive(Clone, Deserialize, Debug)]
+pub struct UdpTransportConfig {
+    #[serde_inline_default(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0)))]
+    pub listen_address: IpAddr,
+    #[serde_inline_default(3437)]
+    pub listen_port: u16,
+    /// Seconds a pseudo-connection can go without receiving anything before it is considered closed
+    #[serde_inline_default(300)]
+    pub idle_timeout: u64,
+}
+
+/// Pseudo-connections keyed by the remote address, all sharing the same socket
+type ConnectionMap = scc::HashMap<SocketAddr, mpsc::Sender<Packet>>;
+
+pub struct UdpTransport {
+    socket: Arc<UdpSocket>,
+    connections: Arc<ConnectionMap>,
+    new_connections: Mutex<mpsc::Receiver<(UdpConnectionReader, SocketAddr)>>,
+    listen_port: u16,
+    idle_timeout: Duration,
+}
+
+impl Transport for UdpTransport {
+    const PROTOCOL: Protocol = Protocol::Udp;
+
+    async fn from_config(config: toml::Value) -> Result<Self, RouteWeaverError> {
+        let config = UdpTransportConfig::deserialize(config)?;
+
+        let listen_address = match config.listen_address {
+            IpAddr::V4(ip) => IpAddr::V6(ip.to_ipv6_mapped()),
+            IpAddr::V6(ip) => IpAddr::V6(ip),
+        };
+
+        let socket = Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::DGRAM,
+            Some(socket2::Protocol::UDP),
+        )?;
+
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.set_reuse_address(true)?;
+
+        socket.bind(&SocketAddr::new(listen_address, config.listen_port).into())?;
+
+        let socket = Arc::new(UdpSocket::from_std(socket.into())?);
+        let connections = Arc::new(ConnectionMap::default());
+        let idle_timeout = Duration::from_secs(config.idle_timeout);
+        let (new_connections_tx, new_connections_rx) = mpsc::channel(10);
+
+        tokio::spawn(datagram_reader(
+            socket.clone(),
+            connections.clone(),
+            new_connections_tx,
+            idle_timeout,
+        ));
+
+        Ok(Self {
+            socket,
+            connections,
+            new_connections: Mutex::new(new_connections_rx),
+            listen_port: config.listen_port,
+            idle_timeout,
+        })
+    }
+
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<(Option<impl TransportReader>, Option<impl TransportWriter>), RouteWeaverError>
+    {
+        let socket_addr = canonical_socket_addr((*address).try_into()?);
+        let (sender, receiver) = mpsc::channel(100);
+
+        self.connections.upsert_async(socket_addr, sender).await;
+
+        Ok((
+            Some(UdpConnectionReader::new(
+                receiver,
+                self.connections.clone(),
+                socket_addr,
+                self.idle_timeout,
+            )),
+            Some(UdpConnectionWriter::new(self.socket.clone(), socket_addr)),
+        ))
+    }
+
+    async fn accept(
+        &self,
+    ) -> Result<
+        (
+            (Option<impl TransportReader>, Option<impl TransportWriter>),
+            Address,
+        ),
+        RouteWeaverError,
+    > {
+        let (reader, socket_addr) = self
+            .new_connections
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or(RouteWeaverError::ConnectionFailed)?;
+
+        Ok((
+            (
+                Some(reader),
+                Some(UdpConnectionWriter::new(self.socket.clone(), socket_addr)),
+            ),
+            socket_addr.into(),
+        ))
+    }
+
+    async fn local_addresses(&self) -> Result<impl Iterator<Item = Address>, RouteWeaverError> {
+        let mut ip_addrs = HashSet::new();
+        let networks = Networks::new_with_refreshed_list();
+
+        for (_, network_data) in &networks {
+            ip_addrs.extend(
+                network_data
+                    .ip_networks()
+                    .iter()
+                    .map(|ip_network| ip_network.addr),
+            );
+        }
+
+        Ok(ip_addrs
+            .into_iter()
+            .filter(|ip_addr| !ip_addr.is_loopback())
+            .map(|ip_addr| Address::Ip {
+                address: ip_addr,
+                port: self.listen_port,
+            }))
+    }
+}
+
+/// The socket is dual stack, so everything is keyed and sent as ipv6, with ipv4 being mapped
+fn canonical_socket_addr(socket_addr: SocketAddr) -> SocketAddr {
+    match socket_addr {
+        SocketAddr::V4(ipv4) => SocketAddr::V6(SocketAddrV6::new(
+            ipv4.ip().to_ipv6_mapped(),
+            ipv4.port(),
+            0,
+            0,
+        )),
+        SocketAddr::V6(ipv6) => SocketAddr::V6(ipv6),
+    }
+}
+
+/// Reads every datagram off the shared socket and hands it to the pseudo-connection for its sender, creating one if
+/// needed
+async fn datagram_reader(
+    socket: Arc<UdpSocket>,
+    connections: Arc<ConnectionMap>,
+    new_connections: mpsc::Sender<(UdpConnectionReader, SocketAddr)>,
+    idle_timeout: Duration,
+) {
+    let mut buffer = vec![0; u16::MAX as usize];
+
+    loop {
+        let (amount, socket_addr) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(err) => {
+                tracing::warn!("Failed to receive datagram: {}", err);
+                continue;
+            }
+        };
+        let socket_addr = canonical_socket_addr(socket_addr);
+
+        // Decoding alone would take whatever fits and ignore the rest
+        if amount > MAX_DATAGRAM_SIZE {
+            tracing::debug!(
+                "Dropping datagram of {} bytes from {}, too large",
+                amount,
+                socket_addr
+            );
+            continue;
+        }
+
+        let packet = match bincode::serde::decode_from_slice(
+            &buffer[..amount],
+            bincode::config::standard().with_limit::<MAX_DATAGRAM_SIZE>(),
+        ) {
+            Ok((packet, _)) => packet,
+            Err(err) => {
+                tracing::debug!("Failed to decode datagram from {}: {}", socket_addr, err);
+                continue;
+            }
+        };
+
+        let packet = match connections.get_async(&socket_addr).await {
+            Some(entry) => match entry.try_send(packet) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    // Datagrams are allowed to get lost, better here than stalling every other sender
+                    tracing::debug!("Pseudo-connection for {} is full, dropping", socket_addr);
+                    continue;
+                }
+                Err(TrySendError::Closed(packet)) => {
+                    // Whoever was reading this is gone, so treat it like a new sender
+                    let _ = entry.remove_entry();
+                    packet
+                }
+            },
+            None => packet,
+        };
+
+        let (sender, receiver) = mpsc::channel(100);
+        let _ = sender.try_send(packet);
+        connections.upsert_async(socket_addr, sender).await;
+
+        // Dropping the reader on failure takes its entry back out of the map
+        match new_connections.try_send((
+            UdpConnectionReader::new(receiver, connections.clone(), socket_addr, idle_timeout),
+            socket_addr,
+        )) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::debug!(
+                    "Too many pseudo-connections waiting to be accepted, dropping datagram from {}",
+                    socket_addr
+                );
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+}
+
+/// Reading half of a pseudo-connection, ends once nothing has been received for the idle timeout
+///
+/// Removes itself from the connection map when dropped, so idle or closed pseudo-connections do not linger
+pub struct UdpConnectionReader {
+    receiver: mpsc::Receiver<Packet>,
+    connections: Arc<ConnectionMap>,
+    socket_addr: SocketAddr,
+    idle_timeout: Duration,
+    idle: Pin<Box<Sleep>>,
+}
+
+impl UdpConnectionReader {
+    fn new(
+        receiver: mpsc::Receiver<Packet>,
+        connections: Arc<ConnectionMap>,
+        socket_addr: SocketAddr,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            receiver,
+            connections,
+            socket_addr,
+            idle_timeout,
+            idle: Box::pin(sleep(idle_timeout)),
+        }
+    }
+}
+
+impl Drop for UdpConnectionReader {
+    fn drop(&mut self) {
+        self.receiver.close();
+
+        // A newer pseudo-connection for the same address may have replaced ours already
+        self.connections
+            .remove_if(&self.socket_addr, |sender| sender.is_closed());
+    }
+}
+
+impl Stream for UdpConnectionReader {
+    type Item = Result<Packet, RouteWeaverError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(packet)) => {
+                let deadline = Instant::now() + self.idle_timeout;
+                self.idle.as_mut().reset(deadline);
+
+                Poll::Ready(Some(Ok(packet)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                if self.idle.as_mut().poll(cx).is_ready() {
+                    Poll::Ready(None)
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+/// Writing half of a pseudo-connection, every packet is exactly one datagram
+pub struct UdpConnectionWriter {
+    socket: Arc<UdpSocket>,
+    socket_addr: SocketAddr,
+    buffer: Vec<u8>,
+    /// Length of the encoded datagram in the buffer that has yet to be sent
+    pending: Option<usize>,
+}
+
+impl UdpConnectionWriter {
+    fn new(socket: Arc<UdpSocket>, socket_addr: SocketAddr) -> Self {
+        Self {
+            socket,
+            socket_addr,
+            buffer: vec![0; MAX_DATAGRAM_SIZE],
+            pending: None,
+        }
+    }
+}
+
+impl Sink<Packet> for UdpConnectionWriter {
+    type Error = RouteWeaverError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Packet) -> Result<(), Self::Error> {
+        let amount = bincode::serde::encode_into_slice(
+            item,
+            &mut self.buffer,
+            bincode::config::standard().with_limit::<MAX_DATAGRAM_SIZE>(),
+        )?;
+
+        self.pending = Some(amount);
+
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(amount) = self.pending {
+            ready!(self
+                .socket
+                .poll_send_to(cx, &self.buffer[..amount], self.socket_addr))?;
+
+            self.pending = None;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UdpTransport, MAX_DATAGRAM_SIZE};
+    use crate::transport::{
+        driver::Transport,
+        packet::{Packet, PacketData},
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use routeweaver_common::{Address, PublicKey};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use tokio::net::UdpSocket;
+
+    async fn transport() -> (UdpTransport, Address) {
+        let config = "listen_address = \"127.0.0.1\"\nlisten_port = 0"
+            .parse::<toml::Table>()
+            .unwrap();
+        let transport = UdpTransport::from_config(toml::Value::Table(config))
+            .await
+            .unwrap();
+        let address = Address::Ip {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: transport.socket.local_addr().unwrap().port(),
+        };
+
+        (transport, address)
+    }
+
+    fn packet(size: usize) -> Packet {
+        Packet::new(
+            PublicKey::new([1; 32]),
+            Some(PublicKey::new([2; 32])),
+            PacketData::MessageSegment {
+                epoch: 0,
+                nonce: 0,
+                data: vec![3; size],
+            },
+        )
+    }
+
+    fn size(packet: &Packet) -> usize {
+        match &packet.data {
+            PacketData::MessageSegment { data, .. } => data.len(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_over_loopback() {
+        let (a, _) = transport().await;
+        let (b, b_address) = transport().await;
+
+        let (Some(mut a_reader), Some(mut a_writer)) = a.connect(&b_address).await.unwrap() else {
+            unreachable!()
+        };
+        a_writer.send(packet(10)).await.unwrap();
+        a_writer.send(packet(20)).await.unwrap();
+
+        let ((Some(mut b_reader), Some(mut b_writer)), _) = b.accept().await.unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(size(&b_reader.next().await.unwrap().unwrap()), 10);
+        assert_eq!(size(&b_reader.next().await.unwrap().unwrap()), 20);
+
+        // A full packet still fits in one datagram
+        let full = MAX_DATAGRAM_SIZE - 1024;
+        b_writer.send(packet(full)).await.unwrap();
+        assert_eq!(size(&a_reader.next().await.unwrap().unwrap()), full);
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_datagrams() {
+        let (a, _) = transport().await;
+        let (b, b_address) = transport().await;
+
+        let (_, Some(mut writer)) = a.connect(&b_address).await.unwrap() else {
+            unreachable!()
+        };
+        assert!(writer.send(packet(MAX_DATAGRAM_SIZE)).await.is_err());
+
+        // Nothing past the limit is decoded, and whoever sent it doesn't get a pseudo-connection out of it
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b_socket_addr = SocketAddr::try_from(b_address).unwrap();
+        socket
+            .send_to(&vec![0; MAX_DATAGRAM_SIZE + 1], b_socket_addr)
+            .await
+            .unwrap();
+        writer.send(packet(10)).await.unwrap();
+
+        let ((Some(mut reader), _), _) = b.accept().await.unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(size(&reader.next().await.unwrap().unwrap()), 10);
+        assert_eq!(b.connections.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dropped_readers_leave_the_map() {
+        let (a, a_address) = transport().await;
+        let (b, b_address) = transport().await;
+
+        let (reader, Some(mut writer)) = a.connect(&b_address).await.unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(a.connections.len(), 1);
+        drop(reader);
+        assert!(a.connections.is_empty());
+
+        writer.send(packet(10)).await.unwrap();
+        let ((reader, _), address) = b.accept().await.unwrap();
+        assert_eq!(address.to_string(), a_address.to_string());
+        assert_eq!(b.connections.len(), 1);
+        drop(reader);
+        assert!(b.connections.is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+use super::{PacketEncoderDecoder, Transport, TransportReader, TransportWriter};
+use crate::error::RouteWeaverError;
+use arrayvec::ArrayString;
+use routeweaver_common::{ipc::RPC_BASE_DIR, Address, Protocol, MAX_UNIX_PATH_LENGTH};
+use serde::Deserialize;
+use serde_inline_default::serde_inline_default;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use tokio::{
+    fs::{create_dir_all, remove_file},
+    net::{UnixListener, UnixStream},
+};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+#[serde_inline_default]
+#[derive(Clone, Deserialize, Debug)]
+pub struct UnixTransportConfig {
+    #[serde_inline_default(RPC_BASE_DIR.join("transport"))]
+    pub path: PathBuf,
+}
+
+pub struct UnixTransport {
+    socket: UnixListener,
+    path: ArrayString<MAX_UNIX_PATH_LENGTH>,
+    /// Numbers handed to incoming connections from unnamed sockets
+    next_unnamed: AtomicU64,
+}
+
+impl Transport for UnixTransport {
+    const PROTOCOL: Protocol = Protocol::Unix;
+
+    async fn from_config(config: toml::Value) -> Result<Self, RouteWeaverError> {
+        let config = UnixTransportConfig::deserialize(config)?;
+
+        // Has to fit in an address to be told to others
+        let path = config
+            .path
+            .to_str()
+            .and_then(|path| ArrayString::from(path).ok())
+            .ok_or(RouteWeaverError::InvalidAddress)?;
+
+        if let Some(parent) = config.path.parent() {
+            create_dir_all(parent).await?;
+        }
+        // Left behind by an earlier run
+        let _ = remove_file(&config.path).await;
+
+        Ok(Self {
+            socket: UnixListener::bind(&config.path)?,
+            path,
+            next_unnamed: AtomicU64::new(0),
+        })
+    }
+
+    async fn connect(
+        &self,
+        address: &Address,
+    ) -> Result<(Option<impl TransportReader>, Option<impl TransportWriter>), RouteWeaverError>
+    {
+        let Address::Unix { path } = address else {
+            return Err(RouteWeaverError::InvalidAddress);
+        };
+
+        Ok(UnixStream::connect(path.as_str()).await.map(|stream| {
+            let (read, write) = stream.into_split();
+            (
+                Some(FramedRead::new(read, PacketEncoderDecoder)),
+                Some(FramedWrite::new(write, PacketEncoderDecoder)),
+            )
+        })?)
+    }
+
+    async fn accept(
+        &self,
+    ) -> Result<
+        (
+            (Option<impl TransportReader>, Option<impl TransportWriter>),
+            Address,
+        ),
+        RouteWeaverError,
+    > {
+        let (stream, socket_addr) = self.socket.accept().await?;
+
+        let path = socket_addr
+            .as_pathname()
+            .and_then(|path| path.to_str())
+            .and_then(|path| ArrayString::from(path).ok())
+            .unwrap_or_else(|| {
+                let number = self.next_unnamed.fetch_add(1, Ordering::Relaxed);
+                let mut path = ArrayString::new();
+                path.push('#');
+                path.push_str(&number.to_string());
+                path
+            });
+
+        let (read, write) = stream.into_split();
+
+        Ok((
+            (
+                Some(FramedRead::new(read, PacketEncoderDecoder)),
+                Some(FramedWrite::new(write, PacketEncoderDecoder)),
+            ),
+            Address::Unix { path },
+        ))
+    }
+
+    async fn local_addresses(&self) -> Result<impl Iterator<Item = Address>, RouteWeaverError> {
+        Ok(std::iter::once(Address::Unix { path: self.path }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnixTransport;
+    use crate::transport::{
+        driver::Transport,
+        packet::{Packet, PacketData},
+    };
+    use futures_util::{SinkExt, StreamExt};
+    use routeweaver_common::{Address, Peer, PublicKey};
+
+    async fn transport(name: &str) -> UnixTransport {
+        let path =
+            std::env::temp_dir().join(format!("routeweaver-unix-{}-{}", name, std::process::id()));
+        let mut config = toml::Table::new();
+        config.insert("path".into(), path.to_str().unwrap().into());
+
+        UnixTransport::from_config(toml::Value::Table(config))
+            .await
+            .unwrap()
+    }
+
+    fn packet(nonce: u64) -> Packet {
+        Packet::new(
+            PublicKey::new([1; 32]),
+            Some(PublicKey::new([2; 32])),
+            PacketData::MessageSegment {
+                epoch: 0,
+                nonce,
+                data: vec![3; 10],
+            },
+        )
+    }
+
+    fn nonce(packet: &Packet) -> u64 {
+        match &packet.data {
+            PacketData::MessageSegment { nonce, .. } => *nonce,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_over_socket() {
+        let transport = transport("round-trip").await;
+        let address = transport.local_addresses().await.unwrap().next().unwrap();
+
+        let (Some(mut reader), Some(mut writer)) = transport.connect(&address).await.unwrap()
+        else {
+            unreachable!()
+        };
+        writer.send(packet(1)).await.unwrap();
+
+        let ((Some(mut accepted_reader), Some(mut accepted_writer)), accepted_address) =
+            transport.accept().await.unwrap()
+        else {
+            unreachable!()
+        };
+        assert_eq!(nonce(&accepted_reader.next().await.unwrap().unwrap()), 1);
+
+        accepted_writer.send(packet(2)).await.unwrap();
+        assert_eq!(nonce(&reader.next().await.unwrap().unwrap()), 2);
+
+        // Connecting sockets are never bound to a path, so they get numbered instead
+        assert_eq!(accepted_address.to_string(), "#0");
+    }
+
+    #[tokio::test]
+    async fn local_address_round_trips() {
+        let transport = transport("address").await;
+        let address = transport.local_addresses().await.unwrap().next().unwrap();
+        let Address::Unix { path } = address else {
+            unreachable!()
+        };
+
+        let peer = Peer {
+            protocol: UnixTransport::PROTOCOL,
+            address,
+        };
+        let printed = peer.to_string();
+        assert_eq!(printed, format!("/unix/path/{}", path));
+        assert_eq!(printed.parse::<Peer>().unwrap(), peer);
+    }
+}
@@ -0,0 +1,866 @@
+use arrayvec::ArrayVec;
+use routeweaver_common::{Peer, PublicKey};
+use snow::HandshakeState;
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    channel::lifetime::Channel,
+    error::RouteWeaverError,
+    noise::{create_handshake_responder, split_handshake},
+    proto::Capabilities,
+    state::ServerState,
+};
+
+use super::{
+    misbehaviour::{punish_node, punish_peer, Offender, Offense},
+    packet::{Packet, PacketData},
+    router::RequestRoutePacket,
+    setup_connection::learn_neighbor,
+};
+
+/// How long a handshake gets to finish before it is given up on
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long the remote gets to answer before our last message is sent again
+const HANDSHAKE_RETRANSMIT_INTERVAL: Duration = Duration::from_secs(2);
+/// How often handshakes are checked for retransmission and expiry
+const HANDSHAKE_KEEPER_INTERVAL: Duration = Duration::from_millis(500);
+/// Most handshakes other nodes started that can be in progress at once, so a flood of first messages can't pile up state
+const MAX_HALF_OPEN_HANDSHAKES: usize = 256;
+/// Same as above but for the ones coming in over any one peer, so a single peer can't take up all of them
+const MAX_HALF_OPEN_HANDSHAKES_PER_PEER: usize = 16;
+/// The first message is only the initiator's ephemeral key, every one after it is longer
+const FIRST_MESSAGE_LENGTH: usize = 32;
+/// Mixing in a preshared key starts with the ephemeral key, so the empty payload of the first message gets a tag
+const PSK_FIRST_MESSAGE_LENGTH: usize = FIRST_MESSAGE_LENGTH + 16;
+
+pub type HandshakeMessage = ArrayVec<u8, 128>;
+
+/// Handshake in progress with a node
+pub struct Handshake {
+    pub state: HandshakeState,
+    deadline: Instant,
+    /// Last message we sent, kept in case the remote never got it
+    last_sent: Option<(HandshakeMessage, Instant)>,
+    /// Last message the remote sent, so a copy of it can be told apart from the next one
+    last_received: Option<HandshakeMessage>,
+    /// What the remote supports, carried by its first encrypted message
+    pub remote_capabilities: Option<Capabilities>,
+    /// Peer the node hinted it is directly behind, only trusted once the handshake proves who the node is
+    ///
+    /// Messages go straight over this peer, as there is no route to the node until then
+    pub via: Option<Peer>,
+    /// Peer the first message came in over, for handshakes the remote started
+    arrived_over: Option<Peer>,
+}
+
+impl Handshake {
+    pub fn new(state: HandshakeState) -> Self {
+        Self {
+            state,
+            deadline: Instant::now() + HANDSHAKE_TIMEOUT,
+            last_sent: None,
+            last_received: None,
+            remote_capabilities: None,
+            via: None,
+            arrived_over: None,
+        }
+    }
+
+    /// Handshake the remote started, for answering a first message that came in over the peer
+    fn inbound(server_state: &ServerState, arrived_over: Peer, via: Option<Peer>) -> Self {
+        Self {
+            via,
+            arrived_over: Some(arrived_over),
+            ..Self::new(create_handshake_responder(
+                &server_state.keys.private,
+                server_state.mesh_trust.psk.as_ref(),
+            ))
+        }
+    }
+
+    /// Nothing has proven who the remote is yet, so anyone could have started this
+    fn is_unproven(&self) -> bool {
+        self.state.get_remote_static().is_none()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Our last message, if the remote has taken long enough answering it that it was probably lost
+    fn retransmit(&mut self) -> Option<HandshakeMessage> {
+        let (message, sent) = self.last_sent.as_mut()?;
+
+        if self.state.is_my_turn() || sent.elapsed() < HANDSHAKE_RETRANSMIT_INTERVAL {
+            return None;
+        }
+
+        *sent = Instant::now();
+        Some(message.clone())
+    }
+}
+
+/// Reads what the remote supports out of a handshake message payload, which is empty for the first message
+pub fn read_capabilities(payload: &[u8]) -> Result<Option<Capabilities>, RouteWeaverError> {
+    if payload.is_empty() {
+        return Ok(None);
+    }
+
+    // Anything past what we know of is from a newer version and left alone
+    let (capabilities, _) =
+        bincode::serde::decode_from_slice(payload, bincode::config::standard())?;
+
+    Ok(Some(capabilities))
+}
+
+/// Whether a message could open a handshake, going by its length as that is all there is to go on before reading it
+fn is_first_message(server_state: &ServerState, data: &HandshakeMessage) -> bool {
+    let length = match server_state.mesh_trust.psk {
+        Some(_) => PSK_FIRST_MESSAGE_LENGTH,
+        None => FIRST_MESSAGE_LENGTH,
+    };
+
+    data.len() == length
+}
+
+/// Whether our handshake is the one kept when both ends start one at the same time
+///
+/// Same as with duplicate links in [learn_neighbor], the one started by the node with the lower key wins
+fn wins_crossing(server_state: &ServerState, node: PublicKey) -> bool {
+    server_state.keys.public.as_ref() < node.as_ref()
+}
+
+/// Whether another handshake started by a node over the peer would be too many, overall or for that peer
+///
+/// Peers reached over IP are counted by their address alone, same as penalties
+async fn too_many_inbound(server_state: &ServerState, peer: Peer) -> bool {
+    let offender = Offender::from(&peer);
+    let mut total = 0;
+    let mut over_peer = 0;
+
+    server_state
+        .handshake_tracker
+        .scan_async(|_, handshake| {
+            if let Some(arrived_over) = &handshake.arrived_over {
+                total += 1;
+
+                if Offender::from(arrived_over) == offender {
+                    over_peer += 1;
+                }
+            }
+        })
+        .await;
+
+    total >= MAX_HALF_OPEN_HANDSHAKES || over_peer >= MAX_HALF_OPEN_HANDSHAKES_PER_PEER
+}
+
+/// Starts tracking a handshake we initiated and sends whatever it has to say first
+///
+/// Does nothing if there is already a handshake going with the node, unless that one is stuck waiting on the remote
+/// while this one already heard back, or the node started it, nothing proved it yet, and ours would win over it anyway
+pub async fn start_handshake(
+    server_state: &ServerState,
+    node: PublicKey,
+    handshake: Handshake,
+) -> Result<(), RouteWeaverError> {
+    match server_state.handshake_tracker.entry_async(node).await {
+        scc::hash_map::Entry::Occupied(mut entry) => {
+            let existing = entry.get();
+
+            let replace = match existing.arrived_over {
+                // Like when a new link got answered while one over the channel was still out, the remote is only
+                // going on with the one it answered
+                None => existing.is_unproven() && !handshake.is_unproven(),
+                Some(_) => existing.is_unproven() && wins_crossing(server_state, node),
+            };
+
+            if !replace {
+                return Ok(());
+            }
+
+            tracing::debug!("Replacing handshake with node {} with ours", node);
+            entry.insert(handshake);
+        }
+        scc::hash_map::Entry::Vacant(entry) => {
+            entry.insert_entry(handshake);
+        }
+    }
+
+    advance_handshake(server_state, node).await
+}
+
+/// Feeds a handshake message from a node into its handshake, starting one as the responder if needed
+///
+/// `peer` is the peer the message came in over, and `via` is that same peer if the node hinted that it is our neighbor
+pub async fn receive_handshake(
+    server_state: &ServerState,
+    source: PublicKey,
+    data: HandshakeMessage,
+    peer: Peer,
+    via: Option<Peer>,
+) -> Result<(), RouteWeaverError> {
+    let first_message = is_first_message(server_state, &data);
+    // Checked up front as the map can't be gone through while holding an entry
+    let full = first_message && too_many_inbound(server_state, peer).await;
+
+    let mut entry = match server_state.handshake_tracker.entry_async(source).await {
+        scc::hash_map::Entry::Occupied(mut entry) => {
+            let existing = entry.get();
+
+            // Whoever sent the first message of the existing one might not have been the node either, so a new
+            // attempt starts over instead of waiting on it to time out
+            if first_message
+                && existing.is_unproven()
+                && existing.last_received.as_ref() != Some(&data)
+            {
+                if existing.arrived_over.is_none() {
+                    if wins_crossing(server_state, source) {
+                        tracing::debug!("Handshakes with node {} crossed, keeping ours", source);
+                        return Ok(());
+                    }
+
+                    tracing::debug!("Handshakes with node {} crossed, going with theirs", source);
+                }
+
+                entry.insert(Handshake::inbound(server_state, peer, via));
+            }
+
+            entry
+        }
+        // Only a first message can start a handshake
+        scc::hash_map::Entry::Vacant(_) if !first_message => {
+            tracing::debug!("Handshake message from node {} for no handshake", source);
+            return Ok(());
+        }
+        scc::hash_map::Entry::Vacant(_) if full => {
+            tracing::debug!("Too many handshakes going, ignoring node {}", source);
+            return Ok(());
+        }
+        scc::hash_map::Entry::Vacant(entry) => {
+            entry.insert_entry(Handshake::inbound(server_state, peer, via))
+        }
+    };
+
+    let handshake = entry.get_mut();
+
+    // They sent the same thing again, so our answer must have gotten lost
+    if handshake.last_received.as_ref() == Some(&data) {
+        let resend = handshake.last_sent.as_mut().map(|(message, sent)| {
+            *sent = Instant::now();
+            message.clone()
+        });
+        let via = handshake.via;
+        drop(entry);
+
+        if let Some(message) = resend {
+            send_handshake_message(server_state, source, message, via).await?;
+        }
+
+        return Ok(());
+    }
+
+    if handshake.state.is_my_turn() || handshake.state.is_handshake_finished() {
+        return Ok(());
+    }
+
+    let mut payload = [0; 128];
+
+    match handshake
+        .state
+        .read_message(&data, &mut payload)
+        .map_err(RouteWeaverError::from)
+        .and_then(|amount| read_capabilities(&payload[..amount]))
+    {
+        Ok(remote_capabilities) => {
+            handshake.last_received = Some(data);
+
+            if remote_capabilities.is_some() {
+                handshake.remote_capabilities = remote_capabilities;
+            }
+        }
+        // The state can't be trusted after a failed read, so start over
+        Err(err) => {
+            tracing::error!(
+                "Error decoding handshake message from node {}: {}",
+                source,
+                err
+            );
+            let _ = entry.remove();
+            return Ok(());
+        }
+    }
+
+    drop(entry);
+
+    advance_handshake(server_state, source).await
+}
+
+/// Writes our next message if it is our turn, and turns the handshake into a channel once it's done
+async fn advance_handshake(
+    server_state: &ServerState,
+    node: PublicKey,
+) -> Result<(), RouteWeaverError> {
+    let mut buffer = [0; 128];
+
+    let Some(mut entry) = server_state.handshake_tracker.get_async(&node).await else {
+        return Ok(());
+    };
+
+    let mut message = None;
+    let via = entry.via;
+
+    // Snow counts it as our turn again once everything has been said
+    if entry.state.is_my_turn() && !entry.state.is_handshake_finished() {
+        // The very first message goes out in the clear, every one after it is encrypted and says what we support
+        let payload = if entry.state.is_initiator() && entry.state.get_remote_static().is_none() {
+            Vec::new()
+        } else {
+            bincode::serde::encode_to_vec(Capabilities::ours(), bincode::config::standard())?
+        };
+
+        let data = entry
+            .state
+            .write_message(&payload, &mut buffer)
+            .map_err(RouteWeaverError::from)
+            .and_then(|amount| {
+                HandshakeMessage::try_from(&buffer[..amount])
+                    .map_err(|_| RouteWeaverError::IncorrectHandshakeMessage)
+            });
+
+        match data {
+            Ok(data) => {
+                entry.last_sent = Some((data.clone(), Instant::now()));
+                message = Some(data);
+            }
+            Err(err) => {
+                tracing::error!("Failed writing handshake message for {}: {}", node, err);
+                let _ = entry.remove();
+                return Ok(());
+            }
+        }
+    }
+
+    if entry.state.is_handshake_finished() {
+        let handshake = entry.remove();
+
+        if let Err(err) = finalize_handshake(server_state, node, handshake).await {
+            tracing::error!("Failed to finalize handshake with {}: {}", node, err);
+
+            if let RouteWeaverError::NodeMismatch { actual, .. } = err {
+                punish_node(server_state, actual, Offense::NodeMismatch).await;
+
+                // The peer vouched for a node that wasn't behind it
+                if let Some(via) = via {
+                    punish_peer(server_state, via, Offense::InvalidIdentityHint).await;
+                }
+            }
+
+            return Ok(());
+        }
+    } else {
+        drop(entry);
+    }
+
+    if let Some(message) = message {
+        send_handshake_message(server_state, node, message, via).await?;
+    }
+
+    Ok(())
+}
+
+async fn send_handshake_message(
+    server_state: &ServerState,
+    node: PublicKey,
+    message: HandshakeMessage,
+    via: Option<Peer>,
+) -> Result<(), RouteWeaverError> {
+    let packet = Packet::new(
+        server_state.keys.public,
+        Some(node),
+        PacketData::Handshake(message),
+    );
+
+    let Some(via) = via else {
+        server_state
+            .request_route_packet
+            .send(RequestRoutePacket {
+                origin: None,
+                packet,
+            })
+            .await?;
+
+        return Ok(());
+    };
+
+    // If the connection is gone the handshake can't finish over it anyway
+    if let Some(sender) = server_state
+        .request_write_packet
+        .read_async(&via, |_, sender| sender.clone())
+        .await
+    {
+        let _ = sender.send(packet).await;
+    }
+
+    Ok(())
+}
+
+/// Sends handshake messages the remote seems to have missed again, and gives up on handshakes that take too long
+pub async fn handshake_keeper(server_state: Arc<ServerState>) {
+    loop {
+        sleep(HANDSHAKE_KEEPER_INTERVAL).await;
+
+        let mut retransmits = Vec::new();
+        server_state
+            .handshake_tracker
+            .retain_async(|node, handshake| {
+                if handshake.is_expired() {
+                    tracing::debug!("Handshake with node {} timed out", node);
+                    return false;
+                }
+
+                if let Some(message) = handshake.retransmit() {
+                    retransmits.push((*node, message, handshake.via));
+                }
+
+                true
+            })
+            .await;
+
+        for (node, message, via) in retransmits {
+            tracing::debug!("Sending handshake message to node {} again", node);
+
+            if send_handshake_message(&server_state, node, message, via)
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+/// Turns a finished handshake into a channel, as long as the node is who it claimed to be and speaks a version we do
+async fn finalize_handshake(
+    server_state: &ServerState,
+    source: PublicKey,
+    handshake: Handshake,
+) -> Result<(), RouteWeaverError> {
+    let remote_node_id = PublicKey::new(
+        handshake
+            .state
+            .get_remote_static()
+            .and_then(|key| key.try_into().ok())
+            .ok_or(RouteWeaverError::InvalidKey)?,
+    );
+
+    if source != remote_node_id {
+        return Err(RouteWeaverError::NodeMismatch {
+            claimed: source,
+            actual: remote_node_id,
+        });
+    }
+
+    // Checked again here as this is the first time the key is actually proven
+    if !server_state.mesh_trust.is_node_trusted(&remote_node_id) {
+        return Err(RouteWeaverError::UntrustedNode {
+            node: remote_node_id,
+        });
+    }
+
+    let remote_capabilities = handshake
+        .remote_capabilities
+        .ok_or(RouteWeaverError::IncorrectHandshakeMessage)?;
+    let capabilities = Capabilities::ours().negotiate(&remote_capabilities).ok_or(
+        RouteWeaverError::UnsupportedVersion {
+            version: remote_capabilities.version,
+        },
+    )?;
+
+    tracing::debug!(
+        "Finalized handshake with node {}, speaking protocol version {}",
+        source,
+        capabilities.version
+    );
+
+    let (sending_key, receiving_key) = split_handshake(handshake.state)?;
+
+    // Only now is it known that the node really is behind the peer it hinted at
+    if let Some(via) = handshake.via {
+        learn_neighbor(server_state, source, via).await;
+    }
+
+    match server_state.transport_tracker.entry_async(source).await {
+        scc::hash_map::Entry::Occupied(mut entry) => {
+            // Most likely the node restarted, so it has to be sent whatever it had not confirmed again
+            tracing::debug!("Replacing existing channel with node {}", source);
+            entry.insert(Channel::new(sending_key, receiving_key, capabilities));
+            let _ = server_state.notification_channel_reset.send(source);
+        }
+        scc::hash_map::Entry::Vacant(entry) => {
+            entry.insert_entry(Channel::new(sending_key, receiving_key, capabilities));
+        }
+    }
+    let _ = server_state.notification_handshaked_node.send(source);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        receive_handshake, start_handshake, Handshake, HandshakeMessage,
+        MAX_HALF_OPEN_HANDSHAKES_PER_PEER,
+    };
+    use crate::{
+        noise::{create_handshake_initiator, generate_keys, PresharedKey},
+        proto::Capabilities,
+        state::ServerState,
+        transport::{packet::PacketData, router::RequestRoutePacket},
+    };
+    use routeweaver_common::{Address, Peer, Protocol};
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::sync::mpsc;
+
+    const PEER: Peer = Peer {
+        protocol: Protocol::Tcp,
+        address: Address::Ip {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3434,
+        },
+    };
+
+    fn server_state() -> (ServerState, mpsc::Receiver<RequestRoutePacket>) {
+        let (request_route_packet, routed_packets) = mpsc::channel(100);
+
+        let server_state = ServerState::new(
+            false,
+            generate_keys(),
+            request_route_packet,
+            mpsc::channel(1).0,
+            mpsc::channel(1).0,
+            mpsc::channel(1).0,
+            mpsc::channel(1).0,
+        );
+
+        (server_state, routed_packets)
+    }
+
+    /// Passes the next handshake message one side sent to the other
+    async fn deliver(from: &mut mpsc::Receiver<RequestRoutePacket>, to: &ServerState) {
+        let RequestRoutePacket { packet, .. } = from.try_recv().unwrap();
+        let PacketData::Handshake(data) = packet.data else {
+            panic!("Expected a handshake message");
+        };
+
+        receive_handshake(to, packet.source, data, PEER, None)
+            .await
+            .unwrap();
+    }
+
+    /// Has both sides read everything the other sent until neither has anything left to say
+    async fn deliver_all(
+        (a, a_packets): (&ServerState, &mut mpsc::Receiver<RequestRoutePacket>),
+        (b, b_packets): (&ServerState, &mut mpsc::Receiver<RequestRoutePacket>),
+    ) {
+        while !a_packets.is_empty() || !b_packets.is_empty() {
+            while !a_packets.is_empty() {
+                deliver(a_packets, b).await;
+            }
+            while !b_packets.is_empty() {
+                deliver(b_packets, a).await;
+            }
+        }
+    }
+
+    /// First message of a handshake from a node that isn't really behind it
+    fn forged_first_message() -> HandshakeMessage {
+        let mut buffer = [0; 128];
+        let amount = create_handshake_initiator(&generate_keys().private, None)
+            .write_message(&[], &mut buffer)
+            .unwrap();
+
+        HandshakeMessage::try_from(&buffer[..amount]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn handshake_driven_by_messages() {
+        let (initiator, mut initiator_packets) = server_state();
+        let (responder, mut responder_packets) = server_state();
+        let initiator_node = initiator.keys.public;
+        let responder_node = responder.keys.public;
+
+        start_handshake(
+            &initiator,
+            responder_node,
+            Handshake::new(create_handshake_initiator(&initiator.keys.private, None)),
+        )
+        .await
+        .unwrap();
+
+        deliver(&mut initiator_packets, &responder).await;
+        deliver(&mut responder_packets, &initiator).await;
+
+        // Initiator is done after its last message, the responder once that arrives
+        assert!(initiator.transport_tracker.contains(&responder_node));
+        assert!(!responder.transport_tracker.contains(&initiator_node));
+
+        deliver(&mut initiator_packets, &responder).await;
+        assert!(responder.transport_tracker.contains(&initiator_node));
+
+        // Both learned what the other supports along the way
+        for (server_state, node) in [(&initiator, responder_node), (&responder, initiator_node)] {
+            assert_eq!(
+                server_state
+                    .transport_tracker
+                    .read(&node, |_, channel| channel.capabilities),
+                Some(Capabilities::ours())
+            );
+        }
+        assert!(initiator.handshake_tracker.is_empty());
+        assert!(responder.handshake_tracker.is_empty());
+        assert!(initiator_packets.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn preshared_key_handshake_driven_by_messages() {
+        let psk = || "11".repeat(32).parse::<PresharedKey>().unwrap();
+        let (mut initiator, mut initiator_packets) = server_state();
+        let (mut responder, mut responder_packets) = server_state();
+        initiator.mesh_trust.psk = Some(psk());
+        responder.mesh_trust.psk = Some(psk());
+
+        start_handshake(
+            &initiator,
+            responder.keys.public,
+            Handshake::new(create_handshake_initiator(
+                &initiator.keys.private,
+                initiator.mesh_trust.psk.as_ref(),
+            )),
+        )
+        .await
+        .unwrap();
+
+        deliver_all(
+            (&initiator, &mut initiator_packets),
+            (&responder, &mut responder_packets),
+        )
+        .await;
+
+        assert!(initiator.transport_tracker.contains(&responder.keys.public));
+        assert!(responder.transport_tracker.contains(&initiator.keys.public));
+        assert!(initiator.handshake_tracker.is_empty());
+        assert!(responder.handshake_tracker.is_empty());
+    }
+
+    #[tokio::test]
+    async fn copy_gets_last_answer_again() {
+        let (initiator, mut initiator_packets) = server_state();
+        let (responder, mut responder_packets) = server_state();
+
+        start_handshake(
+            &initiator,
+            responder.keys.public,
+            Handshake::new(create_handshake_initiator(&initiator.keys.private, None)),
+        )
+        .await
+        .unwrap();
+
+        let RequestRoutePacket { packet, .. } = initiator_packets.try_recv().unwrap();
+        let PacketData::Handshake(first) = packet.data else {
+            unreachable!()
+        };
+
+        receive_handshake(&responder, packet.source, first.clone(), PEER, None)
+            .await
+            .unwrap();
+        receive_handshake(&responder, packet.source, first, PEER, None)
+            .await
+            .unwrap();
+
+        let answer = responder_packets.try_recv().unwrap().packet.data;
+        let again = responder_packets.try_recv().unwrap().packet.data;
+        assert!(matches!(
+            (answer, again),
+            (PacketData::Handshake(answer), PacketData::Handshake(again)) if answer == again
+        ));
+
+        // The handshake survived the copy
+        assert!(responder.handshake_tracker.contains(&initiator.keys.public));
+    }
+
+    #[tokio::test]
+    async fn hinted_neighbor_only_learned_once_proven() {
+        let (initiator, mut initiator_packets) = server_state();
+        let (responder, _) = server_state();
+        let initiator_node = initiator.keys.public;
+
+        // Everything the responder says goes straight back over the peer the hint came from
+        let (peer_tx, mut peer_rx) = mpsc::channel(10);
+        responder
+            .request_write_packet
+            .upsert_async(PEER, peer_tx)
+            .await;
+
+        start_handshake(
+            &initiator,
+            responder.keys.public,
+            Handshake::new(create_handshake_initiator(&initiator.keys.private, None)),
+        )
+        .await
+        .unwrap();
+
+        let PacketData::Handshake(first) = initiator_packets.try_recv().unwrap().packet.data else {
+            unreachable!()
+        };
+        receive_handshake(&responder, initiator_node, first, PEER, Some(PEER))
+            .await
+            .unwrap();
+        assert!(!responder.routing_table.is_neighbor(&initiator_node).await);
+
+        let answer = peer_rx.try_recv().unwrap();
+        let PacketData::Handshake(answer) = answer.data else {
+            unreachable!()
+        };
+        receive_handshake(&initiator, responder.keys.public, answer, PEER, None)
+            .await
+            .unwrap();
+        deliver(&mut initiator_packets, &responder).await;
+
+        assert!(responder.transport_tracker.contains(&initiator_node));
+        assert_eq!(
+            responder.routing_table.next_hop(&initiator_node).await,
+            Some(PEER)
+        );
+    }
+
+    #[tokio::test]
+    async fn crossing_handshakes_settle_on_one() {
+        let (a, mut a_packets) = server_state();
+        let (b, mut b_packets) = server_state();
+
+        for (from, to) in [(&a, &b), (&b, &a)] {
+            start_handshake(
+                from,
+                to.keys.public,
+                Handshake::new(create_handshake_initiator(&from.keys.private, None)),
+            )
+            .await
+            .unwrap();
+        }
+
+        deliver_all((&a, &mut a_packets), (&b, &mut b_packets)).await;
+
+        assert!(a.transport_tracker.contains(&b.keys.public));
+        assert!(b.transport_tracker.contains(&a.keys.public));
+        assert!(a.handshake_tracker.is_empty());
+        assert!(b.handshake_tracker.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forged_first_message_does_not_block_node() {
+        let (initiator, mut initiator_packets) = server_state();
+        let (responder, mut responder_packets) = server_state();
+        let initiator_node = initiator.keys.public;
+
+        receive_handshake(
+            &responder,
+            initiator_node,
+            forged_first_message(),
+            PEER,
+            None,
+        )
+        .await
+        .unwrap();
+        // Goes nowhere, as whoever sent it isn't the node
+        responder_packets.try_recv().unwrap();
+
+        start_handshake(
+            &initiator,
+            responder.keys.public,
+            Handshake::new(create_handshake_initiator(&initiator.keys.private, None)),
+        )
+        .await
+        .unwrap();
+
+        deliver_all(
+            (&initiator, &mut initiator_packets),
+            (&responder, &mut responder_packets),
+        )
+        .await;
+
+        assert!(responder.transport_tracker.contains(&initiator_node));
+        assert!(initiator.transport_tracker.contains(&responder.keys.public));
+    }
+
+    #[tokio::test]
+    async fn half_open_handshakes_capped_per_peer() {
+        let (server_state, _routed_packets) = server_state();
+        let other_peer = Peer {
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)),
+                port: 3434,
+            },
+            ..PEER
+        };
+
+        for _ in 0..MAX_HALF_OPEN_HANDSHAKES_PER_PEER {
+            receive_handshake(
+                &server_state,
+                generate_keys().public,
+                forged_first_message(),
+                PEER,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            server_state.handshake_tracker.len(),
+            MAX_HALF_OPEN_HANDSHAKES_PER_PEER
+        );
+
+        // Another port on the same address is still the same peer
+        let same_address = Peer {
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 4343,
+            },
+            ..PEER
+        };
+        let turned_away = generate_keys().public;
+        receive_handshake(
+            &server_state,
+            turned_away,
+            forged_first_message(),
+            same_address,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(!server_state.handshake_tracker.contains(&turned_away));
+
+        let elsewhere = generate_keys().public;
+        receive_handshake(
+            &server_state,
+            elsewhere,
+            forged_first_message(),
+            other_peer,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(server_state.handshake_tracker.contains(&elsewhere));
+
+        // Our own aren't held back by what others started
+        let ours = generate_keys().public;
+        start_handshake(
+            &server_state,
+            ours,
+            Handshake::new(create_handshake_initiator(&server_state.keys.private, None)),
+        )
+        .await
+        .unwrap();
+        assert!(server_state.handshake_tracker.contains(&ours));
+    }
+}
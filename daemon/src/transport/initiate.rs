@@ -15,6 +15,11 @@ pub async fn connection_initiator<T: Transport>(
             address,
         };
 
+        if !server_state.access_control.is_peer_allowed(&peer).await {
+            tracing::debug!("Not connecting to {} as it is denied", peer);
+            continue;
+        }
+
         tracing::debug!("Initiating connection to {}", peer);
 
         match timeout(Duration::from_secs(10), transport.connect(&address)).await {
@@ -0,0 +1,342 @@
+use crate::{channel::lifetime::close_channel, state::ServerState};
+use routeweaver_common::{Address, Peer, PublicKey};
+use std::{hash::Hash, net::IpAddr, sync::Arc, time::Duration};
+use tokio::time::{sleep, Instant};
+
+/// How long it takes for penalty points to halve
+const PENALTY_HALF_LIFE: Duration = Duration::from_secs(300);
+/// Points at which we cut off whoever is misbehaving
+const DISCONNECT_THRESHOLD: f32 = 50.0;
+/// Points at which we refuse to deal with them at all anymore
+const BAN_THRESHOLD: f32 = 100.0;
+/// How long a ban lasts
+const BAN_DURATION: Duration = Duration::from_secs(60 * 60);
+/// Points low enough that the penalty is forgotten altogether
+const FORGET_THRESHOLD: f32 = 1.0;
+/// How often decayed penalties and lapsed bans are cleaned out
+const MISBEHAVIOUR_KEEPER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Things a peer or node can do wrong
+///
+/// Peers are only blamed for what they sent us themselves, and nodes only for what could be authenticated as theirs,
+/// as anything else could be someone framing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offense {
+    /// Sent something that doesn't decode as a packet
+    MalformedPacket,
+    /// Sent a packet claiming to be from us
+    SpoofedSource,
+    /// Broke the rules around anonymous destinations
+    InvalidIdentityHint,
+    /// Sent something over a channel that doesn't decode
+    MalformedMessage,
+    /// Finished a handshake as a different node than it claimed to be
+    NodeMismatch,
+}
+
+impl Offense {
+    fn penalty(&self) -> f32 {
+        match self {
+            Offense::MalformedPacket => 20.0,
+            Offense::SpoofedSource => 25.0,
+            Offense::InvalidIdentityHint => 10.0,
+            Offense::MalformedMessage => 20.0,
+            Offense::NodeMismatch => 50.0,
+        }
+    }
+}
+
+/// What should be done about whoever was just penalized
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verdict {
+    Tolerate,
+    Disconnect,
+    Ban,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Penalty {
+    points: f32,
+    updated: Instant,
+}
+
+impl Penalty {
+    /// Points left after decaying since the last offense
+    fn current(&self) -> f32 {
+        let half_lives = self.updated.elapsed().as_secs_f32() / PENALTY_HALF_LIFE.as_secs_f32();
+
+        self.points * 0.5f32.powf(half_lives)
+    }
+
+    fn add(&mut self, points: f32) -> f32 {
+        self.points = self.current() + points;
+        self.updated = Instant::now();
+        self.points
+    }
+}
+
+/// What penalties against a peer stick to
+///
+/// A new connection gets a new port, so peers reached over IP are judged by their address alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offender {
+    Ip(IpAddr),
+    Address(Address),
+}
+
+impl From<&Peer> for Offender {
+    fn from(peer: &Peer) -> Self {
+        match peer.address {
+            Address::Ip { address, .. } => Offender::Ip(address.to_canonical()),
+            address => Offender::Address(address),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Penalties<K: Eq + Hash> {
+    points: scc::HashMap<K, Penalty>,
+    /// When each ban runs out
+    banned: scc::HashMap<K, Instant>,
+}
+
+impl<K: Eq + Hash> Default for Penalties<K> {
+    fn default() -> Self {
+        Self {
+            points: scc::HashMap::default(),
+            banned: scc::HashMap::default(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Penalties<K> {
+    async fn penalize(&self, key: K, offense: Offense) -> Verdict {
+        if self.is_banned(&key).await {
+            return Verdict::Ban;
+        }
+
+        let points = self
+            .points
+            .entry_async(key)
+            .await
+            .or_insert(Penalty {
+                points: 0.0,
+                updated: Instant::now(),
+            })
+            .get_mut()
+            .add(offense.penalty());
+
+        if points >= BAN_THRESHOLD {
+            Verdict::Ban
+        } else if points >= DISCONNECT_THRESHOLD {
+            Verdict::Disconnect
+        } else {
+            Verdict::Tolerate
+        }
+    }
+
+    async fn ban(&self, key: K) {
+        self.points.remove_async(&key).await;
+        self.banned
+            .upsert_async(key, Instant::now() + BAN_DURATION)
+            .await;
+    }
+
+    async fn is_banned(&self, key: &K) -> bool {
+        self.banned
+            .read_async(key, |_, until| *until > Instant::now())
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn expire(&self) {
+        self.points
+            .retain_async(|_, penalty| penalty.current() >= FORGET_THRESHOLD)
+            .await;
+        self.banned
+            .retain_async(|_, until| *until > Instant::now())
+            .await;
+    }
+}
+
+/// Tracks penalties for peers and nodes that send us garbage, and who got banned over it
+///
+/// Bans are kept apart from the access control list and the denied peers from the config, as they run out on their
+/// own and nobody should find rules the daemon made up in there, or have theirs removed when a ban lapses
+#[derive(Debug, Default)]
+pub struct MisbehaviourTracker {
+    peers: Penalties<Offender>,
+    nodes: Penalties<PublicKey>,
+}
+
+impl MisbehaviourTracker {
+    pub async fn penalize_peer(&self, peer: Peer, offense: Offense) -> Verdict {
+        let verdict = self.peers.penalize((&peer).into(), offense).await;
+
+        tracing::warn!("Peer {} misbehaved: {:?}, {:?}", peer, offense, verdict);
+
+        if verdict == Verdict::Ban {
+            self.peers.ban((&peer).into()).await;
+        }
+
+        verdict
+    }
+
+    pub async fn penalize_node(&self, node: PublicKey, offense: Offense) -> Verdict {
+        let verdict = self.nodes.penalize(node, offense).await;
+
+        tracing::warn!("Node {} misbehaved: {:?}, {:?}", node, offense, verdict);
+
+        if verdict == Verdict::Ban {
+            self.nodes.ban(node).await;
+        }
+
+        verdict
+    }
+
+    pub async fn is_peer_banned(&self, peer: &Peer) -> bool {
+        self.peers.is_banned(&peer.into()).await
+    }
+
+    pub async fn is_node_banned(&self, node: &PublicKey) -> bool {
+        self.nodes.is_banned(node).await
+    }
+
+    /// Forgets penalties that have decayed away and bans that ran out
+    pub async fn expire(&self) {
+        self.peers.expire().await;
+        self.nodes.expire().await;
+    }
+}
+
+/// Periodically cleans out the [MisbehaviourTracker]
+pub async fn misbehaviour_keeper(server_state: Arc<ServerState>) {
+    loop {
+        sleep(MISBEHAVIOUR_KEEPER_INTERVAL).await;
+
+        server_state.misbehaviour_tracker.expire().await;
+    }
+}
+
+/// Penalizes a peer, dropping the connection to it if it has gone too far
+pub async fn punish_peer(server_state: &ServerState, peer: Peer, offense: Offense) {
+    let verdict = server_state
+        .misbehaviour_tracker
+        .penalize_peer(peer, offense)
+        .await;
+
+    if verdict >= Verdict::Disconnect {
+        server_state.peer_tracker.disconnect(&peer).await;
+    }
+
+    // Any other connections from the same place are cut too, as they could only be used to get around the ban
+    if verdict == Verdict::Ban {
+        let offender = Offender::from(&peer);
+
+        for connected in server_state.peer_tracker.peers().await {
+            if Offender::from(&connected) == offender {
+                server_state.peer_tracker.disconnect(&connected).await;
+            }
+        }
+    }
+}
+
+/// Penalizes a node, tearing down any channel with it if it has gone too far
+pub async fn punish_node(server_state: &ServerState, node: PublicKey, offense: Offense) {
+    let verdict = server_state
+        .misbehaviour_tracker
+        .penalize_node(node, offense)
+        .await;
+
+    if verdict >= Verdict::Disconnect {
+        close_channel(server_state, node).await;
+        server_state.handshake_tracker.remove_async(&node).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MisbehaviourTracker, Offense, Verdict, BAN_DURATION};
+    use routeweaver_common::{Address, Peer, Protocol, PublicKey};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const PEER: Peer = Peer {
+        protocol: Protocol::Tcp,
+        address: Address::Ip {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3434,
+        },
+    };
+
+    #[tokio::test]
+    async fn escalates_to_ban() {
+        let tracker = MisbehaviourTracker::default();
+
+        assert_eq!(
+            tracker.penalize_peer(PEER, Offense::MalformedPacket).await,
+            Verdict::Tolerate
+        );
+        assert!(!tracker.is_peer_banned(&PEER).await);
+
+        let mut verdict = Verdict::Tolerate;
+        for _ in 0..10 {
+            verdict = verdict.max(tracker.penalize_peer(PEER, Offense::MalformedPacket).await);
+        }
+
+        assert_eq!(verdict, Verdict::Ban);
+        assert!(tracker.is_peer_banned(&PEER).await);
+    }
+
+    #[tokio::test]
+    async fn peers_and_nodes_are_separate() {
+        let tracker = MisbehaviourTracker::default();
+        let node = PublicKey::new([1; 32]);
+
+        assert_eq!(
+            tracker.penalize_node(node, Offense::NodeMismatch).await,
+            Verdict::Disconnect
+        );
+        tracker.penalize_node(node, Offense::NodeMismatch).await;
+        assert_eq!(
+            tracker.penalize_node(node, Offense::NodeMismatch).await,
+            Verdict::Ban
+        );
+
+        assert!(tracker.is_node_banned(&node).await);
+        assert!(!tracker.is_peer_banned(&PEER).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_follow_the_address_and_expire() {
+        let tracker = MisbehaviourTracker::default();
+
+        for _ in 0..5 {
+            tracker.penalize_peer(PEER, Offense::MalformedPacket).await;
+        }
+
+        // Coming back from another port, or as a mapped ipv6 address, changes nothing
+        let other_port = Peer {
+            protocol: Protocol::Udp,
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                port: 50000,
+            },
+        };
+        let mapped = Peer {
+            protocol: Protocol::Tcp,
+            address: Address::Ip {
+                address: IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()),
+                port: 3434,
+            },
+        };
+        assert!(tracker.is_peer_banned(&other_port).await);
+        assert!(tracker.is_peer_banned(&mapped).await);
+
+        tokio::time::advance(BAN_DURATION).await;
+        tracker.expire().await;
+
+        assert!(!tracker.is_peer_banned(&PEER).await);
+        assert!(tracker.peers.banned.is_empty());
+        assert!(tracker.peers.points.is_empty());
+    }
+}
@@ -1,9 +1,13 @@
 pub mod accepter;
+pub mod connection_manager;
 pub mod driver;
+pub mod handshake;
 pub mod initiate;
+pub mod misbehaviour;
 pub mod packet;
 pub mod reader;
 pub mod router;
+pub mod routing_table;
+pub mod score_keeper;
 pub mod setup_connection;
 pub mod writer;
-pub mod handshake_continue;
\ No newline at end of file
@@ -1,3 +1,4 @@
+use crate::{channel::lifetime::Epoch, proto::Capabilities};
 use arrayvec::ArrayVec;
 use rangemap::RangeInclusiveSet;
 use routeweaver_common::PublicKey;
@@ -5,6 +6,8 @@ use serde::{Deserialize, Serialize};
 use std::num::NonZero;
 
 pub const MAX_PACKET_PAYLOAD_SIZE: usize = 63 * 1024;
+/// How many hops a packet gets before it is dropped
+pub const DEFAULT_PACKET_TTL: u8 = 32;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Packet {
@@ -15,9 +18,25 @@ pub struct Packet {
     ///
     /// A peer is allowed to send this only once, and this will be dropped if the source for the packet already has any kind of tunnel building up
     pub destination: Option<PublicKey>,
+    /// Hops left before the packet is dropped, so packets caught in a routing loop don't live forever
+    pub ttl: u8,
+    /// Random per packet, so copies of the same packet arriving over different paths can be told apart from new ones
+    pub nonce: u64,
     pub data: PacketData,
 }
 
+impl Packet {
+    pub fn new(source: PublicKey, destination: Option<PublicKey>, data: PacketData) -> Self {
+        Self {
+            source,
+            destination,
+            ttl: DEFAULT_PACKET_TTL,
+            nonce: rand::random(),
+            data,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PacketData {
     /// Sent for doing handshakes.
@@ -28,8 +47,16 @@ pub enum PacketData {
     Handshake(ArrayVec<u8, 128>),
     /// Results in a [MessageSegment]. Done this way to deal with tamperings
     ///
-    /// Contains only noise transport data
-    MessageSegment(Vec<u8>),
+    /// Contains only the encrypted segment, along with the key epoch and nonce it was encrypted under
+    MessageSegment {
+        epoch: Epoch,
+        nonce: u64,
+        data: Vec<u8>,
+    },
+    /// First thing sent over a new connection by both ends, always without a destination
+    ///
+    /// Nothing else goes over the connection until there's a protocol version both speak
+    Hello(Capabilities),
 }
 
 pub type MessageId = u16;
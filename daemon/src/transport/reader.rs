@@ -1,15 +1,55 @@
-use super::{driver::TransportReader, packet::Packet, router::RequestRoutePacket};
+use super::{
+    driver::TransportReader,
+    handshake::receive_handshake,
+    misbehaviour::{punish_node, punish_peer, Offense},
+    packet::Packet,
+    router::RequestRoutePacket,
+};
 use crate::{
     channel::reader::RequestDecodeMessageSegment,
-    noise::create_handshake_responder,
+    error::RouteWeaverError,
     state::ServerState,
     transport::packet::{MessageSegment, PacketData},
 };
-use arrayvec::ArrayVec;
 use futures_util::StreamExt;
 use routeweaver_common::{Peer, PublicKey};
-use snow::{HandshakeState, TransportState};
-use std::{pin::Pin, sync::Arc};
+use std::{pin::Pin, sync::Arc, time::Duration};
+use tokio::time::Instant;
+
+/// How long a packet is remembered for spotting copies of it
+const SEEN_PACKET_LIFETIME: Duration = Duration::from_secs(30);
+/// Most packets remembered at once, the oldest are forgotten first
+const MAX_SEEN_PACKETS: usize = 65536;
+
+/// Remembers recently seen packets so copies arriving over different paths can be dropped
+#[derive(Debug)]
+pub struct SeenPacketTracker(scc::HashCache<(PublicKey, u64), Instant>);
+
+impl Default for SeenPacketTracker {
+    fn default() -> Self {
+        Self(scc::HashCache::with_capacity(0, MAX_SEEN_PACKETS))
+    }
+}
+
+impl SeenPacketTracker {
+    /// Records a packet, returning false if it was already seen recently
+    pub async fn first_sighting(&self, source: PublicKey, nonce: u64) -> bool {
+        match self.0.entry_async((source, nonce)).await {
+            scc::hash_cache::Entry::Occupied(mut entry) => {
+                if entry.get().elapsed() < SEEN_PACKET_LIFETIME {
+                    return false;
+                }
+
+                *entry.get_mut() = Instant::now();
+                true
+            }
+            scc::hash_cache::Entry::Vacant(entry) => {
+                entry.put_entry(Instant::now());
+                true
+            }
+        }
+    }
+}
 
 /// Reads packets from the transport, decodes them, and sends the results to the relevant bins
 pub async fn packet_reader(
@@ -20,146 +60,254 @@ pub async fn packet_reader(
     let mut encryption_buffer = vec![0; u16::MAX as usize];
 
     while let Some(packet) = peer_connection.next().await {
-        match packet {
-            Ok(packet) => {
-                // Someone is pretending to be us or this is from this machine
-                if packet.source == server_state.keys.public {
-                    tracing::warn!("Received packet from self, discarding");
-                    continue;
+        let result = match packet {
+            Ok(packet) => handle_packet(&server_state, peer, packet, &mut encryption_buffer).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            tracing::error!("Connection reader for {} encountered error: {}", peer, err);
+
+            if matches!(
+                err,
+                RouteWeaverError::MessagePackDecoding(_)
+                    | RouteWeaverError::InvalidFrame
+                    | RouteWeaverError::FrameTooLarge { .. }
+            ) {
+                punish_peer(&server_state, peer, Offense::MalformedPacket).await;
+            }
+
+            break;
+        }
+    }
+}
+
+async fn handle_packet(
+    server_state: &ServerState,
+    peer: Peer,
+    mut packet: Packet,
+    encryption_buffer: &mut [u8],
+) -> Result<(), RouteWeaverError> {
+    // Someone is pretending to be us or this is from this machine
+    if packet.source == server_state.keys.public {
+        tracing::warn!("Received packet from self, discarding");
+        punish_peer(server_state, peer, Offense::SpoofedSource).await;
+        return Ok(());
+    }
+
+    // Only ever the first packet over a connection, and that one never makes it here
+    if matches!(packet.data, PacketData::Hello(_)) {
+        tracing::warn!("Peer {} said hello again, discarding", peer);
+        return Ok(());
+    }
+
+    // Someone is trying to ping us, reject it as this is not proper usage
+    if packet.destination == Some(packet.source) {
+        tracing::warn!(
+            "Packet intends to travel to its source {}, discarding",
+            packet.source
+        );
+        return Ok(());
+    }
+
+    // The same packet can reach us over more than one path, only the first copy counts
+    if packet.destination.is_some()
+        && !server_state
+            .seen_packet_tracker
+            .first_sighting(packet.source, packet.nonce)
+            .await
+    {
+        tracing::debug!(
+            "Already saw packet {} from {}, discarding",
+            packet.nonce,
+            packet.source
+        );
+        return Ok(());
+    }
+
+    // It's for us, and if the destination is [Option::None] its our peer speaking to us
+    if packet.destination == Some(server_state.keys.public) || packet.destination.is_none() {
+        tracing::debug!("Received packet for us from {}", packet.source);
+
+        if server_state
+            .misbehaviour_tracker
+            .is_node_banned(&packet.source)
+            .await
+        {
+            tracing::debug!("Packet from banned node {}, discarding", packet.source);
+            return Ok(());
+        }
+
+        match packet.data {
+            // Someone with the intention to make a handshake
+            PacketData::Handshake(data) => {
+                tracing::debug!("Received handshake from {}", packet.source);
+
+                if !server_state
+                    .access_control
+                    .is_node_allowed(&packet.source)
+                    .await
+                {
+                    tracing::debug!("Refusing handshake from denied node {}", packet.source);
+                    return Ok(());
                 }
 
-                // Someone is trying to ping us, reject it as this is not proper usage
-                if packet.destination == Some(packet.source) {
-                    tracing::warn!(
-                        "Packet intends to travel to its source {}, discarding",
+                if !server_state.mesh_trust.is_node_trusted(&packet.source) {
+                    tracing::debug!("Refusing handshake from untrusted node {}", packet.source);
+                    return Ok(());
+                }
+
+                // Only replaced once the handshake finishes, as until then anyone could have sent this
+                if server_state
+                    .transport_tracker
+                    .contains_async(&packet.source)
+                    .await
+                {
+                    tracing::debug!(
+                        "Node {} is handshaking while a channel is up, it probably restarted",
                         packet.source
                     );
-                    continue;
                 }
 
-                // It's for us, and if the destination is [Option::None] its our peer speaking to us
-                if packet.destination == Some(server_state.keys.public)
-                    || packet.destination.is_none()
-                {
-                    tracing::debug!("Received packet for us from {}", packet.source);
-
-                    match packet.data {
-                        // Someone with the intention to make a handshake
-                        PacketData::Handshake(data) => {
-                            tracing::debug!("Received handshake from {}", packet.source);
-
-                            let handshake_state_guard = server_state
-                                .handshake_tracker
-                                .entry_async(packet.source)
-                                .await
-                                .or_insert_with(|| {
-                                    create_handshake_responder(&server_state.keys.private)
-                                });
-
-                            handle_handshake(packet.source, data, handshake_state_guard).await;
-                        }
-                        PacketData::MessageSegment(data) => {
-                            if packet.destination.is_none() {
-                                tracing::warn!("Packet from {} is being sent to anonymous destination yet is not a handshake packet, discarding", packet.source);
-                            }
+                // Our peer hinting who it is, which only counts once the handshake proves it
+                let via = packet.destination.is_none().then_some(peer);
+
+                receive_handshake(server_state, packet.source, data, peer, via).await?;
+            }
+            // Turned away above
+            PacketData::Hello(_) => {}
+            PacketData::MessageSegment { epoch, nonce, data } => {
+                if packet.destination.is_none() {
+                    tracing::warn!("Packet from {} is being sent to anonymous destination yet is not a handshake packet, discarding", packet.source);
+                    punish_peer(server_state, peer, Offense::InvalidIdentityHint).await;
+                    return Ok(());
+                }
+
+                let Some(mut channel) = server_state
+                    .transport_tracker
+                    .get_async(&packet.source)
+                    .await
+                else {
+                    tracing::warn!(
+                        "Got message segment from node {} without channel up",
+                        packet.source
+                    );
+
+                    server_state
+                        .request_initiate_channel
+                        .send(packet.source)
+                        .await?;
+
+                    return Ok(());
+                };
+
+                match channel.open(epoch, nonce, &data, encryption_buffer) {
+                    Ok(amount) => {
+                        channel.decrypt_succeeded();
 
-                            let Some(mut transport_state) = server_state
-                                .transport_tracker
-                                .get_async(&packet.source)
-                                .await
-                            else {
+                        let segment: MessageSegment = match bincode::serde::decode_from_slice(
+                            &encryption_buffer[..amount],
+                            bincode::config::standard(),
+                        ) {
+                            Ok((segment, _)) => segment,
+                            Err(err) => {
+                                drop(channel);
+
+                                // This made it through the channel so it really came from them
                                 tracing::warn!(
-                                    "Got message segment from node {} without channel up",
-                                    packet.source
+                                    "Node {} sent an undecodable message segment: {}",
+                                    packet.source,
+                                    err
                                 );
-                                
-                                server_state
-                                    .request_initiate_channel
-                                    .send(packet.source)
-                                    .await
-                                    .unwrap();
-
-                                continue;
-                            };
-
-                            match transport_state.read_message(&data, &mut encryption_buffer) {
-                                Ok(amount) => {
-                                    let (segment, _): (MessageSegment, _) =
-                                        bincode::serde::decode_from_slice(
-                                            &encryption_buffer[..amount],
-                                            bincode::config::standard(),
-                                        )
-                                        .unwrap();
-
-                                    server_state
-                                        .request_decode_message_segment
-                                        .send(RequestDecodeMessageSegment {
-                                            source: packet.source,
-                                            segment,
-                                        })
-                                        .await
-                                        .unwrap();
-                                }
-                                Err(err) => {
-                                    tracing::error!("Error reading message segment: {}", err);
-                                }
+                                punish_node(server_state, packet.source, Offense::MalformedMessage)
+                                    .await;
+                                return Ok(());
                             }
-                        }
+                        };
+
+                        drop(channel);
+
+                        server_state
+                            .request_decode_message_segment
+                            .send(RequestDecodeMessageSegment {
+                                source: packet.source,
+                                segment,
+                            })
+                            .await?;
                     }
-                // Its for someone else
-                } else {
-                    tracing::debug!(
-                        "Received packet from {} going to {}",
-                        packet.source,
-                        packet.destination.unwrap()
-                    );
+                    // Came some other way too, or got sent again by someone along the path
+                    Err(RouteWeaverError::ReplayedSegment) => {
+                        tracing::debug!(
+                            "Dropping message segment from node {} that was already read",
+                            packet.source
+                        );
+                    }
+                    Err(err) => {
+                        tracing::error!("Error reading message segment: {}", err);
 
-                    server_state
-                        .request_route_packet
-                        .send(RequestRoutePacket {
-                            origin: Some(peer),
-                            packet,
-                        })
-                        .await
-                        .unwrap();
+                        if channel.decrypt_failed() {
+                            drop(channel);
+
+                            tracing::info!(
+                                "Channel with node {} looks stale, setting up a new one",
+                                packet.source
+                            );
+
+                            // Anyone could have sent these, so the channel is only replaced once the handshake
+                            // finishes, which also sends again whatever the node had not confirmed
+                            server_state
+                                .request_initiate_channel
+                                .send(packet.source)
+                                .await?;
+                        }
+                    }
                 }
             }
-            Err(err) => {
-                tracing::error!("Connection reader for {} encountered error: {}", peer, err);
-                break;
-            }
         }
+    // Its for someone else
+    } else if let Some(destination) = packet.destination {
+        tracing::debug!(
+            "Received packet from {} going to {}",
+            packet.source,
+            destination
+        );
+
+        packet.ttl = packet.ttl.saturating_sub(1);
+
+        if packet.ttl == 0 {
+            tracing::warn!(
+                "Packet from {} going to {} ran out of hops, discarding",
+                packet.source,
+                destination
+            );
+            return Ok(());
+        }
+
+        server_state
+            .request_route_packet
+            .send(RequestRoutePacket {
+                origin: Some(peer),
+                packet,
+            })
+            .await?;
     }
+
+    Ok(())
 }
 
-async fn handle_handshake(
-    source: PublicKey,
-    data: ArrayVec<u8, 128>,
-    mut handshake_state_guard: scc::hash_map::OccupiedEntry<'_, PublicKey, HandshakeState>,
-) {
-    let handshake_state = handshake_state_guard.get_mut();
+#[cfg(test)]
+mod tests {
+    use super::SeenPacketTracker;
+    use routeweaver_common::PublicKey;
 
-    if handshake_state.is_my_turn() || handshake_state.is_handshake_finished() {
-        // Ignore this handshake state
-        return;
-    }
+    #[tokio::test]
+    async fn drops_duplicates() {
+        let tracker = SeenPacketTracker::default();
+        let source = PublicKey::new([1; 32]);
 
-    // Attempt to decode and validate it
-    match handshake_state.read_message(&data, &mut []) {
-        Ok(amount) => {
-            if amount != 0 {
-                tracing::error!("Node {} sent incorrect handshake message", source);
-                let _ = handshake_state_guard.remove();
-            }
-        }
-        // Failure means remove the entry
-        Err(err) => {
-            tracing::error!(
-                "Error decoding handshake message from node {}: {}",
-                source,
-                err
-            );
-            let _ = handshake_state_guard.remove();
-        }
+        assert!(tracker.first_sighting(source, 1).await);
+        assert!(!tracker.first_sighting(source, 1).await);
+        assert!(tracker.first_sighting(source, 2).await);
+        assert!(tracker.first_sighting(PublicKey::new([2; 32]), 1).await);
     }
 }
@@ -1,17 +1,18 @@
 use super::packet::Packet;
 use crate::state::ServerState;
-use rand::{prelude::StdRng, Rng, SeedableRng};
-use ringbuffer::{ConstGenericRingBuffer, RingBuffer};
 use routeweaver_common::Peer;
-use std::{
-    collections::{HashMap, VecDeque},
-    sync::Arc,
-    time::Duration,
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{interval, Instant},
 };
-use tokio::{sync::mpsc, time::sleep};
 
 const LOCAL_OUTBOUND_PACKET_TIMEOUT: Duration = Duration::from_secs(5);
 const REMOTE_OUTBOUND_PACKET_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often packets without a route are given another try
+const UNROUTED_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// Most packets we hold onto while waiting for routes to show up
+const MAX_UNROUTED_PACKETS: usize = 1000;
 
 pub struct RequestRoutePacket {
     /// This is tracked so that we cannot route back to the sender, preventing infinite loops
@@ -20,137 +21,118 @@ pub struct RequestRoutePacket {
     pub packet: Packet,
 }
 
-enum PeerEvent {
-    AcceptedPacket { time_taken: Duration, failed: bool },
-}
-
-impl PeerEvent {
-    pub fn score(&self) -> f32 {
-        match self {
-            PeerEvent::AcceptedPacket { time_taken, failed } => {
-                time_taken.as_secs_f32() * if *failed { -1.0 } else { 1.0 }
-            }
-        }
-    }
-}
-
-#[derive(Default)]
-struct ScoreKeeper(HashMap<Peer, ConstGenericRingBuffer<PeerEvent, 50>>);
-
-impl ScoreKeeper {
-    fn insert(&mut self, peer: Peer, event: PeerEvent) {
-        self.0.entry(peer).or_default().push(event);
-    }
-
-    fn best(&self) -> Option<Peer> {
-        self.0
-            .iter()
-            .max_by(|(_, a), (_, b)| {
-                a.iter()
-                    .map(|a| a.score())
-                    .sum::<f32>()
-                    .total_cmp(&b.iter().map(|b| b.score()).sum())
-            })
-            .map(|(k, _)| *k)
-    }
-}
-
-// FIXME: This is essentially me playing around with algos and is obscenely inefficient
-
-/// Delegates packets to a calculated "most correct" peer
+/// Forwards packets to the neighbor the routing table says is closest to their destination
 pub async fn packet_router(
     server_state: Arc<ServerState>,
     mut request_route_packet: mpsc::Receiver<RequestRoutePacket>,
 ) {
-    let score_keeper = ScoreKeeper::default();
-    let mut new_canidates = VecDeque::new();
-    let mut rng = StdRng::from_entropy();
-    let mut notification_new_peer_connection =
-        server_state.notification_new_peer_connection.subscribe();
-
-    while let Some(RequestRoutePacket { origin, packet }) = request_route_packet.recv().await {
-        new_canidates.clear();
-
-        if let Some(destination) = packet.destination {
-            let mut tries_until_return_to_sender_allowed: u8 = 5;
-
-            // TODO: as a implementation hack, chose a random route
-            loop {
-                let current_canidate = 
-                // 10% of the time try to grab from the newly seen canidates
-                if rng.gen_ratio(10, 100) {
-                    new_canidates.pop_front()
-
-                // Otherwise try to grab the canidate with the highest score
-                } else {
-                    score_keeper.best()
+    // Packets we don't know where to send yet, with when we give up on them
+    let mut unrouted = VecDeque::new();
+    let mut retry = interval(UNROUTED_RETRY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            request = request_route_packet.recv() => {
+                let Some(request) = request else {
+                    break;
                 };
 
-                if let Some(peer) = current_canidate {
-                    tracing::debug!(
-                        "Attempting to route packet through {} from {} to {}",
-                        peer,
-                        packet.source,
-                        destination
-                    );
-
-                    if let Some(entry) = server_state.request_write_packet.get_async(&peer).await {
-                        // Don't send back to whoever handed it to us unless we really have to
-                        if Some(*entry.key()) != origin || tries_until_return_to_sender_allowed == 0
-                        {
-                            match entry.get().send(packet.clone()).await {
-                                Ok(_) => {
-                                    tracing::debug!(
-                                        "Routed packet through {} from {} to {}",
-                                        entry.key(),
-                                        packet.source,
-                                        destination
-                                    );
-                                    break;
-                                }
-                                Err(err) => {
-                                    tracing::error!(
-                                        "Failed to route packet through {} from {} to {}: {}",
-                                        entry.key(),
-                                        packet.source,
-                                        destination,
-                                        err
-                                    );
-
-                                    // If a send error occured here that means the task most likely does not exist any more
-                                    let _ = entry.remove_entry();
-                                    continue;
-                                }
-                            }
-                        }
+                if let Some(request) = route_packet(&server_state, request).await {
+                    if unrouted.len() >= MAX_UNROUTED_PACKETS {
+                        tracing::warn!("Too many packets waiting on a route, dropping");
+                        continue;
                     }
 
+                    // Our own packets get more patience, as channels to new nodes take a while to find a route
+                    let timeout = if request.origin.is_none() {
+                        LOCAL_OUTBOUND_PACKET_TIMEOUT
+                    } else {
+                        REMOTE_OUTBOUND_PACKET_TIMEOUT
+                    };
 
+                    unrouted.push_back((Instant::now() + timeout, request));
                 }
+            }
+            _ = retry.tick(), if !unrouted.is_empty() => {
+                for _ in 0..unrouted.len() {
+                    let Some((deadline, request)) = unrouted.pop_front() else {
+                        break;
+                    };
+
+                    if deadline <= Instant::now() {
+                        tracing::debug!(
+                            "Dropping packet from {} to {:?}, no route showed up in time",
+                            request.packet.source,
+                            request.packet.destination
+                        );
+                        continue;
+                    }
 
-                tracing::debug!(
-                    "Failed to select a canidate to route a packet from {} to {} through",
-                    packet.source,
-                    destination
-                );
-
-                tries_until_return_to_sender_allowed = tries_until_return_to_sender_allowed.saturating_sub(1);
-
-                // Wait around either for a new peer connection or for a period of time to pass
-                tokio::select! {
-                    // We prefer a new peer connection
-                    biased;
-
-                    peer = notification_new_peer_connection.recv() => {
-                        if let Ok(peer) = peer {
-                            new_canidates.push_back(peer);
-                        }
+                    if let Some(request) = route_packet(&server_state, request).await {
+                        unrouted.push_back((deadline, request));
                     }
-                    _ = sleep(Duration::from_millis(10)) => {}
                 }
             }
-        } else {
-            tracing::error!("Packet with anonymous destination was erroneously sent to the router, this is a major bug");
+        }
+    }
+}
+
+/// Hands a packet to the next hop on its way, giving it back if there is nowhere to send it yet
+async fn route_packet(
+    server_state: &ServerState,
+    mut request: RequestRoutePacket,
+) -> Option<RequestRoutePacket> {
+    let Some(destination) = request.packet.destination else {
+        tracing::error!(
+            "Packet with anonymous destination was erroneously sent to the router, this is a major bug"
+        );
+        return None;
+    };
+
+    let peer = server_state.routing_table.next_hop(&destination).await?;
+
+    if Some(peer) == request.origin {
+        // Whoever gave us this thinks we are closer, and we think they are, so the routes are still settling
+        tracing::debug!(
+            "Dropping packet from {} to {}, it would go back to where it came from",
+            request.packet.source,
+            destination
+        );
+        return None;
+    }
+
+    let Some(sender) = server_state
+        .request_write_packet
+        .read_async(&peer, |_, sender| sender.clone())
+        .await
+    else {
+        return Some(request);
+    };
+
+    let source = request.packet.source;
+
+    match sender.send(request.packet).await {
+        Ok(()) => {
+            tracing::debug!(
+                "Routed packet through {} from {} to {}",
+                peer,
+                source,
+                destination
+            );
+            None
+        }
+        Err(err) => {
+            tracing::error!(
+                "Failed to route packet through {} to {}: writer is gone",
+                peer,
+                destination
+            );
+
+            // If a send error occured here that means the task most likely does not exist any more
+            server_state.request_write_packet.remove_async(&peer).await;
+
+            request.packet = err.0;
+            Some(request)
         }
     }
 }
@@ -0,0 +1,564 @@
+use crate::{channel::writer::RequestWriteMessage, proto::Message, state::ServerState};
+use routeweaver_common::{ipc::control::RouteEntry, Peer, PublicKey};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::time::{sleep, Instant};
+
+/// How often we tell our neighbors what we can reach
+pub const ROUTE_ADVERTISEMENT_INTERVAL: Duration = Duration::from_secs(15);
+/// How long a route lives without the neighbor advertising it again
+pub const ROUTE_EXPIRY: Duration = Duration::from_secs(45);
+/// Metric at which a node is considered unreachable
+///
+/// Kept low so counting to infinity after a node disappears ends quickly, while leaving room for a few slow links
+pub const METRIC_INFINITY: Metric = 64;
+/// Cost of going over a single link that we know nothing bad about
+pub const LINK_COST: Metric = 1;
+
+/// Rough distance to a node, lower is better
+pub type Metric = u8;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Route {
+    /// Neighbor packets for this destination get handed to
+    pub next_hop: PublicKey,
+    pub metric: Metric,
+    /// When the neighbor last advertised this
+    updated: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Neighbor {
+    /// Links to this neighbor, packets go over the first one and the rest are there for when it drops
+    peers: Vec<Peer>,
+    /// Cost of going over the link to this neighbor
+    cost: Metric,
+}
+
+impl Neighbor {
+    fn peer(&self) -> Peer {
+        self.peers[0]
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    /// Nodes we are directly connected to, and the peers those connections go over
+    ///
+    /// Only added through `learn_neighbor` once a handshake over the peer has finished, so the node really is behind it
+    neighbors: scc::HashMap<PublicKey, Neighbor>,
+    /// Best known route to every node through one of our neighbors
+    ///
+    /// This can include neighbors themselves, for when their direct link is worse than going around
+    routes: scc::HashMap<PublicKey, Route>,
+}
+
+impl RoutingTable {
+    /// Records that a node can be reached directly through a peer
+    ///
+    /// Returns a link to the node we already had over the same protocol, as keeping both around gains nothing
+    pub async fn add_neighbor(&self, node: PublicKey, peer: Peer) -> Option<Peer> {
+        match self.neighbors.entry_async(node).await {
+            scc::hash_map::Entry::Occupied(mut entry) => {
+                let neighbor = entry.get_mut();
+
+                if neighbor.peers.contains(&peer) {
+                    return None;
+                }
+
+                tracing::debug!("Node {} is also a neighbor through {}", node, peer);
+
+                let redundant = neighbor
+                    .peers
+                    .iter()
+                    .find(|existing| existing.protocol == peer.protocol)
+                    .copied();
+                neighbor.peers.push(peer);
+
+                redundant
+            }
+            scc::hash_map::Entry::Vacant(entry) => {
+                tracing::debug!("Node {} is a neighbor through {}", node, peer);
+
+                entry.insert_entry(Neighbor {
+                    peers: vec![peer],
+                    cost: LINK_COST,
+                });
+
+                None
+            }
+        }
+    }
+
+    /// Updates what going over the link to a neighbor costs, as measured by the [super::score_keeper::ScoreKeeper]
+    pub async fn set_link_cost(&self, node: &PublicKey, cost: Metric) {
+        self.neighbors
+            .update_async(node, |_, neighbor| {
+                neighbor.cost = cost.min(METRIC_INFINITY)
+            })
+            .await;
+    }
+
+    pub async fn is_neighbor(&self, node: &PublicKey) -> bool {
+        self.neighbors.contains_async(node).await
+    }
+
+    pub async fn neighbors(&self) -> Vec<(PublicKey, Peer)> {
+        let mut neighbors = Vec::new();
+
+        self.neighbors
+            .scan_async(|node, neighbor| neighbors.push((*node, neighbor.peer())))
+            .await;
+
+        neighbors
+    }
+
+    /// Everything in the table, with neighbors as their own next hop
+    pub async fn routes(&self) -> Vec<RouteEntry> {
+        let mut routes = Vec::new();
+
+        self.neighbors
+            .scan_async(|node, neighbor| {
+                routes.push(RouteEntry {
+                    destination: *node,
+                    next_hop: *node,
+                    metric: neighbor.cost,
+                })
+            })
+            .await;
+
+        self.routes
+            .scan_async(|node, route| {
+                routes.push(RouteEntry {
+                    destination: *node,
+                    next_hop: route.next_hop,
+                    metric: route.metric,
+                })
+            })
+            .await;
+
+        routes
+    }
+
+    /// Forgets everything reachable through a peer, for when the connection to it goes away
+    ///
+    /// Neighbors with another link left just move over to it
+    pub async fn remove_peer(&self, peer: &Peer) {
+        let mut lost = Vec::new();
+
+        self.neighbors
+            .retain_async(|node, neighbor| {
+                let was_primary = neighbor.peer() == *peer;
+                neighbor.peers.retain(|existing| existing != peer);
+
+                if neighbor.peers.is_empty() {
+                    lost.push(*node);
+                    return false;
+                }
+
+                if was_primary {
+                    tracing::info!(
+                        "Link to node {} over {} dropped, moving over to {}",
+                        node,
+                        peer,
+                        neighbor.peer()
+                    );
+                }
+
+                true
+            })
+            .await;
+
+        if !lost.is_empty() {
+            self.routes
+                .retain_async(|_, route| !lost.contains(&route.next_hop))
+                .await;
+        }
+    }
+
+    /// Figures out which peer a packet for a node should be handed to
+    pub async fn next_hop(&self, destination: &PublicKey) -> Option<Peer> {
+        let direct = self
+            .neighbors
+            .read_async(destination, |_, neighbor| (neighbor.peer(), neighbor.cost))
+            .await;
+        let route = self.routes.read_async(destination, |_, route| *route).await;
+
+        match (direct, route) {
+            (Some((peer, cost)), Some(route)) if route.metric < cost => {
+                // Going around is cheaper, as long as the neighbor we go through is still there
+                self.neighbors
+                    .read_async(&route.next_hop, |_, neighbor| neighbor.peer())
+                    .await
+                    .or(Some(peer))
+            }
+            (Some((peer, _)), _) => Some(peer),
+            (None, Some(route)) => {
+                self.neighbors
+                    .read_async(&route.next_hop, |_, neighbor| neighbor.peer())
+                    .await
+            }
+            (None, None) => None,
+        }
+    }
+
+    /// Builds what we tell a neighbor we can reach
+    ///
+    /// Routes going through that neighbor are advertised as unreachable so it never tries routing back through us
+    pub async fn advertisement_for(&self, neighbor: &PublicKey) -> HashMap<PublicKey, Metric> {
+        let mut advertisement = HashMap::new();
+
+        self.neighbors
+            .scan_async(|node, direct| {
+                if node != neighbor {
+                    advertisement.insert(*node, direct.cost);
+                }
+            })
+            .await;
+
+        self.routes
+            .scan_async(|node, route| {
+                if node == neighbor {
+                    return;
+                }
+
+                let metric = if route.next_hop == *neighbor {
+                    METRIC_INFINITY
+                } else {
+                    route.metric
+                };
+
+                advertisement
+                    .entry(*node)
+                    .and_modify(|current: &mut Metric| *current = (*current).min(metric))
+                    .or_insert(metric);
+            })
+            .await;
+
+        advertisement
+    }
+
+    /// Takes in everything a neighbor says it can reach
+    ///
+    /// Advertisements are complete, so anything we were routing through the neighbor that it no longer mentions is
+    /// dropped
+    pub async fn update(
+        &self,
+        local: &PublicKey,
+        neighbor: PublicKey,
+        advertisement: HashMap<PublicKey, Metric>,
+    ) {
+        let Some(link_cost) = self
+            .neighbors
+            .read_async(&neighbor, |_, neighbor| neighbor.cost)
+            .await
+        else {
+            return;
+        };
+        let now = Instant::now();
+
+        self.routes
+            .retain_async(|node, route| {
+                route.next_hop != neighbor || advertisement.contains_key(node)
+            })
+            .await;
+
+        for (destination, metric) in advertisement {
+            if destination == *local || destination == neighbor {
+                continue;
+            }
+
+            let metric = metric.saturating_add(link_cost).min(METRIC_INFINITY);
+
+            match self.routes.entry_async(destination).await {
+                scc::hash_map::Entry::Occupied(mut entry) => {
+                    let route = entry.get_mut();
+
+                    if route.next_hop == neighbor {
+                        // Our current route got worse or went away, believe it
+                        if metric >= METRIC_INFINITY {
+                            let _ = entry.remove();
+                        } else {
+                            route.metric = metric;
+                            route.updated = now;
+                        }
+                    } else if metric < route.metric {
+                        *route = Route {
+                            next_hop: neighbor,
+                            metric,
+                            updated: now,
+                        };
+                    }
+                }
+                scc::hash_map::Entry::Vacant(entry) => {
+                    if metric < METRIC_INFINITY {
+                        entry.insert_entry(Route {
+                            next_hop: neighbor,
+                            metric,
+                            updated: now,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops routes that have not been advertised in a while
+    pub async fn expire(&self) {
+        self.routes
+            .retain_async(|_, route| route.updated.elapsed() < ROUTE_EXPIRY)
+            .await;
+    }
+}
+
+/// Periodically tells every neighbor what we can reach and cleans out stale routes and scores
+pub async fn route_advertiser(server_state: Arc<ServerState>) {
+    loop {
+        sleep(ROUTE_ADVERTISEMENT_INTERVAL).await;
+
+        server_state.routing_table.expire().await;
+        server_state.score_keeper.prune().await;
+
+        for (neighbor, peer) in server_state.routing_table.neighbors().await {
+            let cost = server_state.score_keeper.link_cost(&peer, &neighbor).await;
+            server_state
+                .routing_table
+                .set_link_cost(&neighbor, cost)
+                .await;
+
+            // Advertisements would just pile up in the writer without a channel
+            let Some(route_advertisements) = server_state
+                .transport_tracker
+                .read_async(&neighbor, |_, channel| {
+                    channel.capabilities.supports_route_advertisements()
+                })
+                .await
+            else {
+                if server_state
+                    .request_initiate_channel
+                    .send(neighbor)
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+
+                continue;
+            };
+
+            // Neighbor doesn't route, so it would only throw them away
+            if !route_advertisements {
+                continue;
+            }
+
+            let routes = server_state
+                .routing_table
+                .advertisement_for(&neighbor)
+                .await;
+
+            if server_state
+                .request_write_message
+                .send(RequestWriteMessage {
+                    notify_sent: None,
+                    destination: neighbor,
+                    message: Message::RouteAdvertisement { routes },
+                })
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metric, RoutingTable, LINK_COST, METRIC_INFINITY};
+    use routeweaver_common::{Address, Peer, Protocol, PublicKey};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    fn node(id: u8) -> PublicKey {
+        PublicKey::new([id; 32])
+    }
+
+    /// What the table would tell a node that isn't involved in anything about a destination
+    async fn metric(table: &RoutingTable, destination: u8) -> Option<Metric> {
+        table
+            .advertisement_for(&node(u8::MAX))
+            .await
+            .get(&node(destination))
+            .copied()
+    }
+
+    fn peer(id: u8) -> Peer {
+        Peer {
+            protocol: Protocol::Tcp,
+            address: Address::Ip {
+                address: IpAddr::V4(Ipv4Addr::new(10, 0, 0, id)),
+                port: 3434,
+            },
+        }
+    }
+
+    #[tokio::test]
+    async fn learns_routes_through_neighbors() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1), (local, 1)]))
+            .await;
+
+        assert_eq!(metric(&table, 2).await, Some(1 + LINK_COST));
+        assert_eq!(table.next_hop(&node(2)).await, Some(peer(1)));
+        assert_eq!(metric(&table, 0).await, None);
+    }
+
+    #[tokio::test]
+    async fn prefers_shorter_routes() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table.add_neighbor(node(2), peer(2)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(3), 5)]))
+            .await;
+        table
+            .update(&local, node(2), HashMap::from([(node(3), 2)]))
+            .await;
+        assert_eq!(table.next_hop(&node(3)).await, Some(peer(2)));
+
+        // A worse offer from someone else doesn't replace it
+        table
+            .update(&local, node(1), HashMap::from([(node(3), 4)]))
+            .await;
+        assert_eq!(table.next_hop(&node(3)).await, Some(peer(2)));
+    }
+
+    #[tokio::test]
+    async fn avoids_slow_links() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table.add_neighbor(node(2), peer(2)).await;
+        table.set_link_cost(&node(2), 10).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1)]))
+            .await;
+
+        assert_eq!(table.next_hop(&node(2)).await, Some(peer(1)));
+        assert_eq!(metric(&table, 2).await, Some(1 + LINK_COST));
+    }
+
+    #[tokio::test]
+    async fn withdrawn_routes_are_removed() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1), (node(3), 1)]))
+            .await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), METRIC_INFINITY)]))
+            .await;
+
+        assert_eq!(metric(&table, 2).await, None);
+        assert_eq!(metric(&table, 3).await, None);
+    }
+
+    #[tokio::test]
+    async fn poisons_routes_back_to_next_hop() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table.add_neighbor(node(2), peer(2)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(3), 1)]))
+            .await;
+
+        let advertisement = table.advertisement_for(&node(1)).await;
+        assert_eq!(advertisement.get(&node(3)), Some(&METRIC_INFINITY));
+        assert_eq!(advertisement.get(&node(2)), Some(&LINK_COST));
+        assert!(!advertisement.contains_key(&node(1)));
+
+        let advertisement = table.advertisement_for(&node(2)).await;
+        assert_eq!(advertisement.get(&node(3)), Some(&(1 + LINK_COST)));
+    }
+
+    #[tokio::test]
+    async fn lists_neighbors_and_routes() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1)]))
+            .await;
+
+        let mut routes: Vec<_> = table
+            .routes()
+            .await
+            .into_iter()
+            .map(|route| (route.destination, route.next_hop, route.metric))
+            .collect();
+        routes.sort();
+
+        assert_eq!(
+            routes,
+            vec![
+                (node(1), node(1), LINK_COST),
+                (node(2), node(1), 1 + LINK_COST)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn losing_a_peer_drops_its_routes() {
+        let table = RoutingTable::default();
+        let local = node(0);
+
+        table.add_neighbor(node(1), peer(1)).await;
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1)]))
+            .await;
+        table.remove_peer(&peer(1)).await;
+
+        assert!(!table.is_neighbor(&node(1)).await);
+        assert!(table.next_hop(&node(2)).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_over_between_links() {
+        let table = RoutingTable::default();
+        let local = node(0);
+        let other_protocol = Peer {
+            protocol: Protocol::Ws,
+            ..peer(2)
+        };
+
+        assert_eq!(table.add_neighbor(node(1), peer(1)).await, None);
+        assert_eq!(table.add_neighbor(node(1), other_protocol).await, None);
+        // Same protocol again is a connection too many
+        assert_eq!(table.add_neighbor(node(1), peer(3)).await, Some(peer(1)));
+        assert_eq!(table.add_neighbor(node(1), peer(1)).await, None);
+        table.remove_peer(&peer(3)).await;
+
+        table
+            .update(&local, node(1), HashMap::from([(node(2), 1)]))
+            .await;
+        assert_eq!(table.next_hop(&node(1)).await, Some(peer(1)));
+
+        table.remove_peer(&peer(1)).await;
+        assert_eq!(table.next_hop(&node(1)).await, Some(other_protocol));
+        assert_eq!(table.next_hop(&node(2)).await, Some(other_protocol));
+        assert_eq!(table.neighbors().await, vec![(node(1), other_protocol)]);
+    }
+}
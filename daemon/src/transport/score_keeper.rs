@@ -0,0 +1,233 @@
+use super::routing_table::{Metric, LINK_COST};
+use routeweaver_common::{
+    ipc::control::{LinkScore, Scores},
+    Peer, PublicKey,
+};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Weight the newest sample gets, everything before it decays by the rest
+const SCORE_DECAY: f32 = 0.2;
+/// Latency that adds one to the cost of a link
+const LATENCY_STEP: Duration = Duration::from_millis(50);
+/// Most a link can be penalized for latency, and separately for failures
+const MAX_PENALTY: f32 = 4.0;
+/// How long a score is kept after its last sample, so a peer that reconnects is still judged by how it did before
+const SCORE_LIFETIME: Duration = Duration::from_secs(60 * 10);
+
+pub enum PeerEvent {
+    /// A packet was handed to the transport for a peer
+    AcceptedPacket { time_taken: Duration, failed: bool },
+}
+
+/// Exponentially decaying averages, so a link that recovers stops being punished for old behavior
+#[derive(Debug, Clone, Copy, Default)]
+struct Score {
+    /// Seconds
+    latency: f32,
+    failure_rate: f32,
+    /// When the last sample was recorded, the first sample is taken as is
+    updated: Option<Instant>,
+}
+
+impl From<Score> for LinkScore {
+    fn from(score: Score) -> Self {
+        LinkScore {
+            latency: Duration::from_secs_f32(score.latency),
+            failure_rate: score.failure_rate,
+        }
+    }
+}
+
+impl Score {
+    fn record(&mut self, latency: Option<Duration>, failed: bool) {
+        let failed = if failed { 1.0 } else { 0.0 };
+
+        if self.updated.replace(Instant::now()).is_none() {
+            self.latency = latency.unwrap_or_default().as_secs_f32();
+            self.failure_rate = failed;
+            return;
+        }
+
+        // Failures say nothing about how fast the link is
+        if let Some(latency) = latency {
+            self.latency += (latency.as_secs_f32() - self.latency) * SCORE_DECAY;
+        }
+
+        self.failure_rate += (failed - self.failure_rate) * SCORE_DECAY;
+    }
+
+    fn is_stale(&self) -> bool {
+        self.updated
+            .is_none_or(|updated| updated.elapsed() >= SCORE_LIFETIME)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ScoreKeeper {
+    /// How well handing packets to each peer goes
+    peers: scc::HashMap<Peer, Score>,
+    /// How long messages take to be confirmed by each node
+    nodes: scc::HashMap<PublicKey, Score>,
+}
+
+impl ScoreKeeper {
+    pub async fn insert(&self, peer: Peer, event: PeerEvent) {
+        match event {
+            PeerEvent::AcceptedPacket { time_taken, failed } => {
+                self.peers
+                    .entry_async(peer)
+                    .await
+                    .or_default()
+                    .get_mut()
+                    .record((!failed).then_some(time_taken), failed);
+            }
+        }
+    }
+
+    /// Records how long a message took from first being sent to being confirmed
+    pub async fn message_confirmed(&self, node: PublicKey, round_trip_time: Duration) {
+        self.nodes
+            .entry_async(node)
+            .await
+            .or_default()
+            .get_mut()
+            .record(Some(round_trip_time), false);
+    }
+
+    pub async fn scores(&self) -> Scores {
+        let mut scores = Scores::default();
+
+        self.peers
+            .scan_async(|peer, score| scores.peers.push((*peer, (*score).into())))
+            .await;
+        self.nodes
+            .scan_async(|node, score| scores.nodes.push((*node, (*score).into())))
+            .await;
+
+        scores
+    }
+
+    /// Forgets scores that have not been updated in a while, including those of peers that went away
+    pub async fn prune(&self) {
+        self.peers.retain_async(|_, score| !score.is_stale()).await;
+        self.nodes.retain_async(|_, score| !score.is_stale()).await;
+    }
+
+    /// Cost of reaching a neighbor over the peer it is connected through
+    pub async fn link_cost(&self, peer: &Peer, node: &PublicKey) -> Metric {
+        let peer_score = self
+            .peers
+            .read_async(peer, |_, score| *score)
+            .await
+            .unwrap_or_default();
+        let node_score = self
+            .nodes
+            .read_async(node, |_, score| *score)
+            .await
+            .unwrap_or_default();
+
+        let latency = peer_score.latency.max(node_score.latency);
+        let latency_penalty = (latency / LATENCY_STEP.as_secs_f32())
+            .floor()
+            .min(MAX_PENALTY);
+        let failure_penalty = (peer_score.failure_rate * MAX_PENALTY).round();
+
+        LINK_COST + latency_penalty as Metric + failure_penalty as Metric
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerEvent, ScoreKeeper, SCORE_LIFETIME};
+    use crate::transport::routing_table::LINK_COST;
+    use routeweaver_common::{Address, Peer, Protocol, PublicKey};
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    const PEER: Peer = Peer {
+        protocol: Protocol::Tcp,
+        address: Address::Ip {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 3434,
+        },
+    };
+    const NODE: PublicKey = PublicKey::new([1; 32]);
+
+    #[tokio::test]
+    async fn unknown_links_cost_the_minimum() {
+        let score_keeper = ScoreKeeper::default();
+
+        assert_eq!(score_keeper.link_cost(&PEER, &NODE).await, LINK_COST);
+    }
+
+    #[tokio::test]
+    async fn slow_links_cost_more() {
+        let score_keeper = ScoreKeeper::default();
+
+        score_keeper
+            .message_confirmed(NODE, Duration::from_millis(120))
+            .await;
+
+        assert_eq!(score_keeper.link_cost(&PEER, &NODE).await, LINK_COST + 2);
+    }
+
+    #[tokio::test]
+    async fn failures_decay() {
+        let score_keeper = ScoreKeeper::default();
+
+        score_keeper
+            .insert(
+                PEER,
+                PeerEvent::AcceptedPacket {
+                    time_taken: Duration::ZERO,
+                    failed: true,
+                },
+            )
+            .await;
+        let failing = score_keeper.link_cost(&PEER, &NODE).await;
+
+        for _ in 0..20 {
+            score_keeper
+                .insert(
+                    PEER,
+                    PeerEvent::AcceptedPacket {
+                        time_taken: Duration::ZERO,
+                        failed: false,
+                    },
+                )
+                .await;
+        }
+
+        assert!(failing > LINK_COST);
+        assert_eq!(score_keeper.link_cost(&PEER, &NODE).await, LINK_COST);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_outlive_the_connection_for_a_while() {
+        let score_keeper = ScoreKeeper::default();
+
+        score_keeper
+            .insert(
+                PEER,
+                PeerEvent::AcceptedPacket {
+                    time_taken: Duration::ZERO,
+                    failed: true,
+                },
+            )
+            .await;
+        score_keeper
+            .message_confirmed(NODE, Duration::from_millis(120))
+            .await;
+
+        score_keeper.prune().await;
+        assert!(score_keeper.link_cost(&PEER, &NODE).await > LINK_COST);
+
+        tokio::time::advance(SCORE_LIFETIME).await;
+        score_keeper.prune().await;
+        assert_eq!(score_keeper.link_cost(&PEER, &NODE).await, LINK_COST);
+        assert!(score_keeper.scores().await.nodes.is_empty());
+    }
+}
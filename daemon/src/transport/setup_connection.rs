@@ -1,35 +1,87 @@
 use super::driver::{TransportReader, TransportWriter};
 use crate::{
+    error::RouteWeaverError,
     noise::create_handshake_initiator,
+    proto::Capabilities,
     state::ServerState,
     transport::{
+        handshake::{read_capabilities, start_handshake, Handshake},
+        misbehaviour::{punish_peer, Offense},
         packet::{Packet, PacketData},
         reader::packet_reader,
         writer::packet_writer,
     },
 };
 use arrayvec::ArrayVec;
-use futures_util::{SinkExt, StreamExt, TryStreamExt};
-use routeweaver_common::Peer;
+use futures_util::{stream::Peekable, SinkExt, StreamExt};
+use routeweaver_common::{Peer, PublicKey};
 use std::{pin::Pin, sync::Arc, time::Duration};
 use tokio::{sync::mpsc, time::timeout};
+use tokio_util::sync::CancellationToken;
+
+/// How long a new peer gets to say hello before the connection is dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Connection {
+    /// Tears down the reader and writer
+    cancellation_token: CancellationToken,
+    /// We were the ones who connected
+    initiator: bool,
+}
 
 #[derive(Debug, Default)]
 pub struct PeerTracker {
-    connected: scc::HashSet<Peer>,
+    /// Connected peers
+    connected: scc::HashMap<Peer, Connection>,
 }
 
 impl PeerTracker {
-    pub async fn add(&self, peer: Peer) {
-        let _ = self.connected.insert_async(peer).await;
+    /// Returns [Option::None] if the peer is already connected
+    pub async fn add(&self, peer: Peer, initiator: bool) -> Option<CancellationToken> {
+        let cancellation_token = CancellationToken::new();
+
+        self.connected
+            .insert_async(
+                peer,
+                Connection {
+                    cancellation_token: cancellation_token.clone(),
+                    initiator,
+                },
+            )
+            .await
+            .ok()?;
+
+        Some(cancellation_token)
     }
 
     pub async fn remove(&self, peer: &Peer) {
         self.connected.remove_async(peer).await;
     }
 
-    pub async fn is_connected(&self, peer: &Peer) -> bool {
-        self.connected.contains_async(peer).await
+    /// Whether we were the ones who connected to a peer, if it is connected at all
+    pub async fn is_initiator(&self, peer: &Peer) -> Option<bool> {
+        self.connected
+            .read_async(peer, |_, connection| connection.initiator)
+            .await
+    }
+
+    pub async fn peers(&self) -> Vec<Peer> {
+        let mut peers = Vec::new();
+
+        self.connected.scan_async(|peer, _| peers.push(*peer)).await;
+
+        peers
+    }
+
+    /// Drops the connection to a peer, returning false if there was none
+    pub async fn disconnect(&self, peer: &Peer) -> bool {
+        let Some((_, connection)) = self.connected.remove_async(peer).await else {
+            return false;
+        };
+
+        connection.cancellation_token.cancel();
+        true
     }
 }
 
@@ -40,12 +92,20 @@ pub async fn finalize_peer_connection(
     peer: Peer,
     initiator: bool,
 ) {
-    if server_state.peer_tracker.is_connected(&peer).await {
+    if server_state
+        .misbehaviour_tracker
+        .is_peer_banned(&peer)
+        .await
+    {
+        tracing::debug!("Refusing connection with banned peer {}", peer);
         return;
     }
 
+    let Some(cancellation_token) = server_state.peer_tracker.add(peer, initiator).await else {
+        return;
+    };
+
     tracing::debug!("Setting up connection for {}", peer);
-    server_state.peer_tracker.add(peer).await;
     let _ = server_state.notification_new_peer_connection.send(peer);
 
     match (reader, writer) {
@@ -57,8 +117,12 @@ pub async fn finalize_peer_connection(
             tokio::spawn(async move {
                 tracing::debug!("Connection reader for {} starting", peer);
 
-                packet_reader(server_state.clone(), Box::pin(reader), peer).await;
-                server_state.peer_tracker.remove(&peer).await;
+                tokio::select! {
+                    _ = packet_reader(server_state.clone(), Box::pin(reader), peer) => {}
+                    _ = cancellation_token.cancelled() => {}
+                }
+
+                forget_peer(&server_state, &peer).await;
 
                 tracing::debug!("Connection reader for {} closed", peer);
             });
@@ -73,16 +137,12 @@ pub async fn finalize_peer_connection(
             tokio::spawn(async move {
                 tracing::debug!("Connection writer for {} starting", peer);
 
-                packet_writer(
-                    server_state.clone(),
-                    Box::pin(writer),
-                    peer,
-                    request_write_packet_rx,
-                )
-                .await;
+                tokio::select! {
+                    _ = packet_writer(server_state.clone(), Box::pin(writer), peer, request_write_packet_rx) => {}
+                    _ = cancellation_token.cancelled() => {}
+                }
 
-                server_state.peer_tracker.remove(&peer).await;
-                server_state.request_write_packet.remove_async(&peer).await;
+                forget_peer(&server_state, &peer).await;
 
                 tracing::debug!("Connection writer for {} closed", peer);
             });
@@ -94,13 +154,23 @@ pub async fn finalize_peer_connection(
                 .upsert_async(peer, request_write_packet_tx)
                 .await;
 
-            let mut reader = Box::pin(reader);
+            // Peekable so the anonymous handshake can leave whatever isn't its reply for the packet reader
+            let mut reader = Box::pin(reader.peekable());
             let mut writer = Box::pin(writer);
 
+            if !exchange_hello(&server_state, peer, &mut reader, &mut writer).await {
+                forget_peer(&server_state, &peer).await;
+                return;
+            }
+
             // Try doing an anonymous handshake here if we can
             // Note only the initator does the anonymous handshake to stop confusion
-            if !server_state.anonymous && initiator {
-                anonymous_handshake(&server_state, peer, &mut reader, &mut writer).await;
+            if !server_state.anonymous
+                && initiator
+                && !anonymous_handshake(&server_state, peer, &mut reader, &mut writer).await
+            {
+                forget_peer(&server_state, &peer).await;
+                return;
             }
 
             tokio::spawn(async move {
@@ -109,10 +179,10 @@ pub async fn finalize_peer_connection(
                 tokio::select! {
                     _ = packet_reader(server_state.clone(), reader, peer) => {}
                     _ = packet_writer(server_state.clone(), writer, peer, request_write_packet_rx) => {}
+                    _ = cancellation_token.cancelled() => {}
                 }
 
-                server_state.peer_tracker.remove(&peer).await;
-                server_state.request_write_packet.remove_async(&peer).await;
+                forget_peer(&server_state, &peer).await;
 
                 tracing::debug!("Connection reader and writer for {} closed", peer);
             });
@@ -120,82 +190,229 @@ pub async fn finalize_peer_connection(
     }
 }
 
+/// Records which node is behind a peer, once a handshake over that peer has proven it
+///
+/// A node only needs one connection per protocol, so if there already was one the extra gets dropped. Links over
+/// other protocols are kept for failing over to. Both links are known to really lead to the node, so nobody can get a
+/// link dropped by claiming to be on the other end of it
+pub async fn learn_neighbor(server_state: &ServerState, node: PublicKey, peer: Peer) {
+    let Some(existing) = server_state.routing_table.add_neighbor(node, peer).await else {
+        return;
+    };
+
+    // Both ends have to close the same one, so keep whichever was made by the node with the lower key
+    let we_are_lower = server_state.keys.public.as_ref() < node.as_ref();
+    let made_by_lower =
+        |initiator: Option<bool>| initiator.map(|initiator| initiator == we_are_lower);
+
+    let redundant = match (
+        made_by_lower(server_state.peer_tracker.is_initiator(&existing).await),
+        made_by_lower(server_state.peer_tracker.is_initiator(&peer).await),
+    ) {
+        (Some(false), Some(true)) => existing,
+        _ => peer,
+    };
+
+    tracing::info!(
+        "Already connected to node {} over {}, dropping {}",
+        node,
+        peer.protocol,
+        redundant
+    );
+    server_state.peer_tracker.disconnect(&redundant).await;
+}
+
+/// Cleans up everything tied to a peer once its connection is gone
+///
+/// Its score is left to age out, so failures that ended the connection still count against it if it comes back
+async fn forget_peer(server_state: &ServerState, peer: &Peer) {
+    server_state.peer_tracker.remove(peer).await;
+    server_state.routing_table.remove_peer(peer).await;
+    server_state.request_write_packet.remove_async(peer).await;
+    let _ = server_state.notification_peer_disconnected.send(*peer);
+}
+
+/// Tells the peer what we speak and makes sure it speaks a version we do before anything else goes over the connection
+///
+/// Only the version matters here, everything optional is worked out with each node when setting up a channel.
+/// Returns false if the connection should be dropped
+async fn exchange_hello<R: TransportReader>(
+    server_state: &ServerState,
+    peer: Peer,
+    reader: &mut Pin<Box<Peekable<R>>>,
+    writer: &mut Pin<Box<impl TransportWriter>>,
+) -> bool {
+    let hello = Packet::new(
+        server_state.keys.public,
+        None,
+        PacketData::Hello(Capabilities::ours()),
+    );
+
+    match timeout(HELLO_TIMEOUT, writer.send(hello)).await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => {
+            tracing::info!("Failed saying hello to peer {}: {}", peer, err);
+            return false;
+        }
+        Err(_) => {
+            tracing::info!("Peer {} is not taking our hello", peer);
+            return false;
+        }
+    }
+
+    let remote_capabilities = match timeout(HELLO_TIMEOUT, reader.next()).await {
+        Ok(Some(Ok(Packet {
+            destination: None,
+            data: PacketData::Hello(remote_capabilities),
+            ..
+        }))) => remote_capabilities,
+        Ok(Some(Ok(_))) => {
+            tracing::info!("Peer {} did not start with a hello", peer);
+            return false;
+        }
+        Ok(Some(Err(err))) => {
+            tracing::info!("Failed reading hello from peer {}: {}", peer, err);
+            return false;
+        }
+        Ok(None) | Err(_) => {
+            tracing::info!("Peer {} never said hello", peer);
+            return false;
+        }
+    };
+
+    let Some(version) = Capabilities::ours().common_version(&remote_capabilities) else {
+        tracing::info!(
+            "Peer {} speaks protocol versions {} to {}, none of which we do",
+            peer,
+            remote_capabilities.min_version,
+            remote_capabilities.version
+        );
+        return false;
+    };
+
+    tracing::debug!("Speaking protocol version {} with peer {}", version, peer);
+
+    true
+}
+
 /// Manually do part of the handshake logic here
 ///
 /// This function must do the first handshake step, and get a response, so the handshake_tracker can actually store the thing
-async fn anonymous_handshake(
+///
+/// Returns false if the connection went away in the meantime
+async fn anonymous_handshake<R: TransportReader>(
     server_state: &ServerState,
     peer: Peer,
-    reader: &mut Pin<Box<impl TransportReader>>,
+    reader: &mut Pin<Box<Peekable<R>>>,
     writer: &mut Pin<Box<impl TransportWriter>>,
-) {
+) -> bool {
     let mut buffer = vec![0; 128];
 
     tracing::debug!("Attempting anonymous handshake with peer {}", peer);
 
-    let mut handshake_state = create_handshake_initiator(&server_state.keys.private);
+    let mut handshake_state = create_handshake_initiator(
+        &server_state.keys.private,
+        server_state.mesh_trust.psk.as_ref(),
+    );
 
-    if let Ok(amount) = handshake_state.write_message(&[], &mut buffer) {
-        let packet = Packet {
-            source: server_state.keys.public,
-            destination: None,
-            data: PacketData::Handshake(
-                ArrayVec::try_from(&buffer[..amount]).expect("This shouldn't happen"),
-            ),
-        };
+    let Some(data) = handshake_state
+        .write_message(&[], &mut buffer)
+        .ok()
+        .and_then(|amount| ArrayVec::try_from(&buffer[..amount]).ok())
+    else {
+        return true;
+    };
 
-        // Do the first step,
-        match timeout(Duration::from_secs(10), writer.send(packet)).await {
-            Ok(Ok(_)) => {
-                // Best scenario, they accepted our packet (probably)
-            }
-            Ok(Err(err)) => {
-                // Worst scenario, they closed the connection over this
-                todo!()
-            }
-            Err(_) => {
-                // Timeout occured, they are probably operating in anonymous mode
-                tracing::info!(
-                    "Anonymous handshake with peer {} failed, this is not vital",
-                    peer
-                );
-            }
+    let packet = Packet::new(server_state.keys.public, None, PacketData::Handshake(data));
+
+    // Do the first step,
+    match timeout(Duration::from_secs(10), writer.send(packet)).await {
+        Ok(Ok(_)) => {
+            // Best scenario, they accepted our packet (probably)
         }
+        Ok(Err(err)) => {
+            // Worst scenario, they closed the connection over this
+            tracing::info!(
+                "Peer {} closed the connection over our anonymous handshake: {}",
+                peer,
+                err
+            );
+            return false;
+        }
+        Err(_) => {
+            // Timeout occured, they are probably operating in anonymous mode
+            tracing::info!(
+                "Anonymous handshake with peer {} failed, this is not vital",
+                peer
+            );
+        }
+    }
 
-        // Read the next packet and interpret it
+    // Look at the next packet, only taking it if it's the reply
+    let is_reply = match timeout(Duration::from_secs(10), reader.as_mut().peek()).await {
+        Ok(Some(Ok(packet))) => {
+            packet.destination == Some(server_state.keys.public)
+                && matches!(packet.data, PacketData::Handshake(_))
+        }
+        // Left for the packet reader to deal with
+        Ok(Some(Err(_))) => false,
+        // Connection was closed
+        Ok(None) => return false,
+        // Remote didn't seem to care
+        Err(_) => false,
+    };
 
-        match timeout(Duration::from_secs(10), reader.try_next()).await {
-            Ok(Ok(Some(packet))) => {
-                // FIXME: This is dropping a packet, while this isn't life ending, it is kind of bad.
-                // In the future, we need the readable to be peekable
-                if packet.destination != Some(server_state.keys.public) {
-                    // Remote didn't care
-                    return;
-                }
+    if !is_reply {
+        return true;
+    }
 
-                if let PacketData::Handshake(data) = packet.data {
-                    if handshake_state.read_message(&data, &mut buffer).is_err() {
-                        // Remote didn't care
-                        return;
-                    }
-
-                    tracing::info!(
-                        "Node {} seems to have cared about the anonymous handshake, storing",
-                        packet.source
-                    );
-
-                    let _ = server_state
-                        .handshake_tracker
-                        .upsert_async(packet.source, handshake_state)
-                        .await;
-                }
-            }
-            Ok(Ok(None)) | Ok(Err(_)) => {
-                // Connection was closed
-            }
-            Err(_) => {
-                // Remote didn't seem to care
-            }
-        }
+    // Can't be anything else, it was just peeked
+    let Some(Ok(Packet {
+        source,
+        data: PacketData::Handshake(data),
+        ..
+    })) = reader.next().await
+    else {
+        return true;
+    };
+
+    let Ok(remote_capabilities) = handshake_state
+        .read_message(&data, &mut buffer)
+        .map_err(RouteWeaverError::from)
+        .and_then(|amount| read_capabilities(&buffer[..amount]))
+    else {
+        // Remote didn't care
+        return true;
+    };
+
+    // Their static key came with the reply, so this is who is actually behind the peer
+    let remote_node_id = handshake_state
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .map(PublicKey::new);
+
+    if remote_node_id != Some(source) {
+        tracing::warn!(
+            "Peer {} answered our anonymous handshake as node {}, but could not prove it",
+            peer,
+            source
+        );
+        punish_peer(server_state, peer, Offense::InvalidIdentityHint).await;
+        return false;
     }
+
+    tracing::info!(
+        "Node {} seems to have cared about the anonymous handshake, storing",
+        source
+    );
+
+    let mut handshake = Handshake::new(handshake_state);
+    handshake.remote_capabilities = remote_capabilities;
+    handshake.via = Some(peer);
+
+    if let Err(err) = start_handshake(server_state, source, handshake).await {
+        tracing::info!("Could not continue handshake with node {}: {}", source, err);
+    }
+
+    true
 }
@@ -1,9 +1,9 @@
-use super::driver::TransportWriter;
+use super::{driver::TransportWriter, score_keeper::PeerEvent};
 use crate::{state::ServerState, transport::packet::Packet};
 use futures_util::SinkExt;
 use routeweaver_common::Peer;
 use std::{pin::Pin, sync::Arc};
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, time::Instant};
 
 pub async fn packet_writer(
     server_state: Arc<ServerState>,
@@ -19,7 +19,21 @@ pub async fn packet_writer(
             packet.destination
         );
 
-        if let Err(err) = peer_connection.send(packet).await {
+        let started = Instant::now();
+        let result = peer_connection.send(packet).await;
+
+        server_state
+            .score_keeper
+            .insert(
+                peer,
+                PeerEvent::AcceptedPacket {
+                    time_taken: started.elapsed(),
+                    failed: result.is_err(),
+                },
+            )
+            .await;
+
+        if let Err(err) = result {
             tracing::warn!("Failed to write packet through {}: {}", peer, err);
 
             break;
@@ -0,0 +1,279 @@
+use clap::{Parser, Subcommand};
+use futures_util::StreamExt;
+use routeweaver_common::{
+    acl::AclRule,
+    ipc::{
+        control::{ControlEvent, LinkScore, RouteWeaverControl},
+        DAEMON_CONTROL_SOCKET,
+    },
+    Peer, RouteWeaverCommonError,
+};
+use serde_json::json;
+use std::{collections::BTreeMap, path::PathBuf, process::ExitCode};
+use table::Table;
+
+mod table;
+
+#[derive(Subcommand, Debug)]
+pub enum AclAction {
+    List,
+    /// Adds a rule like `deny ip 10.0.0.0/8`, at the end unless an index is given
+    Add {
+        #[arg(required=true, num_args=1..)]
+        rule: Vec<String>,
+        #[clap(short, long)]
+        index: Option<usize>,
+    },
+    Remove {
+        index: usize,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliActions {
+    /// Public key of the node the daemon runs
+    PublicKey,
+    /// Peers the daemon has a connection with
+    Peers,
+    /// Peers the daemon keeps reconnecting to
+    DesiredPeers,
+    /// Every node the daemon knows of, with how it reaches it
+    Nodes,
+    Routes,
+    Channels,
+    Handshakes,
+    /// Addresses the daemon can be reached at
+    Addresses,
+    Applications,
+    Scores,
+    /// Keeps connected to a peer, written like /tcp/ip/127.0.0.1/3434
+    Connect {
+        peer: Peer,
+    },
+    Disconnect {
+        peer: Peer,
+    },
+    Acl {
+        #[clap(subcommand)]
+        action: AclAction,
+    },
+    /// Prints what the daemon does until interrupted
+    Events,
+}
+
+#[derive(Parser, Debug)]
+pub struct Cli {
+    /// Print json instead of tables
+    #[clap(short, long, global = true)]
+    json: bool,
+    #[clap(short, long, default_value_os_t = DAEMON_CONTROL_SOCKET.clone())]
+    socket: PathBuf,
+    #[clap(subcommand)]
+    action: CliActions,
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+
+    match run(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), RouteWeaverCommonError> {
+    let mut control = RouteWeaverControl::connect(&cli.socket).await?;
+
+    match cli.action {
+        CliActions::PublicKey => {
+            let public_key = control.public_key().await?;
+
+            if cli.json {
+                println!("{}", json!({ "public_key": public_key.to_string() }));
+            } else {
+                println!("{}", public_key);
+            }
+        }
+        CliActions::Peers => {
+            let mut table = Table::new(["peer"]);
+
+            for peer in control.peers().await? {
+                table.push([peer.to_string()]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::DesiredPeers => {
+            let mut table = Table::new(["peer", "origin", "connected", "failures", "next_attempt"]);
+
+            for desired in control.desired_peers().await? {
+                table.push([
+                    desired.peer.to_string(),
+                    if desired.configured {
+                        "configured"
+                    } else {
+                        "discovered"
+                    }
+                    .to_string(),
+                    desired.connected.to_string(),
+                    desired.failures.to_string(),
+                    desired
+                        .next_attempt
+                        .map(|next_attempt| format!("{:?}", next_attempt))
+                        .unwrap_or_else(|| "-".to_string()),
+                ]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Nodes => {
+            // Node to channel state, next hop and metric, ordered so the output is stable between runs
+            let mut nodes = BTreeMap::new();
+
+            for route in control.routes().await? {
+                nodes.insert(
+                    route.destination,
+                    ("none", route.next_hop.to_string(), route.metric.to_string()),
+                );
+            }
+
+            for (nodes_in_state, state) in [
+                (control.handshakes().await?, "handshaking"),
+                (control.channels().await?, "established"),
+            ] {
+                for node in nodes_in_state {
+                    nodes
+                        .entry(node)
+                        .or_insert(("none", "-".to_string(), "-".to_string()))
+                        .0 = state;
+                }
+            }
+
+            let mut table = Table::new(["node", "channel", "next_hop", "metric"]);
+
+            for (node, (state, next_hop, metric)) in nodes {
+                table.push([node.to_string(), state.to_string(), next_hop, metric]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Routes => {
+            let mut table = Table::new(["destination", "next_hop", "metric"]);
+
+            for route in control.routes().await? {
+                table.push([
+                    route.destination.to_string(),
+                    route.next_hop.to_string(),
+                    route.metric.to_string(),
+                ]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Channels => {
+            let mut table = Table::new(["node"]);
+
+            for node in control.channels().await? {
+                table.push([node.to_string()]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Handshakes => {
+            let mut table = Table::new(["node"]);
+
+            for node in control.handshakes().await? {
+                table.push([node.to_string()]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Addresses => {
+            let mut table = Table::new(["address"]);
+
+            for address in control.local_addresses().await? {
+                table.push([address.to_string()]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Applications => {
+            let mut table = Table::new(["application"]);
+
+            for application in control.applications().await? {
+                table.push([application.to_string()]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Scores => {
+            let scores = control.scores().await?;
+            let mut table = Table::new(["kind", "target", "latency", "failure_rate"]);
+
+            let score_cells = |score: LinkScore| {
+                [
+                    format!("{:?}", score.latency),
+                    format!("{:.2}", score.failure_rate),
+                ]
+            };
+
+            for (peer, score) in scores.peers {
+                let [latency, failure_rate] = score_cells(score);
+                table.push(["peer".to_string(), peer.to_string(), latency, failure_rate]);
+            }
+
+            for (node, score) in scores.nodes {
+                let [latency, failure_rate] = score_cells(score);
+                table.push(["node".to_string(), node.to_string(), latency, failure_rate]);
+            }
+
+            table.print(cli.json);
+        }
+        CliActions::Connect { peer } => control.connect_peer(peer).await?,
+        CliActions::Disconnect { peer } => control.disconnect_peer(peer).await?,
+        CliActions::Acl { action } => match action {
+            AclAction::List => {
+                let mut table = Table::new(["index", "rule"]);
+
+                for (index, rule) in control.acl_rules().await?.into_iter().enumerate() {
+                    table.push([index.to_string(), rule.to_string()]);
+                }
+
+                table.print(cli.json);
+            }
+            AclAction::Add { rule, index } => {
+                let rule: AclRule = rule.join(" ").parse()?;
+                control.insert_acl_rule(index, rule).await?;
+            }
+            AclAction::Remove { index } => control.remove_acl_rule(index).await?,
+        },
+        CliActions::Events => {
+            let mut events = Box::pin(control.events().await?);
+
+            while let Some(event) = events.next().await {
+                let (kind, target) = match event? {
+                    ControlEvent::PeerConnected { peer } => ("peer_connected", peer.to_string()),
+                    ControlEvent::PeerDisconnected { peer } => {
+                        ("peer_disconnected", peer.to_string())
+                    }
+                    ControlEvent::ChannelEstablished { node } => {
+                        ("channel_established", node.to_string())
+                    }
+                };
+
+                if cli.json {
+                    println!("{}", json!({ "event": kind, "target": target }));
+                } else {
+                    println!("{} {}", kind, target);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use serde_json::{Map, Value};
+
+/// Rows of text that can be printed either aligned for people or as json for scripts
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: impl IntoIterator<Item = &'static str>) -> Self {
+        Self {
+            headers: headers.into_iter().collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, row: impl IntoIterator<Item = String>) {
+        let row: Vec<_> = row.into_iter().collect();
+        debug_assert_eq!(row.len(), self.headers.len());
+
+        self.rows.push(row);
+    }
+
+    /// Columns padded to the widest cell, with a header line on top
+    pub fn render(&self) -> String {
+        let mut widths: Vec<_> = self.headers.iter().map(|header| header.len()).collect();
+
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        let headers = self.headers.iter().map(|header| header.to_uppercase());
+        let mut output = String::new();
+
+        for row in std::iter::once(headers.collect()).chain(self.rows.iter().cloned()) {
+            let line = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ");
+
+            output.push_str(line.trim_end());
+            output.push('\n');
+        }
+
+        output
+    }
+
+    /// Array with an object per row, keyed by the headers
+    pub fn to_json(&self) -> Value {
+        Value::Array(
+            self.rows
+                .iter()
+                .map(|row| {
+                    Value::Object(
+                        self.headers
+                            .iter()
+                            .zip(row)
+                            .map(|(header, cell)| (header.to_string(), Value::from(cell.as_str())))
+                            .collect::<Map<_, _>>(),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    pub fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.to_json());
+        } else {
+            print!("{}", self.render());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Table;
+
+    #[test]
+    fn render_and_json() {
+        let mut table = Table::new(["peer", "latency"]);
+        table.push(["/tcp/ip/127.0.0.1/3434".to_string(), "3ms".to_string()]);
+        table.push(["/udp/ip/::1/3434".to_string(), "12ms".to_string()]);
+
+        assert_eq!(
+            table.render(),
+            "PEER                    LATENCY\n\
+             /tcp/ip/127.0.0.1/3434  3ms\n\
+             /udp/ip/::1/3434        12ms\n"
+        );
+        assert_eq!(
+            table.to_json().to_string(),
+            r#"[{"latency":"3ms","peer":"/tcp/ip/127.0.0.1/3434"},{"latency":"12ms","peer":"/udp/ip/::1/3434"}]"#
+        );
+    }
+}